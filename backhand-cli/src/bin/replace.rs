@@ -44,6 +44,14 @@ struct Args {
     /// Don't emit compression options
     #[clap(long)]
     no_compression_options: bool,
+
+    /// Don't use fragments, storing all files in data blocks
+    #[clap(long)]
+    no_fragments: bool,
+
+    /// Use fragments for the tail-end of files larger than the block size
+    #[clap(long, conflicts_with = "no_fragments")]
+    always_use_fragments: bool,
 }
 
 fn main() -> ExitCode {
@@ -79,6 +87,13 @@ fn main() -> ExitCode {
     if args.no_compression_options {
         filesystem.set_emit_compression_options(false);
     }
+    if args.no_fragments {
+        filesystem.set_no_fragments(true);
+    }
+    if args.always_use_fragments {
+        filesystem.set_no_fragments(false);
+        filesystem.set_tail_ends(true);
+    }
 
     // write new file
     let Ok(output) = File::create_new(&args.output_image) else {
@@ -1,16 +1,20 @@
 use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt;
 use std::fs::{self, File, Permissions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::lchown;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
 use std::process::ExitCode;
 use std::sync::Mutex;
 
-use backhand::kind::Kind;
+use backhand::kind::{Confidence, Kind};
 use backhand::{
-    BufReadSeek, FilesystemReader, InnerNode, Node, NodeHeader, Squashfs, SquashfsBlockDevice,
-    SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsSymlink, DEFAULT_BLOCK_SIZE,
+    BufReadSeek, FilesystemReader, InnerNode, Node, Squashfs, SquashfsBlockDevice,
+    SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsHardlink, SquashfsSymlink,
+    Xattr, DEFAULT_BLOCK_SIZE,
 };
 use backhand_cli::after_help;
 use clap::builder::PossibleValuesParser;
@@ -40,34 +44,27 @@ pub fn required_root(a: &str) -> Result<PathBuf, String> {
     }
 }
 
-fn find_offset(file: &mut BufReader<File>, kind: &Kind) -> Option<u64> {
-    let mut magic = [0_u8; 4];
-    while file.read_exact(&mut magic).is_ok() {
-        if magic == kind.magic() {
-            let found = file.stream_position().unwrap() - magic.len() as u64;
-            file.rewind().unwrap();
-            return Some(found);
-        }
-    }
-    file.rewind().unwrap();
-    None
+/// Offset of the first image of `target`, preferring images with a valid superblock
+fn find_offset(file: &mut BufReader<File>, target: &str) -> Option<u64> {
+    let candidates = Kind::detect(file).ok()?;
+    candidates.iter().find(|c| c.target == Some(target)).map(|c| c.offset)
 }
 
-pub fn extracted(pb: &ProgressBar, s: &str) {
+pub fn extracted(pb: &ProgressBar, s: impl fmt::Display) {
     let blue_bold: console::Style = console::Style::new().blue().bold();
-    let line = format!("{:>16} {}", blue_bold.apply_to("Extracted"), s,);
+    let line = format!("{:>16} {}", blue_bold.apply_to("Extracted"), s);
     pb.println(line);
 }
 
-pub fn created(pb: &ProgressBar, s: &str) {
+pub fn created(pb: &ProgressBar, s: impl fmt::Display) {
     let blue_bold: console::Style = console::Style::new().blue().bold();
-    let line = format!("{:>16} {}", blue_bold.apply_to("Created"), s,);
+    let line = format!("{:>16} {}", blue_bold.apply_to("Created"), s);
     pb.println(line);
 }
 
-pub fn exists(pb: &ProgressBar, s: &str) {
+pub fn exists(pb: &ProgressBar, s: impl fmt::Display) {
     let red_bold: console::Style = console::Style::new().red().bold();
-    let line = format!("{:>16} {}", red_bold.apply_to("Exists"), s,);
+    let line = format!("{:>16} {}", red_bold.apply_to("Exists"), s);
     pb.println(line);
 }
 
@@ -97,7 +94,7 @@ struct Args {
     #[arg(short, long, default_value_t = 0, name = "BYTES")]
     offset: u64,
 
-    /// Find first instance of squashfs --kind magic
+    /// Find first instance of squashfs --kind at any byte offset
     ///
     /// Will overwrite given --offset
     #[arg(short, long)]
@@ -143,6 +140,17 @@ struct Args {
               "be_v4_0",
               "le_v4_0",
               "avm_be_v4_0",
+              "be_v3_0",
+              "le_v3_0",
+              "be_v2_0",
+              "le_v2_0",
+              "be_v1_0",
+              "le_v1_0",
+              "shsq_le_v3_0",
+              "qshs_be_v3_0",
+              "sqlz_be_v3_0",
+              "brcm_le_v3_0",
+              "brcm_be_v3_0",
           ]
     ))]
     kind: String,
@@ -154,6 +162,10 @@ struct Args {
     /// Silence all progress bar and RUST_LOG output
     #[arg(long)]
     quiet: bool,
+
+    /// Do not restore extended attributes
+    #[arg(long)]
+    no_xattrs: bool,
 }
 
 fn main() -> ExitCode {
@@ -186,7 +198,7 @@ fn main() -> ExitCode {
             let line = format!("{:>14}", blue_bold.apply_to("Searching for magic"));
             pb.set_message(line);
         }
-        if let Some(found_offset) = find_offset(&mut file, &kind) {
+        if let Some(found_offset) = find_offset(&mut file, &args.kind) {
             if !args.quiet {
                 let line =
                     format!("{:>14} 0x{:08x}", blue_bold.apply_to("Found magic"), found_offset,);
@@ -212,6 +224,25 @@ fn main() -> ExitCode {
         Err(_e) => {
             let line = format!("{:>14}", red_bold.apply_to(format!("Could not read image: {_e}")));
             pb.finish_with_message(line);
+
+            // hint at the kinds and offsets that could be read
+            let mut file = BufReader::new(File::open(args.filesystem.as_ref().unwrap()).unwrap());
+            let candidates = Kind::detect(&mut file).unwrap_or_default();
+            for candidate in candidates.iter().filter(|c| c.confidence > Confidence::Magic) {
+                // only known kinds can be set with --kind
+                let Some(target) = candidate.target else {
+                    continue;
+                };
+                let line = format!(
+                    "{:>14} --kind {} --offset {} ({:?}: {})",
+                    blue_bold.apply_to("Possible image"),
+                    target,
+                    candidate.offset,
+                    candidate.confidence,
+                    candidate.reason
+                );
+                eprintln!("{line}");
+            }
             return ExitCode::FAILURE;
         }
     };
@@ -353,14 +384,42 @@ fn stat(args: Args, mut file: BufReader<File>, kind: Kind) {
     }
 }
 
+fn set_xattrs(pb: &ProgressBar, args: &Args, path: &Path, xattrs: &[Xattr]) {
+    if args.no_xattrs {
+        return;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    for xattr in xattrs {
+        let Ok(name) = CString::new(xattr.name.clone()) else {
+            continue;
+        };
+        let ret = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                xattr.value.as_ptr().cast(),
+                xattr.value.len(),
+                0,
+            )
+        };
+        if ret != 0 && !args.quiet {
+            let e = io::Error::last_os_error();
+            let line = format!("lsetxattr {} {} : {e}", path.display(), name.to_string_lossy());
+            failed(pb, &line);
+        }
+    }
+}
+
 fn set_attributes(
     pb: &ProgressBar,
     args: &Args,
     path: &Path,
-    header: &NodeHeader,
+    node: &Node<SquashfsFileReader>,
     root_process: bool,
     is_file: bool,
 ) {
+    let header = &node.header;
     // TODO Use (file_set_times) when not nightly: https://github.com/rust-lang/rust/issues/98245
     let timeval = TimeVal::new(header.mtime as _, 0);
     utimes(path, &timeval, &timeval).unwrap();
@@ -387,6 +446,9 @@ fn set_attributes(
         mode &= !0o022;
     }
 
+    // set after chown, as changing the owner clears security.capability
+    set_xattrs(pb, args, path, &node.xattrs);
+
     // set permissions
     //
     // NOTE: In squashfs-tools/unsquashfs they remove the write bits for user and group?
@@ -397,13 +459,42 @@ fn set_attributes(
             if fs::set_permissions(path, Permissions::from_mode(mode & !1000)).is_err()
                 && !args.quiet
             {
-                let line = format!("{} : could not set permissions", path.to_str().unwrap());
+                let line = format!("{} : could not set permissions", path.display());
                 failed(pb, &line);
             }
         }
     }
 }
 
+/// Copy the data of `file` into `writer`, seeking over sparse blocks to recreate the holes
+fn copy_sparse(
+    filesystem: &FilesystemReader,
+    file: &SquashfsFileReader,
+    writer: &mut BufWriter<&File>,
+    fd: &File,
+) -> io::Result<()> {
+    let block_size = filesystem.block_size as u64;
+    let file_len = file.file_len() as u64;
+    let mut reader = filesystem.file(file).reader();
+    let mut pos = 0;
+    for block in file.block_sizes() {
+        let len = block_size.min(file_len - pos);
+        pos += len;
+        if block.size() == 0 {
+            io::copy(&mut (&mut reader).take(len), &mut io::sink())?;
+            writer.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            io::copy(&mut (&mut reader).take(len), writer)?;
+        }
+    }
+    // remaining fragment
+    io::copy(&mut reader, writer)?;
+    writer.flush()?;
+
+    // a hole at the end of the file is not created by seeking alone
+    fd.set_len(file_len)
+}
+
 fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
     args: &Args,
     filesystem: &'a FilesystemReader,
@@ -441,10 +532,7 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
             let mut p = processing.lock().unwrap();
             p.insert(fullpath);
             pb.set_message(
-                p.iter()
-                    .map(|a| a.to_path_buf().into_os_string().into_string().unwrap())
-                    .collect::<Vec<String>>()
-                    .join(", "),
+                p.iter().map(|a| a.display().to_string()).collect::<Vec<String>>().join(", "),
             );
             pb.inc(1);
         }
@@ -459,7 +547,7 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 // check if file exists
                 if !args.force && filepath.exists() {
                     if !args.quiet {
-                        exists(&pb, filepath.to_str().unwrap());
+                        exists(&pb, filepath.display());
                         let mut p = processing.lock().unwrap();
                         p.remove(fullpath);
                     }
@@ -469,19 +557,17 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 // write to file
                 let fd = File::create(&filepath).unwrap();
                 let mut writer = BufWriter::with_capacity(file.file_len(), &fd);
-                let file = filesystem.file(file);
-                let mut reader = file.reader();
 
-                match io::copy(&mut reader, &mut writer) {
+                match copy_sparse(filesystem, file, &mut writer, &fd) {
                     Ok(_) => {
                         if args.info && !args.quiet {
-                            extracted(&pb, filepath.to_str().unwrap());
+                            extracted(&pb, filepath.display());
                         }
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
+                        set_attributes(&pb, args, &filepath, node, root_process, true);
                     }
                     Err(e) => {
                         if !args.quiet {
-                            let line = format!("{} : {e}", filepath.to_str().unwrap());
+                            let line = format!("{} : {e}", filepath.display());
                             failed(&pb, &line);
                             let mut p = processing.lock().unwrap();
                             p.remove(fullpath);
@@ -495,7 +581,7 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 let link_display = link.display();
                 // check if file exists
                 if !args.force && filepath.exists() {
-                    exists(&pb, filepath.to_str().unwrap());
+                    exists(&pb, filepath.display());
                     let mut p = processing.lock().unwrap();
                     p.remove(fullpath);
                     return;
@@ -504,14 +590,13 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 match std::os::unix::fs::symlink(link, &filepath) {
                     Ok(_) => {
                         if args.info && !args.quiet {
-                            let line = format!("{}->{link_display}", filepath.to_str().unwrap());
+                            let line = format!("{}->{link_display}", filepath.display());
                             created(&pb, &line);
                         }
                     }
                     Err(e) => {
                         if !args.quiet {
-                            let line =
-                                format!("{}->{link_display} : {e}", filepath.to_str().unwrap());
+                            let line = format!("{}->{link_display} : {e}", filepath.display());
                             failed(&pb, &line);
                             let mut p = processing.lock().unwrap();
                             p.remove(fullpath);
@@ -543,6 +628,8 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                     }
                 }
 
+                set_xattrs(&pb, args, &filepath, &node.xattrs);
+
                 // TODO Use (file_set_times) when not nightly: https://github.com/rust-lang/rust/issues/98245
                 // Make sure this doesn't follow symlinks when changed to std library!
                 let timespec = TimeSpec::new(node.header.mtime as _, 0);
@@ -555,7 +642,7 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 // don't display error if this was already created, we might have already
                 // created it in another thread to put down a file
                 if std::fs::create_dir(&filepath).is_ok() && args.info && !args.quiet {
-                    created(&pb, filepath.to_str().unwrap())
+                    created(&pb, filepath.display())
                 }
             }
             InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number }) => {
@@ -569,16 +656,16 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                     ) {
                         Ok(_) => {
                             if args.info && !args.quiet {
-                                created(&pb, filepath.to_str().unwrap());
+                                created(&pb, filepath.display());
                             }
 
-                            set_attributes(&pb, args, &filepath, &node.header, root_process, true);
+                            set_attributes(&pb, args, &filepath, node, root_process, true);
                         }
                         Err(_) => {
                             if !args.quiet {
                                 let line = format!(
                                     "char device {}, are you superuser?",
-                                    filepath.to_str().unwrap()
+                                    filepath.display()
                                 );
                                 failed(&pb, &line);
                                 let mut p = processing.lock().unwrap();
@@ -589,10 +676,8 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                     }
                 } else {
                     if !args.quiet {
-                        let line = format!(
-                            "char device {}, are you superuser?",
-                            filepath.to_str().unwrap()
-                        );
+                        let line =
+                            format!("char device {}, are you superuser?", filepath.display());
                         failed(&pb, &line);
                     }
                     let mut p = processing.lock().unwrap();
@@ -610,14 +695,14 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 ) {
                     Ok(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                         }
 
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
+                        set_attributes(&pb, args, &filepath, node, root_process, true);
                     }
                     Err(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                             let mut p = processing.lock().unwrap();
                             p.remove(fullpath);
                         }
@@ -632,14 +717,14 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 ) {
                     Ok(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                         }
 
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
+                        set_attributes(&pb, args, &filepath, node, root_process, true);
                     }
                     Err(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                         }
                         let mut p = processing.lock().unwrap();
                         p.remove(fullpath);
@@ -657,14 +742,14 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                 ) {
                     Ok(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                         }
 
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
+                        set_attributes(&pb, args, &filepath, node, root_process, true);
                     }
                     Err(_) => {
                         if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
+                            created(&pb, filepath.display());
                             let mut p = processing.lock().unwrap();
                             p.remove(fullpath);
                         }
@@ -672,18 +757,55 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
                     }
                 }
             }
+            // created once all other nodes are extracted, as the target must exist
+            InnerNode::Hardlink(_) => (),
         }
         let mut p = processing.lock().unwrap();
         p.remove(fullpath);
     });
 
+    // create hard links
+    for node in filesystem.files().filter(|a| a.fullpath.starts_with(&args.path_filter)) {
+        if let InnerNode::Hardlink(SquashfsHardlink { link }) = &node.inner {
+            let path = &node.fullpath;
+            let path = path.strip_prefix(Component::RootDir).unwrap_or(path);
+            let path = Path::new(&args.dest).join(path);
+            let link = link.strip_prefix(Component::RootDir).unwrap_or(link);
+            let link = Path::new(&args.dest).join(link);
+
+            // check if file exists
+            if path.exists() {
+                if !args.force {
+                    exists(&pb, path.display());
+                    continue;
+                }
+                let _ = fs::remove_file(&path);
+            }
+
+            match fs::hard_link(&link, &path) {
+                Ok(_) => {
+                    if args.info && !args.quiet {
+                        let line = format!("{}=>{}", path.display(), link.display());
+                        created(&pb, &line);
+                    }
+                }
+                Err(e) => {
+                    if !args.quiet {
+                        let line = format!("{}=>{} : {e}", path.display(), link.display());
+                        failed(&pb, &line);
+                    }
+                }
+            }
+        }
+    }
+
     // fixup dir permissions
     for node in filesystem.files().filter(|a| a.fullpath.starts_with(&args.path_filter)) {
         if let InnerNode::Dir(SquashfsDir { .. }) = &node.inner {
             let path = &node.fullpath;
             let path = path.strip_prefix(Component::RootDir).unwrap_or(path);
             let path = Path::new(&args.dest).join(path);
-            set_attributes(&pb, args, &path, &node.header, root_process, false);
+            set_attributes(&pb, args, &path, node, root_process, false);
         }
     }
 
@@ -25,6 +25,9 @@ pub fn after_help(rayon_env: bool) -> String {
     #[cfg(feature = "xz")]
     s.push_str(color_print::cstr!("  <cyan, bold>xz\n"));
 
+    #[cfg(feature = "lzma")]
+    s.push_str(color_print::cstr!("  <cyan, bold>lzma\n"));
+
     #[cfg(feature = "lzo")]
     s.push_str(color_print::cstr!("  <cyan, bold>lzo\n"));
 
@@ -0,0 +1,196 @@
+use std::io::{Cursor, Read};
+
+use backhand::compression::{CompressionOptions, Compressor, Gzip, GzipStrategies};
+use backhand::{
+    FilesystemCompressor, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, SuperBlock,
+    DEFAULT_BLOCK_SIZE,
+};
+use test_log::test;
+
+/// Write files of compressible data with `compressor`, and check the read back files
+fn roundtrip(id: Compressor, options: Option<CompressionOptions>) -> (SuperBlock, Vec<u8>) {
+    let (superblock, bytes) = roundtrip_with(FilesystemCompressor::new(id, options).unwrap());
+    assert_eq!(superblock.compressor, id);
+    (superblock, bytes)
+}
+
+fn roundtrip_with(compressor: FilesystemCompressor) -> (SuperBlock, Vec<u8>) {
+    let files: Vec<(String, Vec<u8>)> = [10, 0x1000, DEFAULT_BLOCK_SIZE as usize * 2 + 10]
+        .into_iter()
+        .map(|len| {
+            let data = (0..len).map(|n| b"backhand"[n % 8] ^ (n / 0x1000) as u8).collect();
+            (format!("file_{len}"), data)
+        })
+        .collect();
+
+    let mut fs = FilesystemWriter::default();
+    fs.set_compressor(compressor);
+    for (name, data) in &files {
+        fs.push_file(Cursor::new(data.clone()), name, NodeHeader::default()).unwrap();
+    }
+    let mut bytes = Cursor::new(vec![]);
+    let (superblock, _) = fs.write(&mut bytes).unwrap();
+    let bytes = bytes.into_inner();
+
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.clone())).unwrap();
+    for (name, data) in &files {
+        let path = format!("/{name}");
+        let node = reader.files().find(|node| node.fullpath.to_str() == Some(&path)).unwrap();
+        let InnerNode::File(file) = &node.inner else { panic!("expected file") };
+        let mut read = vec![];
+        reader.file(file).reader().read_to_end(&mut read).unwrap();
+        assert_eq!(&read, data);
+    }
+    (superblock, bytes)
+}
+
+#[test]
+#[cfg(feature = "lzma")]
+fn test_lzma_roundtrip() {
+    let (_, bytes) = roundtrip(Compressor::Lzma, None);
+
+    // squashfs-tools requires the uncompressed size in the lzma-alone header of each block
+    let first_block = &bytes[96..];
+    assert_eq!(first_block[0], 0x5d);
+    let size = u64::from_le_bytes(first_block[5..13].try_into().unwrap());
+    assert_eq!(size, u64::from(DEFAULT_BLOCK_SIZE));
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn test_gzip_options() {
+    let gzip = |window_size, strategies| {
+        let options =
+            Gzip { compression_level: 9, window_size, strategies: GzipStrategies::new(strategies) };
+        roundtrip(Compressor::Gzip, Some(CompressionOptions::Gzip(options))).0.bytes_used
+    };
+    let all = GzipStrategies::DEFAULT
+        | GzipStrategies::FILTERED
+        | GzipStrategies::HUFFMAN_ONLY
+        | GzipStrategies::RLE
+        | GzipStrategies::FIXED;
+
+    for window_size in [8, 15] {
+        // the smallest of all the strategies is kept
+        let smallest = gzip(window_size, all);
+        for strategy in 0..5 {
+            assert!(smallest <= gzip(window_size, 1 << strategy));
+        }
+        assert!(smallest < gzip(window_size, GzipStrategies::HUFFMAN_ONLY));
+    }
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn test_lz4_hc() {
+    use backhand::compression::{Lz4, Lz4Flags};
+
+    let lz4 = |flags| {
+        let options = Lz4 { version: 1, flags: Lz4Flags::new(flags) };
+        roundtrip(Compressor::Lz4, Some(CompressionOptions::Lz4(options))).0.bytes_used
+    };
+    assert!(lz4(Lz4Flags::HC) <= lz4(0));
+}
+
+#[test]
+#[cfg(feature = "lzo")]
+fn test_lzo_algorithms() {
+    use backhand::compression::{Lzo, LzoAlgorithm};
+
+    let lzo = |algorithm, compression_level| {
+        let options = Lzo { algorithm, compression_level };
+        roundtrip(Compressor::Lzo, Some(CompressionOptions::Lzo(options))).0.bytes_used
+    };
+    let lzo1x_1 = lzo(LzoAlgorithm::Lzo1x1, 0);
+    for algorithm in [LzoAlgorithm::Lzo1x1_11, LzoAlgorithm::Lzo1x1_12, LzoAlgorithm::Lzo1x1_15] {
+        lzo(algorithm, 0);
+    }
+    for compression_level in 1..=9 {
+        lzo(LzoAlgorithm::Lzo1x999, compression_level);
+    }
+    assert!(lzo(LzoAlgorithm::Lzo1x999, 9) <= lzo1x_1);
+
+    // only lzo1x_999 has a compression level
+    let invalid =
+        [(LzoAlgorithm::Lzo1x1, 1), (LzoAlgorithm::Lzo1x999, 0), (LzoAlgorithm::Lzo1x999, 10)];
+    for (algorithm, compression_level) in invalid {
+        let options = Lzo { algorithm, compression_level };
+        assert!(FilesystemCompressor::new(Compressor::Lzo, Some(CompressionOptions::Lzo(options)))
+            .is_err());
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_xz_openwrt_options() {
+    use backhand::compression::{Xz, XzFilter};
+
+    for dictionary_size_last in [false, true] {
+        let options = Xz {
+            dictionary_size: 0x20000,
+            filters: XzFilter::new(0),
+            bit_opts: Some(2 << 3 | 2 << 6),
+            fb: Some(64),
+            dictionary_size_last,
+        };
+        let (_, bytes) = roundtrip(Compressor::Xz, Some(CompressionOptions::Xz(options)));
+        let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.compression_options, Some(CompressionOptions::Xz(options)));
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_xz_invalid_openwrt_options() {
+    use backhand::compression::{Xz, XzFilter};
+
+    // lc + lp > 4, pb > 4, nice_len out of 2..=273
+    for (bit_opts, fb) in
+        [(Some(3 | 2 << 3), None), (Some(5 << 6), None), (None, Some(1)), (Some(7), Some(1000))]
+    {
+        let options = CompressionOptions::Xz(Xz {
+            dictionary_size: 0x20000,
+            filters: XzFilter::new(0),
+            bit_opts,
+            fb,
+            dictionary_size_last: false,
+        });
+        assert!(FilesystemCompressor::new(Compressor::Xz, Some(options)).is_err());
+        let mut compressor = FilesystemCompressor::new(Compressor::Xz, None).unwrap();
+        assert!(compressor.options(options).is_err());
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_xz_filter_selection() {
+    use backhand::compression::{Xz, XzFilter};
+    use backhand::{CompressionExtra, ExtraXz};
+
+    let options = Xz {
+        dictionary_size: DEFAULT_BLOCK_SIZE,
+        filters: XzFilter::new(0),
+        bit_opts: None,
+        fb: None,
+        dictionary_size_last: false,
+    };
+    let plain = roundtrip(Compressor::Xz, Some(CompressionOptions::Xz(options))).0.bytes_used;
+
+    let filters = XzFilter::new(XzFilter::X86 | XzFilter::ARM | XzFilter::ARMTHUMB);
+    let mut extra = ExtraXz::default();
+    extra.filters(filters).unwrap();
+    let mut compressor = FilesystemCompressor::new(Compressor::Xz, None).unwrap();
+    compressor.extra(CompressionExtra::Xz(extra)).unwrap();
+    let (superblock, bytes) = roundtrip_with(compressor);
+
+    // no filter is also tried for each block
+    assert!(superblock.bytes_used <= plain);
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+    let Some(CompressionOptions::Xz(xz)) = reader.compression_options else {
+        panic!("expected xz options")
+    };
+    assert_eq!(xz.filters, filters);
+    assert_eq!(xz.dictionary_size, DEFAULT_BLOCK_SIZE);
+
+    assert!(ExtraXz::default().filters(XzFilter::new(0x40)).is_err());
+}
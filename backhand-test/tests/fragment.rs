@@ -0,0 +1,108 @@
+use std::io::{Cursor, Read};
+
+use backhand::{
+    FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, SuperBlock, DEFAULT_BLOCK_SIZE,
+};
+use test_log::test;
+
+/// Files a bit larger than a block, with differing tail-ends
+fn files() -> Vec<(String, Vec<u8>)> {
+    (0..8_u8)
+        .map(|i| {
+            let len = DEFAULT_BLOCK_SIZE as usize + 1000 * (usize::from(i) + 1);
+            let data = (0..len).map(|n| (n as u8).wrapping_mul(i + 1) ^ (n >> 8) as u8).collect();
+            (format!("file_{i}"), data)
+        })
+        .collect()
+}
+
+/// Write the image, returning it with its superblock
+fn write(fs: &mut FilesystemWriter) -> (Vec<u8>, SuperBlock) {
+    let mut bytes = Cursor::new(vec![]);
+    let (superblock, _) = fs.write(&mut bytes).unwrap();
+    (bytes.into_inner(), superblock)
+}
+
+fn check(reader: &FilesystemReader, files: &[(String, Vec<u8>)]) {
+    for (name, data) in files {
+        let path = format!("/{name}");
+        let node = reader.files().find(|node| node.fullpath.to_str() == Some(&path)).unwrap();
+        let InnerNode::File(file) = &node.inner else { panic!("expected file") };
+        let mut read = vec![];
+        reader.file(file).reader().read_to_end(&mut read).unwrap();
+        assert_eq!(&read, data);
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_tail_ends_roundtrip() {
+    let files = files();
+    let writer = |tail_ends| {
+        let mut fs = FilesystemWriter::default();
+        fs.set_tail_ends(tail_ends);
+        for (name, data) in &files {
+            fs.push_file(Cursor::new(data.clone()), name, NodeHeader::default()).unwrap();
+        }
+        write(&mut fs)
+    };
+    let (_, without) = writer(false);
+    assert!(!without.fragments_are_always_generated());
+    let (bytes, with) = writer(true);
+    assert!(with.fragments_are_always_generated());
+    assert!(with.bytes_used < without.bytes_used);
+
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+    check(&reader, &files);
+
+    // kept when copying the raw blocks
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    let (bytes, superblock) = write(&mut fs);
+    assert!(superblock.fragments_are_always_generated());
+    assert_eq!(superblock.bytes_used, with.bytes_used);
+    check(&FilesystemReader::from_reader(Cursor::new(bytes)).unwrap(), &files);
+
+    // and stored as blocks when disabled
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    fs.set_tail_ends(false);
+    let (bytes, superblock) = write(&mut fs);
+    assert!(!superblock.fragments_are_always_generated());
+    assert_eq!(superblock.bytes_used, without.bytes_used);
+    check(&FilesystemReader::from_reader(Cursor::new(bytes)).unwrap(), &files);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_no_fragments_roundtrip() {
+    let mut files = files();
+    files.push(("small".to_string(), b"small file".to_vec()));
+    files.push(("empty".to_string(), vec![]));
+
+    let mut fs = FilesystemWriter::default();
+    for (name, data) in &files {
+        fs.push_file(Cursor::new(data.clone()), name, NodeHeader::default()).unwrap();
+    }
+    let (bytes, superblock) = write(&mut fs);
+    assert!(superblock.frag_count > 0);
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+
+    // fragments of the image are stored as blocks when copied
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    fs.set_no_fragments(true);
+    fs.set_tail_ends(true);
+    let (bytes, superblock) = write(&mut fs);
+    assert!(superblock.fragments_are_not_used());
+    assert!(!superblock.fragments_are_always_generated());
+    assert_eq!(superblock.frag_count, 0);
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+    check(&reader, &files);
+
+    // kept when re-writing the image, with a new file
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    fs.push_file(Cursor::new(b"new".to_vec()), "new", NodeHeader::default()).unwrap();
+    let (bytes, superblock) = write(&mut fs);
+    assert!(superblock.fragments_are_not_used());
+    assert_eq!(superblock.frag_count, 0);
+    files.push(("new".to_string(), b"new".to_vec()));
+    check(&FilesystemReader::from_reader(Cursor::new(bytes)).unwrap(), &files);
+}
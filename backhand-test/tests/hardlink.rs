@@ -0,0 +1,69 @@
+use std::io::{Cursor, Read};
+
+use backhand::{
+    BackhandError, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, Squashfs,
+    SquashfsFileReader, SquashfsHardlink,
+};
+use test_log::test;
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_hardlink_roundtrip() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir("dir", header).unwrap();
+    fs.push_file(Cursor::new(b"shared data"), "file", header).unwrap();
+    fs.push_hardlink("file", "dir/link").unwrap();
+    // link of a link, points to the original
+    fs.push_hardlink("dir/link", "link").unwrap();
+    fs.push_symlink("file", "symlink", header).unwrap();
+    fs.push_hardlink("symlink", "dir/symlink").unwrap();
+
+    assert!(matches!(fs.push_hardlink("dir", "dir_link"), Err(BackhandError::InvalidFilePath)));
+    assert!(matches!(
+        fs.push_hardlink("missing", "missing_link"),
+        Err(BackhandError::FileNotFound)
+    ));
+
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let bytes = bytes.into_inner();
+
+    // root, dir, file, symlink
+    let squashfs = Squashfs::from_reader(Cursor::new(bytes.clone())).unwrap();
+    assert_eq!(squashfs.superblock.inode_count, 4);
+
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+    let mut files = vec![];
+    let mut links = vec![];
+    for node in reader.files() {
+        match &node.inner {
+            InnerNode::File(file) => {
+                let SquashfsFileReader::Extended(extended) = file else {
+                    panic!("linked file must be extended");
+                };
+                assert_eq!(extended.link_count, 3);
+                let mut data = vec![];
+                reader.file(file).reader().read_to_end(&mut data).unwrap();
+                assert_eq!(data, b"shared data");
+                files.push(node.fullpath.clone());
+            }
+            InnerNode::Hardlink(SquashfsHardlink { link }) => {
+                links.push((node.fullpath.clone(), link.clone()))
+            }
+            _ => (),
+        }
+    }
+    assert_eq!(files.len(), 1);
+    assert_eq!(links.len(), 3);
+    let file_links = links.iter().filter(|(_, link)| *link == files[0]).count();
+    assert_eq!(file_links, 2);
+
+    // written again, links are kept
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let squashfs = Squashfs::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    assert_eq!(squashfs.superblock.inode_count, 4);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use assert_cmd::prelude::*;
+use tempfile::tempdir;
+use test_log::test;
+
+// These images are written by the image builders of the backhand/src/legacy.rs tests, not by
+// squashfs-tools, sqlzma or vendor tools, and only check extracting the kinds from the command
+// line. Each holds `/hello`, `/link` to it, the char device `/null` and the empty directory
+// `/sub`, listed in a root directory with an index for v2.x and later. v1.x and v2.x images also
+// hold the named pipe `/fifo`.
+
+/// v3.1 little-endian image, with check data in the metadata blocks
+const LE_V3_1: &[&str] = &[
+    "6873717305000000000000000000000000000000000000000000000003000100",
+    "000011000401010000000069000000000000000000020000000000000000005b",
+    "010000000000005301000000000000570100000000000082000000000000001a",
+    "010000000000005301000000000000ffffffffffffffff68656c6c6f20776f72",
+    "6c649580ff421a00ff00000000010000007700000000000000ffffffff000000",
+    "000b0000000b000001f31f0000000000000200000001000000050068656c6c6f",
+    "651b00000000000003000000010000000301d11e000000000000040000000200",
+    "0000030000000000000005000000d81e00000000000005000000030000003900",
+    "000000000000000100060000001e00000000000000036e756c6c3680ff010000",
+    "000001000000004004000068656c6c6f24600301006c696e6b01000000000300",
+    "00003ba00300006e756c6c4d20020100737562e803000064000000",
+];
+
+/// v3.0 big-endian image
+const BE_V3_0: &[&str] = &[
+    "7371736800000005000000000000000000000000000000000000000000030000",
+    "0000001100010100000000000000000000006900020000000000000000000000",
+    "000000000001510000000000000149000000000000014d000000000000007a00",
+    "00000000000111000000000000014968656c6c6f20776f726c64809521a400ff",
+    "0000000000000001000000000000006fffffffff000000000000000b0100000b",
+    "31ff0000000000000000000200000001000568656c6c6f51b600000000000000",
+    "00000300000001010311ed000000000000000000040000000200006000000000",
+    "000000000581ed00000000000000000005000000030000072000000000000001",
+    "000000060000001e00000000036e756c6c803601000000000000000100020400",
+    "0068656c6c6f01230300016c696e6b01000000000000000301dd0300006e756c",
+    "6c0269020001737562000003e800000064",
+];
+
+/// v2.1 little-endian image
+const LE_V2_1: &[&str] = &[
+    "687371730600000029010000e1000000250100004a000000a600000002000100",
+    "00800f00001101000000003c000000000000000080000001000000dd00000068",
+    "656c6c6f20776f726c645a80461a0000421a00ff000000000000000000000000",
+    "000000000b000000f31f1000050068656c6c6f651b00000301d11e0000030000",
+    "0000000000000000d81e00002e000000000000000000000001001a0000000000",
+    "00036e756c6c2b800200000000c0036669666f04400468656c6c6f1c60036c69",
+    "6e6b0100000027a0036e756c6c2d200273756208803f0000000b000001d30000",
+    "00e8030000e9030000ea030000eb030000ec030000ed030000ee030000ef0300",
+    "00f0030000f1030000f2030000f3030000f4030000f5030000f6030000f70300",
+    "00f803000064000000",
+];
+
+/// v2.0 big-endian image
+const BE_V2_0: &[&str] = &[
+    "737173680000000600000129000000e1000001250000004a000000a600020000",
+    "8000000f00110100000000000000000000003c0000800000000001000000dd68",
+    "656c6c6f20776f726c64805a61a4000021a400ff000000000000000000000000",
+    "000000000000000b31ff1000000568656c6c6f51b60000010311ed0000000060",
+    "000000000000000081ed0000000005c000000000000000000001000003400000",
+    "00036e756c6c802b020000000006036669666f00220468656c6c6f00e3036c69",
+    "6e6b01000000013d036e756c6c01690273756280080000003f0100000b000000",
+    "d3000003e8000003e9000003ea000003eb000003ec000003ed000003ee000003",
+    "ef000003f0000003f1000003f2000003f3000003f4000003f5000003f6000003",
+    "f7000003f800000064",
+];
+
+/// v1.0 little-endian image
+const LE_V1_0: &[&str] = &[
+    "6873717306000000f5000000ad000000f10000003e0000008000000001000000",
+    "00800f0000110100000000320000000000000068656c6c6f20776f726c644080",
+    "401a0006421af000000000330000000b0000000b80f81f00050068656c6c6f65",
+    "1b000301d11e000300000000000000000000d11e002e00000000000000000000",
+    "2b800200000000c0036669666f04400468656c6c6f1560036c696e6b01000000",
+    "1fa0036e756c6c242002737562e8030000e9030000ea030000eb030000ec0300",
+    "00ed030000ee030000ef030000f0030000f1030000f2030000f3030000f40300",
+    "00f5030000f6030000f7030000f803000064000000",
+];
+
+/// `shsq` little-endian image of sqlzma, with zlib blocks
+const SHSQ_LE_V3_0: &[&str] = &[
+    "7368737105000000000000000000000000000000000000000000000003000000",
+    "0000110000010100000000690000000000000000000200000000000000000022",
+    "010000000000001a010000000000001e010000000000008200000000000000e0",
+    "000000000000001a01000000000000789ccb48cdc9c95728cf2fca4901001a0b",
+    "045d5c00789c2dcab10d80300c44d11f426828e900c11cb4ec1289c2221583d2",
+    "b107031899d8d5f9dded230a1080620150d5df7a6000deb57ae3bbc491454a9e",
+    "2a47e718eea54aeb636bec12f07867d97cf38e4007ccfec5f312f900f7ea0db1",
+    "3800789c63646060606004110e2c0c0c19a93939f92a09cc8c0c399979d96061",
+    "66060606eb05cc0c0c79a53939be0a4c8c0cc5a54900c3db08e8e80300006400",
+    "0000",
+];
+
+/// `sqlz` big-endian image, with LZMA blocks with the 13 byte header of lzma-alone
+const SQLZ_BE_V3_0: &[&str] = &[
+    "73716c7a00000005000000000000000000000000000000000000000000030000",
+    "0000001100010100000000000000000000006900020000000000000000000000",
+    "0000000000013900000000000001310000000000000135000000000000009100",
+    "000000000000ef00000000000001315d000080000b0000000000000000341949",
+    "ee8de917893a336005f7cf64fffb782000005c5d000080009500000000000000",
+    "0010a8fc0ff086a95a1d47a4aecd39cbb9cf5fbd189842e9a32f43abd02b8c8f",
+    "d8cda419474f8670eee6b0257ac040bc83f1b85d31d31720ce9c76d01e5dd79d",
+    "75a1f8210d33820839d0ffe0b5800000405d0000800036000000000000000000",
+    "803dd3f870cb2e4fe03376163c70a096a7fc4aefe5f0e97212a8718f6ea52140",
+    "1daa358c9aea52c1ec6697d5fef8a4e680000003e800000064",
+];
+
+/// Broadcom little-endian image, with LZMA blocks with only the 5 byte properties
+const BRCM_LE_V3_0: &[&str] = &[
+    "6873717305000000000000000000000000000000000000000000000003000000",
+    "0000110000010100000000690000000000000000000200000000000000000027",
+    "010000000000001f0100000000000023010000000000008900000000000000e3",
+    "000000000000001f010000000000005d0000800000341949ee8de917893a3360",
+    "05f7cf64fffb78200058005d000080000021067c0fed05e3f46e6df4a105a1b1",
+    "628ef7fbb5428e55139fb904dfc7be8a6876d3afe779d0050c0f8938c7181d87",
+    "ad6325f17a491cac3793c53c7c898c24f242549af56c6ac597359bdb1816ffc6",
+    "aa00003a005d000080000000803cf0e035e6e433c9cbd01b79655bda7088cbba",
+    "823f7369f07eff208565ce4280520b6b1ab81f0f395328a69ab1ffdf4e3000e8",
+    "03000064000000",
+];
+
+fn image(hex: &[&str]) -> Vec<u8> {
+    let hex = hex.concat();
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+/// List and extract `image` with `unsquashfs-backhand --kind <kind>`, checking it lists `paths`
+fn extract(image: &[u8], kind: &str, paths: &str) {
+    let tmp_dir = tempdir().unwrap();
+    let image_path = tmp_dir.path().join("image.squashfs");
+    fs::write(&image_path, image).unwrap();
+
+    let cmd = common::get_base_command("unsquashfs-backhand")
+        .env("RUST_LOG", "none")
+        .args(["--kind", kind, "-l", "--quiet"])
+        .arg(&image_path)
+        .unwrap();
+    cmd.assert().stdout(paths.to_string());
+
+    let out = tmp_dir.path().join("out");
+    let cmd = common::get_base_command("unsquashfs-backhand")
+        .env("RUST_LOG", "none")
+        .args(["--kind", kind, "--quiet", "-d"])
+        .arg(&out)
+        .arg(&image_path)
+        .unwrap();
+    cmd.assert().code(&[0] as &[i32]);
+    assert_eq!(fs::read(out.join("hello")).unwrap(), b"hello world");
+    assert_eq!(fs::read_link(out.join("link")).unwrap(), Path::new("hello"));
+    assert!(out.join("sub").is_dir());
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn test_unsquashfs_v3() {
+    let paths = "/\n/hello\n/link\n/null\n/sub\n";
+    extract(&image(LE_V3_1), "le_v3_0", paths);
+    extract(&image(BE_V3_0), "be_v3_0", paths);
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn test_unsquashfs_v1_v2() {
+    let paths = "/\n/fifo\n/hello\n/link\n/null\n/sub\n";
+    extract(&image(LE_V2_1), "le_v2_0", paths);
+    extract(&image(BE_V2_0), "be_v2_0", paths);
+    extract(&image(LE_V1_0), "le_v1_0", paths);
+}
+
+#[test]
+#[cfg(all(feature = "gzip", feature = "lzma"))]
+fn test_unsquashfs_vendor() {
+    let paths = "/\n/hello\n/link\n/null\n/sub\n";
+    extract(&image(SHSQ_LE_V3_0), "shsq_le_v3_0", paths);
+    extract(&image(SQLZ_BE_V3_0), "sqlz_be_v3_0", paths);
+    extract(&image(BRCM_LE_V3_0), "brcm_le_v3_0", paths);
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use assert_cmd::prelude::*;
+use backhand::{FilesystemReader, FilesystemWriter, NodeHeader};
+use tempfile::tempdir;
+use test_log::test;
+
+fn paths(reader: &FilesystemReader) -> Vec<PathBuf> {
+    reader.files().map(|node| node.fullpath.clone()).collect()
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_non_utf8_names_roundtrip() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    // latin-1 "café" and shift-jis "テスト"
+    let latin1 = PathBuf::from(OsStr::from_bytes(b"/caf\xe9"));
+    let sjis = latin1.join(OsStr::from_bytes(b"\x83e\x83X\x83g"));
+    let link = latin1.join(OsStr::from_bytes(b"link\xff"));
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir(&latin1, header).unwrap();
+    fs.push_file(Cursor::new(b"hello".to_vec()), &sjis, header).unwrap();
+    fs.push_symlink(OsStr::from_bytes(b"\x83e\x83X\x83g"), &link, header).unwrap();
+
+    // sorted by raw bytes
+    let expected = vec![PathBuf::from("/"), latin1.clone(), link.clone(), sjis.clone()];
+
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    assert_eq!(paths(&reader), expected);
+
+    // written again, names are kept byte for byte
+    let tmp_dir = tempdir().unwrap();
+    let image_path = tmp_dir.path().join("out.squashfs");
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    fs.write(File::create(&image_path).unwrap()).unwrap();
+    let reader =
+        FilesystemReader::from_reader(BufReader::new(File::open(&image_path).unwrap())).unwrap();
+    assert_eq!(paths(&reader), expected);
+
+    // add a file under the non-utf8 dir
+    let new_file = tmp_dir.path().join("new");
+    fs::write(&new_file, b"new").unwrap();
+    let added = latin1.join(OsStr::from_bytes(b"new\xfe"));
+    let added_image = tmp_dir.path().join("added.squashfs");
+    let cmd = common::get_base_command("add-backhand")
+        .env("RUST_LOG", "none")
+        .arg(&image_path)
+        .arg(&added)
+        .arg(&added_image)
+        .arg("--file")
+        .arg(&new_file)
+        .unwrap();
+    cmd.assert().code(0);
+
+    // replace the shift-jis file
+    let replaced_image = tmp_dir.path().join("replaced.squashfs");
+    let cmd = common::get_base_command("replace-backhand")
+        .env("RUST_LOG", "none")
+        .arg(&added_image)
+        .arg(&new_file)
+        .arg(&sjis)
+        .arg(&replaced_image)
+        .unwrap();
+    cmd.assert().code(0);
+
+    // extract
+    let dest = tmp_dir.path().join("squashfs-root");
+    let cmd = common::get_base_command("unsquashfs-backhand")
+        .env("RUST_LOG", "none")
+        .arg("--quiet")
+        .arg("-d")
+        .arg(&dest)
+        .arg(&replaced_image)
+        .unwrap();
+    cmd.assert().code(0);
+
+    let extracted = |path: &Path| dest.join(path.strip_prefix("/").unwrap());
+    assert!(extracted(&latin1).is_dir());
+    assert_eq!(fs::read(extracted(&sjis)).unwrap(), b"new");
+    assert_eq!(fs::read(extracted(&added)).unwrap(), b"new");
+    assert_eq!(
+        fs::read_link(extracted(&link)).unwrap(),
+        Path::new(OsStr::from_bytes(b"\x83e\x83X\x83g"))
+    );
+}
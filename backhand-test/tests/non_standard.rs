@@ -171,3 +171,70 @@ fn test_custom_compressor() {
     const TEST_PATH: &str = "test-assets/custom_compressor";
     full_test(&asset_defs, FILE_NAME, TEST_PATH, 0, &kind, Some(0));
 }
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_custom_transformer() {
+    use std::io::{Cursor, Read};
+
+    use backhand::transformation::TransformAction;
+    use backhand::{InnerNode, NodeHeader, Xattr};
+
+    /// Obfuscate all blocks by adding a key to each byte
+    #[derive(Copy, Clone)]
+    pub struct AddTransformer;
+
+    impl TransformAction for AddTransformer {
+        fn from(&self, buf: &mut [u8], _skip: Option<usize>) -> Result<(), BackhandError> {
+            buf.iter_mut().for_each(|byte| *byte = byte.wrapping_sub(0x5a));
+            Ok(())
+        }
+
+        fn to(&self, buf: &mut [u8], _skip: Option<usize>) -> Result<(), BackhandError> {
+            buf.iter_mut().for_each(|byte| *byte = byte.wrapping_add(0x5a));
+            Ok(())
+        }
+    }
+
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let big: Vec<u8> = (0..0x50000).map(|i| (i % 251) as u8).collect();
+    let xattrs = vec![Xattr::new("user.comment", "obfuscated")];
+    let check = |reader: &FilesystemReader| {
+        let node =
+            |path: &str| reader.files().find(|node| node.fullpath.to_str() == Some(path)).unwrap();
+        for (path, expected) in [("/big", &big[..]), ("/dir/small", b"hello")] {
+            let InnerNode::File(file) = &node(path).inner else { panic!("expected file") };
+            let mut data = vec![];
+            reader.file(file).reader().read_to_end(&mut data).unwrap();
+            assert_eq!(data, expected);
+        }
+        assert_eq!(node("/dir/small").xattrs, xattrs);
+    };
+
+    let kind = Kind::new_with_transformer(&AddTransformer);
+    let mut fs = FilesystemWriter::default();
+    fs.set_kind(Kind::from_kind(&kind));
+    fs.set_export_table(true);
+    fs.push_dir("dir", header).unwrap();
+    fs.push_file(Cursor::new(big.clone()), "big", header).unwrap();
+    fs.push_file(Cursor::new(b"hello"), "dir/small", header).unwrap();
+    fs.set_xattrs("dir/small", xattrs.clone()).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let bytes = bytes.into_inner();
+
+    // tables and blocks can't be read without the transformer
+    assert!(FilesystemReader::from_reader(Cursor::new(bytes.clone())).is_err());
+
+    let reader =
+        FilesystemReader::from_reader_with_offset_and_kind(Cursor::new(bytes), 0, kind).unwrap();
+    check(&reader);
+
+    // copied data blocks are transformed back when written with the default transformer
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    fs.set_kind(Kind::from_const(kind::LE_V4_0).unwrap());
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    check(&reader);
+}
@@ -0,0 +1,44 @@
+use std::io::{Cursor, Read};
+
+use backhand::{
+    FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, SquashfsFileReader,
+    DEFAULT_BLOCK_SIZE,
+};
+use test_log::test;
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_sparse_roundtrip() {
+    let block_size = DEFAULT_BLOCK_SIZE as usize;
+    // hole, data, hole, partial hole at the end
+    let mut data = vec![0_u8; block_size * 3 + 100];
+    data[block_size..block_size * 2].fill(0xaa);
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(data.clone()), "sparse", NodeHeader::default()).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let bytes = bytes.into_inner();
+    assert!(bytes.len() < block_size);
+
+    let check = |reader: &FilesystemReader| {
+        let node = reader.files().find(|node| node.fullpath.to_str() == Some("/sparse")).unwrap();
+        let InnerNode::File(file) = &node.inner else { panic!("expected file") };
+        let SquashfsFileReader::Extended(extended) = file else {
+            panic!("sparse file must be extended");
+        };
+        assert_eq!(extended.sparse, block_size as u64 * 2 + 100);
+        let mut read = vec![];
+        reader.file(file).reader().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    };
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes)).unwrap();
+    check(&reader);
+
+    // written again, copying the raw blocks
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    check(&reader);
+}
@@ -0,0 +1,59 @@
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use backhand::{BackhandError, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, Xattr};
+use test_log::test;
+
+fn links(reader: &FilesystemReader) -> Vec<(PathBuf, PathBuf)> {
+    reader
+        .files()
+        .filter_map(|node| match &node.inner {
+            InnerNode::Symlink(symlink) => Some((node.fullpath.clone(), symlink.link.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_symlink_long_and_binary_roundtrip() {
+    let header = NodeHeader { permissions: 0o777, uid: 0, gid: 0, mtime: 0 };
+    let long: PathBuf = (0..100).map(|i| format!("generated_dir_{i:03}")).collect();
+    let max = PathBuf::from("a".repeat(4096));
+    let binary = PathBuf::from(OsStr::from_bytes(b"target\xff\xfe\x80/\x01name"));
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_symlink(long.clone(), "long", header).unwrap();
+    fs.push_symlink(long.clone(), "long_xattr", header).unwrap();
+    fs.push_symlink(max.clone(), "max", header).unwrap();
+    fs.push_symlink(binary.clone(), "binary", header).unwrap();
+    fs.set_xattrs("long_xattr", vec![Xattr::new("user.generated", "yes")]).unwrap();
+
+    // longer than PATH_MAX
+    let too_long = PathBuf::from("a".repeat(4097));
+    assert!(matches!(
+        fs.push_symlink(too_long, "too_long", header),
+        Err(BackhandError::InvalidFilePath)
+    ));
+
+    let expected = vec![
+        (PathBuf::from("/binary"), binary),
+        (PathBuf::from("/long"), long.clone()),
+        (PathBuf::from("/long_xattr"), long),
+        (PathBuf::from("/max"), max),
+    ];
+
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    assert_eq!(links(&reader), expected);
+
+    // written again, links are kept
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    assert_eq!(links(&reader), expected);
+}
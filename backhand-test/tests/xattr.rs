@@ -0,0 +1,88 @@
+use std::io::Cursor;
+
+use backhand::{BackhandError, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, Xattr};
+use test_log::test;
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_xattr_roundtrip() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let shared = vec![Xattr::new("user.shared", b"same for everyone, and long enough".to_vec())];
+    let capability = vec![
+        Xattr::new("security.capability", vec![0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00]),
+        Xattr::new("trusted.overlay.opaque", "y"),
+        Xattr::new("user.comment", b"same for everyone, and long enough".to_vec()),
+    ];
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir("dir", header).unwrap();
+    fs.push_file(Cursor::new(b"first"), "dir/first", header).unwrap();
+    fs.push_file(Cursor::new(b"second"), "dir/second", header).unwrap();
+    fs.push_file(Cursor::new(b"plain"), "plain", header).unwrap();
+    fs.set_xattrs("/", vec![Xattr::new("user.root", "")]).unwrap();
+    fs.set_xattrs("dir", shared.clone()).unwrap();
+    fs.set_xattrs("dir/first", shared.clone()).unwrap();
+    fs.set_xattrs("dir/second", capability.clone()).unwrap();
+
+    // unknown prefix
+    assert!(matches!(
+        fs.set_xattrs("plain", vec![Xattr::new("system.posix_acl_access", "")]),
+        Err(BackhandError::InvalidXattr)
+    ));
+
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    let xattrs = |path: &str| {
+        reader.files().find(|node| node.fullpath.to_str() == Some(path)).unwrap().xattrs.clone()
+    };
+    assert_eq!(xattrs("/"), vec![Xattr::new("user.root", "")]);
+    assert_eq!(xattrs("/dir"), shared);
+    assert_eq!(xattrs("/dir/first"), shared);
+    assert_eq!(xattrs("/dir/second"), capability);
+    assert!(xattrs("/plain").is_empty());
+
+    // written again, xattrs are kept
+    let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    let second = reader.files().find(|node| node.fullpath.to_str() == Some("/dir/second"));
+    assert_eq!(second.unwrap().xattrs, capability);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_xattr_special_files() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let xattrs = vec![Xattr::new("security.selinux", "system_u:object_r:device_t:s0")];
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_symlink("target", "symlink", header).unwrap();
+    fs.push_char_device(1, "char", header).unwrap();
+    fs.push_block_device(2, "block", header).unwrap();
+    fs.push_fifo("fifo", header).unwrap();
+    fs.push_socket("socket", header).unwrap();
+    for path in ["symlink", "char", "block", "fifo", "socket"] {
+        fs.set_xattrs(path, xattrs.clone()).unwrap();
+    }
+    let mut bytes = Cursor::new(vec![]);
+    fs.write(&mut bytes).unwrap();
+
+    let reader = FilesystemReader::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+    let node =
+        |path: &str| reader.files().find(|node| node.fullpath.to_str() == Some(path)).unwrap();
+    for path in ["/symlink", "/char", "/block", "/fifo", "/socket"] {
+        assert_eq!(node(path).xattrs, xattrs);
+    }
+    assert!(
+        matches!(&node("/symlink").inner, InnerNode::Symlink(symlink) if symlink.link.to_str() == Some("target"))
+    );
+    assert!(
+        matches!(&node("/char").inner, InnerNode::CharacterDevice(dev) if dev.device_number == 1)
+    );
+    assert!(matches!(&node("/block").inner, InnerNode::BlockDevice(dev) if dev.device_number == 2));
+    assert!(matches!(&node("/fifo").inner, InnerNode::NamedPipe));
+    assert!(matches!(&node("/socket").inner, InnerNode::Socket));
+}
@@ -1,22 +1,24 @@
 //! Types of supported compression algorithms
 
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, Write};
 
 use deku::prelude::*;
-#[cfg(feature = "any-flate2")]
-use flate2::read::ZlibEncoder;
-#[cfg(feature = "any-flate2")]
-use flate2::Compression;
-use tracing::trace;
-#[cfg(feature = "xz")]
+use tracing::{error, trace};
+#[cfg(any(feature = "xz", feature = "lzma"))]
 use xz2::read::{XzDecoder, XzEncoder};
+#[cfg(any(feature = "xz", feature = "lzma"))]
+use xz2::stream::LzmaOptions;
+#[cfg(feature = "lzma")]
+use xz2::stream::{Action, Status, Stream};
 #[cfg(feature = "xz")]
-use xz2::stream::{Check, Filters, LzmaOptions, MtStreamBuilder};
+use xz2::stream::{Check, Filters, MtStreamBuilder};
 
 use crate::error::BackhandError;
 use crate::filesystem::writer::{CompressionExtra, FilesystemCompressor};
 use crate::kind::Kind;
 use crate::metadata::MetadataWriter;
+#[cfg(feature = "lzma")]
+use crate::metadata::METADATA_MAXSIZE;
 use crate::squashfs::Flags;
 use crate::SuperBlock;
 
@@ -63,20 +65,93 @@ pub enum CompressionOptions {
 pub struct Gzip {
     pub compression_level: u32,
     pub window_size: u16,
-    // TODO: enum
-    pub strategies: u16,
+    pub strategies: GzipStrategies,
+}
+
+/// Gzip strategies, each tried by mksquashfs with the smallest output kept
+#[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite, Default)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct GzipStrategies(u16);
+
+impl GzipStrategies {
+    pub const DEFAULT: u16 = 0x0001;
+    pub const FILTERED: u16 = 0x0002;
+    pub const HUFFMAN_ONLY: u16 = 0x0004;
+    pub const RLE: u16 = 0x0008;
+    pub const FIXED: u16 = 0x0010;
+
+    pub fn new(strategies: u16) -> Self {
+        Self(strategies)
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn default_strategy(&self) -> bool {
+        self.0 & Self::DEFAULT == Self::DEFAULT
+    }
+
+    pub fn filtered(&self) -> bool {
+        self.0 & Self::FILTERED == Self::FILTERED
+    }
+
+    pub fn huffman_only(&self) -> bool {
+        self.0 & Self::HUFFMAN_ONLY == Self::HUFFMAN_ONLY
+    }
+
+    pub fn rle(&self) -> bool {
+        self.0 & Self::RLE == Self::RLE
+    }
+
+    pub fn fixed(&self) -> bool {
+        self.0 & Self::FIXED == Self::FIXED
+    }
+
+    /// Enabled strategies, in the order tried by mksquashfs. Only the default if none are enabled
+    #[cfg(feature = "any-flate2")]
+    fn strategies(&self) -> Vec<zlib_rs::Strategy> {
+        use zlib_rs::Strategy;
+        let strategies: Vec<_> = [
+            (self.default_strategy(), Strategy::Default),
+            (self.filtered(), Strategy::Filtered),
+            (self.huffman_only(), Strategy::HuffmanOnly),
+            (self.rle(), Strategy::Rle),
+            (self.fixed(), Strategy::Fixed),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, strategy)| enabled.then_some(strategy))
+        .collect();
+        if strategies.is_empty() {
+            vec![Strategy::Default]
+        } else {
+            strategies
+        }
+    }
 }
 
 #[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Clone, Copy)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct Lzo {
-    // TODO: enum
-    pub algorithm: u32,
+    pub algorithm: LzoAlgorithm,
+    /// Only used by [`LzoAlgorithm::Lzo1x999`], from 1 to 9. Otherwise 0
     pub compression_level: u32,
 }
 
-#[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Clone, Copy)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite, Default)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+#[deku(id_type = "u32")]
+#[rustfmt::skip]
+pub enum LzoAlgorithm {
+    Lzo1x1 =    0,
+    Lzo1x1_11 = 1,
+    Lzo1x1_12 = 2,
+    Lzo1x1_15 = 3,
+    #[default]
+    Lzo1x999 =  4,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Xz {
     pub dictionary_size: u32,
     pub filters: XzFilter,
@@ -84,43 +159,163 @@ pub struct Xz {
     // the rest of these fields are from OpenWRT. These are optional, as the kernel will ignore
     // these fields when seen. We follow the same behaviour and don't attempt to parse if the bytes
     // for these aren't found
-    // TODO: both are currently unused in this library
-    // TODO: in openwrt, git-hash:f97ad870e11ebe5f3dcf833dda6c83b9165b37cb shows that before
-    // official squashfs-tools had xz support they had the dictionary_size field as the last field
-    // in this struct. If we get test images, I guess we can support this in the future.
-    #[deku(cond = "!deku::reader.end()")]
+    /// Packed lzma `lc`, `lp` and `pb` of `-Xlc`, `-Xlp` and `-Xpb`, 3 bits each starting from
+    /// `lc` in the lowest bits
     pub bit_opts: Option<u16>,
-    #[deku(cond = "!deku::reader.end()")]
+    /// lzma `nice_len` of `-Xfb`
     pub fb: Option<u16>,
+    /// Written in the layout used by OpenWRT before official squashfs-tools had xz support
+    /// (git-hash:f97ad870e11ebe5f3dcf833dda6c83b9165b37cb), with `dictionary_size` as the last
+    /// field and the `filters` first
+    pub dictionary_size_last: bool,
+}
+
+impl Xz {
+    /// lzma `(lc, lp, pb)` from `bit_opts`
+    pub fn lc_lp_pb(&self) -> Option<(u32, u32, u32)> {
+        self.bit_opts.map(|bits| {
+            let bits = u32::from(bits);
+            (bits & 0x7, (bits >> 3) & 0x7, (bits >> 6) & 0x7)
+        })
+    }
+
+    /// squashfs-tools only allows a dictionary size of 2^n or 2^n+2^(n-1)
+    fn is_dictionary_size(size: u32) -> bool {
+        size >= 8192 && (size >> size.trailing_zeros()) | 2 == 3
+    }
+}
+
+impl<'a> DekuReader<'a, deku::ctx::Endian> for Xz {
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        endian: deku::ctx::Endian,
+    ) -> Result<Self, DekuError> {
+        let first = u32::from_reader_with_ctx(reader, endian)?;
+        let second = u32::from_reader_with_ctx(reader, endian)?;
+        let bit_opts =
+            if reader.end() { None } else { Some(u16::from_reader_with_ctx(reader, endian)?) };
+        let fb = if reader.end() { None } else { Some(u16::from_reader_with_ctx(reader, endian)?) };
+
+        // The older layout is only used if the fields don't make sense in the current layout
+        if let (Some(low), Some(high)) = (bit_opts, fb) {
+            let last = match endian {
+                deku::ctx::Endian::Little => u32::from(low) | u32::from(high) << 16,
+                deku::ctx::Endian::Big => u32::from(low) << 16 | u32::from(high),
+            };
+            let (bit_opts, fb) = match endian {
+                deku::ctx::Endian::Little => (second as u16, (second >> 16) as u16),
+                deku::ctx::Endian::Big => ((second >> 16) as u16, second as u16),
+            };
+            if Self::is_dictionary_size(last)
+                && (!Self::is_dictionary_size(first) || second & !XzFilter::ALL != 0)
+            {
+                return Ok(Self {
+                    dictionary_size: last,
+                    filters: XzFilter(first),
+                    bit_opts: Some(bit_opts),
+                    fb: Some(fb),
+                    dictionary_size_last: true,
+                });
+            }
+        }
+
+        Ok(Self {
+            dictionary_size: first,
+            filters: XzFilter(second),
+            bit_opts,
+            fb,
+            dictionary_size_last: false,
+        })
+    }
+}
+
+impl DekuWriter<deku::ctx::Endian> for Xz {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        endian: deku::ctx::Endian,
+    ) -> Result<(), DekuError> {
+        if self.dictionary_size_last {
+            self.filters.to_writer(writer, endian)?;
+            self.bit_opts.unwrap_or(0).to_writer(writer, endian)?;
+            self.fb.unwrap_or(0).to_writer(writer, endian)?;
+            self.dictionary_size.to_writer(writer, endian)?;
+        } else {
+            self.dictionary_size.to_writer(writer, endian)?;
+            self.filters.to_writer(writer, endian)?;
+            if let Some(bit_opts) = self.bit_opts {
+                bit_opts.to_writer(writer, endian)?;
+            }
+            if let Some(fb) = self.fb {
+                fb.to_writer(writer, endian)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct XzFilter(u32);
 
+/// Adds a filter to the chain of xz [`Filters`]
+#[cfg(feature = "xz")]
+type AddFilter = fn(&mut Filters) -> &mut Filters;
+
 impl XzFilter {
-    fn x86(&self) -> bool {
-        self.0 & 0x0001 == 0x0001
+    pub const X86: u32 = 0x0001;
+    pub const POWERPC: u32 = 0x0002;
+    pub const IA64: u32 = 0x0004;
+    pub const ARM: u32 = 0x0008;
+    pub const ARMTHUMB: u32 = 0x0010;
+    pub const SPARC: u32 = 0x0020;
+    /// All filters supported by squashfs-tools
+    pub const ALL: u32 = 0x003f;
+
+    pub fn new(filters: u32) -> Self {
+        Self(filters)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn x86(&self) -> bool {
+        self.0 & Self::X86 == Self::X86
+    }
+
+    pub fn powerpc(&self) -> bool {
+        self.0 & Self::POWERPC == Self::POWERPC
     }
 
-    fn powerpc(&self) -> bool {
-        self.0 & 0x0002 == 0x0002
+    pub fn ia64(&self) -> bool {
+        self.0 & Self::IA64 == Self::IA64
     }
 
-    fn ia64(&self) -> bool {
-        self.0 & 0x0004 == 0x0004
+    pub fn arm(&self) -> bool {
+        self.0 & Self::ARM == Self::ARM
     }
 
-    fn arm(&self) -> bool {
-        self.0 & 0x0008 == 0x0008
+    pub fn armthumb(&self) -> bool {
+        self.0 & Self::ARMTHUMB == Self::ARMTHUMB
     }
 
-    fn armthumb(&self) -> bool {
-        self.0 & 0x0010 == 0x0010
+    pub fn sparc(&self) -> bool {
+        self.0 & Self::SPARC == Self::SPARC
     }
 
-    fn sparc(&self) -> bool {
-        self.0 & 0x0020 == 0x0020
+    /// Enabled filters, as functions adding them to [`Filters`]
+    #[cfg(feature = "xz")]
+    fn enabled(&self) -> Vec<AddFilter> {
+        let filters: [(bool, AddFilter); 6] = [
+            (self.x86(), Filters::x86),
+            (self.powerpc(), Filters::powerpc),
+            (self.ia64(), Filters::ia64),
+            (self.arm(), Filters::arm),
+            (self.armthumb(), Filters::arm_thumb),
+            (self.sparc(), Filters::sparc),
+        ];
+        filters.into_iter().filter_map(|(enabled, filter)| enabled.then_some(filter)).collect()
     }
 }
 
@@ -128,8 +323,29 @@ impl XzFilter {
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct Lz4 {
     pub version: u32,
-    //TODO: enum
-    pub flags: u32,
+    pub flags: Lz4Flags,
+}
+
+/// Lz4 flags, as set by `mksquashfs -Xhc`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite, Default)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct Lz4Flags(u32);
+
+impl Lz4Flags {
+    /// Use the high compression encoder
+    pub const HC: u32 = 0x0001;
+
+    pub fn new(flags: u32) -> Self {
+        Self(flags)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn hc(&self) -> bool {
+        self.0 & Self::HC == Self::HC
+    }
 }
 
 #[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Clone, Copy)]
@@ -225,6 +441,12 @@ impl CompressionAction for DefaultCompressor {
                 let mut decoder = XzDecoder::new(bytes);
                 decoder.read_to_end(out)?;
             }
+            #[cfg(feature = "lzma")]
+            Compressor::Lzma => {
+                let stream = Stream::new_lzma_decoder(u64::MAX).map_err(std::io::Error::from)?;
+                let mut decoder = XzDecoder::new_stream(bytes, stream);
+                decoder.read_to_end(out)?;
+            }
             #[cfg(feature = "lzo")]
             Compressor::Lzo => {
                 out.resize(out.capacity(), 0);
@@ -268,82 +490,124 @@ impl CompressionAction for DefaultCompressor {
                     Some(_) => unreachable!(),
                 };
                 let default_level = 6; // LZMA_DEFAULT
-                let level = match extra {
-                    None => default_level,
+                let (level, selection) = match extra {
+                    None => (default_level, None),
                     Some(CompressionExtra::Xz(xz)) => {
-                        if let Some(level) = xz.level {
-                            level
-                        } else {
-                            default_level
-                        }
+                        (xz.level.unwrap_or(default_level), xz.filters)
                     }
                 };
                 let check = Check::Crc32;
                 let mut opts = LzmaOptions::new_preset(level).unwrap();
                 opts.dict_size(dict_size);
-
-                let mut filters = Filters::new();
                 if let Some(CompressionOptions::Xz(xz)) = option {
-                    if xz.filters.x86() {
-                        filters.x86();
-                    }
-                    if xz.filters.powerpc() {
-                        filters.powerpc();
-                    }
-                    if xz.filters.ia64() {
-                        filters.ia64();
+                    if let Some((lc, lp, pb)) = xz.lc_lp_pb() {
+                        opts.literal_context_bits(lc).literal_position_bits(lp).position_bits(pb);
                     }
-                    if xz.filters.arm() {
-                        filters.arm();
+                    if let Some(fb) = xz.fb.filter(|fb| *fb != 0) {
+                        opts.nice_len(u32::from(fb));
                     }
-                    if xz.filters.armthumb() {
-                        filters.arm_thumb();
+                }
+
+                let encode = |bcj: &[AddFilter]| {
+                    let mut filters = Filters::new();
+                    for filter in bcj {
+                        filter(&mut filters);
                     }
-                    if xz.filters.sparc() {
-                        filters.sparc();
+                    filters.lzma2(&opts);
+
+                    let stream = MtStreamBuilder::new()
+                        .threads(2)
+                        .filters(filters)
+                        .check(check)
+                        .encoder()
+                        .map_err(std::io::Error::from)?;
+
+                    let mut encoder = XzEncoder::new_stream(Cursor::new(bytes), stream);
+                    let mut buf = vec![];
+                    encoder.read_to_end(&mut buf)?;
+                    Ok::<_, BackhandError>(buf)
+                };
+
+                match (selection, option) {
+                    // like mksquashfs, try no filter and each filter on its own, keeping the
+                    // smallest
+                    (Some(selection), _) => {
+                        let mut smallest = encode(&[])?;
+                        for filter in selection.enabled() {
+                            let buf = encode(&[filter])?;
+                            if buf.len() < smallest.len() {
+                                smallest = buf;
+                            }
+                        }
+                        Ok(smallest)
                     }
+                    (None, Some(CompressionOptions::Xz(xz))) => encode(&xz.filters.enabled()),
+                    (None, _) => encode(&[]),
                 }
-                filters.lzma2(&opts);
-
-                let stream = MtStreamBuilder::new()
-                    .threads(2)
-                    .filters(filters)
-                    .check(check)
-                    .encoder()
-                    .unwrap();
+            }
+            #[cfg(feature = "lzma")]
+            (Compressor::Lzma, _, _) => {
+                // same settings as squashfs-tools
+                let mut opts = LzmaOptions::new_preset(5).unwrap();
+                opts.dict_size(block_size).nice_len(32);
+                let stream = Stream::new_lzma_encoder(&opts).map_err(std::io::Error::from)?;
 
                 let mut encoder = XzEncoder::new_stream(Cursor::new(bytes), stream);
                 let mut buf = vec![];
                 encoder.read_to_end(&mut buf)?;
+
+                // lzma-alone header: 5 bytes of properties, then the uncompressed size,
+                // which squashfs-tools requires to be known
+                buf[5..13].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
                 Ok(buf)
             }
             #[cfg(feature = "any-flate2")]
             (Compressor::Gzip, option @ (Some(CompressionOptions::Gzip(_)) | None), _) => {
-                let compression_level = match option {
-                    None => Compression::best(), // 9
+                let (compression_level, window_size, strategies) = match option {
+                    None => (9, 15, GzipStrategies::default()),
                     Some(CompressionOptions::Gzip(option)) => {
-                        Compression::new(option.compression_level)
+                        (option.compression_level, option.window_size, option.strategies)
                     }
                     Some(_) => unreachable!(),
                 };
 
-                // TODO(#8): Use window_size and strategies (current window size defaults to 15)
-
-                let mut encoder = ZlibEncoder::new(Cursor::new(bytes), compression_level);
-                let mut buf = vec![];
-                encoder.read_to_end(&mut buf)?;
-                Ok(buf)
+                // like mksquashfs, try each strategy and keep the smallest
+                let mut buf = vec![0; zlib_rs::compress_bound(bytes.len())];
+                let mut smallest: Option<Vec<u8>> = None;
+                for strategy in strategies.strategies() {
+                    let config = zlib_rs::DeflateConfig {
+                        level: compression_level as i32,
+                        window_bits: i32::from(window_size),
+                        strategy,
+                        ..Default::default()
+                    };
+                    let (compressed, ret) = zlib_rs::compress_slice(&mut buf, bytes, config);
+                    if ret != zlib_rs::ReturnCode::Ok {
+                        error!("gzip compression failed: {ret:?}");
+                        return Err(BackhandError::InvalidCompressionOption);
+                    }
+                    if smallest.as_ref().map_or(true, |smallest| compressed.len() < smallest.len())
+                    {
+                        smallest = Some(compressed.to_vec());
+                    }
+                }
+                Ok(smallest.unwrap())
             }
             #[cfg(feature = "lzo")]
-            (Compressor::Lzo, _, _) => {
-                let mut lzo = rust_lzo::LZOContext::new();
-                let mut buf = vec![0; rust_lzo::worst_compress(bytes.len())];
-                let error = lzo.compress(bytes, &mut buf);
-                if error != rust_lzo::LZOError::OK {
-                    return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            (Compressor::Lzo, option, _) => match option {
+                Some(CompressionOptions::Lzo(lzo)) if lzo.algorithm != LzoAlgorithm::Lzo1x1 => {
+                    Ok(crate::lzo1x::compress(bytes, lzo.algorithm, lzo.compression_level))
                 }
-                Ok(buf)
-            }
+                _ => {
+                    let mut lzo = rust_lzo::LZOContext::new();
+                    let mut buf = vec![0; rust_lzo::worst_compress(bytes.len())];
+                    let error = lzo.compress(bytes, &mut buf);
+                    if error != rust_lzo::LZOError::OK {
+                        return Err(BackhandError::CorruptedOrInvalidSquashfs);
+                    }
+                    Ok(buf)
+                }
+            },
             #[cfg(feature = "zstd")]
             (Compressor::Zstd, option @ (Some(CompressionOptions::Zstd(_)) | None), _) => {
                 let compression_level = match option {
@@ -358,7 +622,12 @@ impl CompressionAction for DefaultCompressor {
                 Ok(buf)
             }
             #[cfg(feature = "lz4")]
-            (Compressor::Lz4, _option, _) => Ok(lz4_flex::compress(bytes)),
+            (Compressor::Lz4, option, _) => match option {
+                Some(CompressionOptions::Lz4(lz4)) if lz4.flags.hc() => {
+                    Ok(crate::lz4hc::compress(bytes))
+                }
+                _ => Ok(lz4_flex::compress(bytes)),
+            },
             _ => Err(BackhandError::UnsupportedCompression(fc.id)),
         }
     }
@@ -406,3 +675,93 @@ impl CompressionAction for DefaultCompressor {
         Ok(w.into_inner())
     }
 }
+
+/// Largest block size of an image, bounding the uncompressed size of LZMA headers
+#[cfg(feature = "lzma")]
+const MAX_BLOCK_SIZE: u64 = 0x10_0000;
+
+/// Compressor of firmware images from vendors patching zlib or LZMA into mksquashfs, such as
+/// sqlzma (TP-Link, Netgear), Broadcom and DD-WRT.
+///
+/// The compressor id of these images is not trusted, each block is instead decompressed as:
+/// - zlib, if it starts with a zlib header
+/// - LZMA with the 13 byte header of lzma-alone, if the uncompressed size is valid
+/// - LZMA with only the 5 byte properties, without the uncompressed size
+///
+/// Compression uses [`DefaultCompressor`], as these images are written as standard images.
+#[derive(Copy, Clone)]
+pub struct VendorLzmaCompressor;
+
+impl CompressionAction for VendorLzmaCompressor {
+    fn decompress(
+        &self,
+        bytes: &[u8],
+        out: &mut Vec<u8>,
+        compressor: Compressor,
+    ) -> Result<(), BackhandError> {
+        if compressor == Compressor::None {
+            out.extend_from_slice(bytes);
+            return Ok(());
+        }
+        // deflate method and header checksum of a zlib stream. Never the properties of LZMA
+        if bytes.len() >= 2
+            && bytes[0] & 0x0f == 8
+            && u16::from_be_bytes([bytes[0], bytes[1]]) % 31 == 0
+        {
+            return DefaultCompressor.decompress(bytes, out, Compressor::Gzip);
+        }
+        lzma(bytes, out)
+    }
+
+    fn compress(
+        &self,
+        bytes: &[u8],
+        fc: FilesystemCompressor,
+        block_size: u32,
+    ) -> Result<Vec<u8>, BackhandError> {
+        DefaultCompressor.compress(bytes, fc, block_size)
+    }
+
+    fn compression_options(
+        &self,
+        superblock: &mut SuperBlock,
+        kind: &Kind,
+        fs_compressor: FilesystemCompressor,
+    ) -> Result<Vec<u8>, BackhandError> {
+        DefaultCompressor.compression_options(superblock, kind, fs_compressor)
+    }
+}
+
+/// Decompress LZMA, with or without the uncompressed size after the properties
+#[cfg(feature = "lzma")]
+fn lzma(bytes: &[u8], out: &mut Vec<u8>) -> Result<(), BackhandError> {
+    if bytes.len() < 5 {
+        return Err(BackhandError::CorruptedOrInvalidSquashfs);
+    }
+    let (props, rest) = bytes.split_at(5);
+    let size = rest.get(..8).map(|size| u64::from_le_bytes(size.try_into().unwrap()));
+    let input = match size {
+        Some(size) if size == u64::MAX || size <= MAX_BLOCK_SIZE => bytes.to_vec(),
+        // unknown size, decoding until the end marker or the end of input
+        _ => [props, &u64::MAX.to_le_bytes(), rest].concat(),
+    };
+
+    let mut stream = Stream::new_lzma_decoder(u64::MAX).map_err(std::io::Error::from)?;
+    loop {
+        out.reserve(METADATA_MAXSIZE);
+        let read = stream.total_in() as usize;
+        let status =
+            stream.process_vec(&input[read..], out, Action::Run).map_err(std::io::Error::from)?;
+        // streams without an end marker stop with all input read and room left in `out`
+        if status == Status::StreamEnd
+            || (stream.total_in() as usize == input.len() && out.len() < out.capacity())
+        {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(not(feature = "lzma"))]
+fn lzma(_bytes: &[u8], _out: &mut Vec<u8>) -> Result<(), BackhandError> {
+    Err(BackhandError::UnsupportedCompression(Compressor::Lzma))
+}
@@ -14,6 +14,7 @@ use crate::filesystem::reader::SquashfsRawData;
 use crate::filesystem::writer::FilesystemCompressor;
 use crate::fragment::Fragment;
 use crate::reader::WriteSeek;
+use crate::transformer::TransformAction;
 
 // bitflag for data size field in inode for signifying that the data is uncompressed
 const DATA_STORED_UNCOMPRESSED: u32 = 1 << 24;
@@ -68,9 +69,22 @@ impl DataSize {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Added {
     // Only Data was added
-    Data { blocks_start: u32, block_sizes: Vec<DataSize> },
+    Data {
+        blocks_start: u64,
+        block_sizes: Vec<DataSize>,
+    },
     // Only Fragment was added
-    Fragment { frag_index: u32, block_offset: u32 },
+    Fragment {
+        frag_index: u32,
+        block_offset: u32,
+    },
+    // Data was added, with the tail-end in a Fragment
+    DataAndFragment {
+        blocks_start: u64,
+        block_sizes: Vec<DataSize>,
+        frag_index: u32,
+        block_offset: u32,
+    },
 }
 
 struct DataWriterChunkReader<R: std::io::Read> {
@@ -102,35 +116,105 @@ impl<R: std::io::Read> DataWriterChunkReader<R> {
 
 pub(crate) struct DataWriter<'a> {
     kind: &'a dyn CompressionAction,
+    transformer: &'a dyn TransformAction,
     block_size: u32,
     fs_compressor: FilesystemCompressor,
     /// If some, cache of HashMap<file_len, HashMap<hash, (file_len, Added)>>
     #[allow(clippy::type_complexity)]
     dup_cache: Option<IntMap<u64, IntMap<u64, (usize, Added)>>>,
+    /// Store the last partial block of files larger than a block in a fragment
+    tail_ends: bool,
+    /// Store all file data in blocks, never in a fragment
+    no_fragments: bool,
+    /// Store data blocks uncompressed
+    uncompressed_data: bool,
+    /// Store fragment blocks uncompressed
+    uncompressed_fragments: bool,
     /// Un-written fragment_bytes
     pub(crate) fragment_bytes: Vec<u8>,
     pub(crate) fragment_table: Vec<Fragment>,
 }
 
 impl<'a> DataWriter<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         kind: &'a dyn CompressionAction,
+        transformer: &'a dyn TransformAction,
         fs_compressor: FilesystemCompressor,
         block_size: u32,
         no_duplicate_files: bool,
+        tail_ends: bool,
+        no_fragments: bool,
+        uncompressed_data: bool,
+        uncompressed_fragments: bool,
     ) -> Self {
         Self {
             kind,
+            transformer,
             block_size,
             fs_compressor,
             dup_cache: no_duplicate_files.then_some(HashMap::default()),
+            tail_ends: tail_ends && !no_fragments,
+            no_fragments,
+            uncompressed_data,
+            uncompressed_fragments,
             fragment_bytes: Vec::with_capacity(block_size as usize),
             fragment_table: vec![],
         }
     }
 
+    /// Add to fragment bytes, writing the current fragment if `bytes` don't fit
+    ///
+    /// Returns the `(frag_index, block_offset)` of the added bytes
+    fn add_fragment<W: WriteSeek>(
+        &mut self,
+        bytes: &[u8],
+        writer: W,
+    ) -> Result<(u32, u32), BackhandError> {
+        // if this doesn't fit in the current fragment bytes
+        // compress the current fragment bytes and add to data_bytes
+        if (bytes.len() + self.fragment_bytes.len()) > self.block_size as usize {
+            self.finalize(writer)?;
+        }
+
+        // add to fragment bytes
+        let frag_index = self.fragment_table.len() as u32;
+        let block_offset = self.fragment_bytes.len() as u32;
+        self.fragment_bytes.write_all(bytes)?;
+        Ok((frag_index, block_offset))
+    }
+
+    /// Compress and write `bytes` as a data block, storing uncompressed if that is smaller
+    fn write_block<W: WriteSeek>(
+        &self,
+        bytes: &[u8],
+        mut writer: W,
+    ) -> Result<DataSize, BackhandError> {
+        if self.uncompressed_data {
+            let mut bytes = bytes.to_vec();
+            self.transformer.to(&mut bytes, None)?;
+            writer.write_all(&bytes)?;
+            return Ok(DataSize::new_uncompressed(bytes.len() as u32));
+        }
+
+        let mut cb = self.kind.compress(bytes, self.fs_compressor, self.block_size)?;
+
+        // compression didn't reduce size
+        if cb.len() > bytes.len() {
+            // store uncompressed
+            let mut bytes = bytes.to_vec();
+            self.transformer.to(&mut bytes, None)?;
+            writer.write_all(&bytes)?;
+            Ok(DataSize::new_uncompressed(bytes.len() as u32))
+        } else {
+            // store compressed
+            self.transformer.to(&mut cb, None)?;
+            writer.write_all(&cb)?;
+            Ok(DataSize::new_compressed(cb.len() as u32))
+        }
+    }
+
     /// Add to data writer, either a pre-compressed Data or Fragment
-    // TODO: support tail-end fragments (off by default in squashfs-tools/mksquashfs)
     pub(crate) fn just_copy_it<W: WriteSeek>(
         &mut self,
         mut reader: SquashfsRawData,
@@ -143,7 +227,7 @@ impl<'a> DataWriter<'a> {
 
         // if the first block is not full (fragment), store only a fragment
         // otherwise processed to store blocks
-        let blocks_start = writer.stream_position()? as u32;
+        let blocks_start = writer.stream_position()?;
         let first_block = match reader.next_block(&mut read_buf) {
             Some(Ok(first_block)) => first_block,
             Some(Err(x)) => return Err(x),
@@ -153,40 +237,48 @@ impl<'a> DataWriter<'a> {
         // write and early return if fragment
         if first_block.fragment {
             reader.decompress(first_block, &mut read_buf, &mut decompress_buf)?;
-            // if this doesn't fit in the current fragment bytes
-            // compress the current fragment bytes and add to data_bytes
-            if (decompress_buf.len() + self.fragment_bytes.len()) > self.block_size as usize {
-                self.finalize(writer)?;
+            // without fragments, store it as a block. empty files have no blocks
+            if self.no_fragments {
+                if !decompress_buf.is_empty() {
+                    block_sizes.push(self.write_block(&decompress_buf, writer)?);
+                }
+                return Ok((decompress_buf.len(), Added::Data { blocks_start, block_sizes }));
             }
-            // add to fragment bytes
-            let frag_index = self.fragment_table.len() as u32;
-            let block_offset = self.fragment_bytes.len() as u32;
-            self.fragment_bytes.write_all(&decompress_buf)?;
-
+            let (frag_index, block_offset) = self.add_fragment(&decompress_buf, writer)?;
             return Ok((decompress_buf.len(), Added::Fragment { frag_index, block_offset }));
         }
 
-        //if is a block, just copy it
-        writer.write_all(&read_buf)?;
+        //if is a block, just copy it. Sparse blocks only exist in block_sizes
+        let sparse: Vec<bool> = block_sizes.iter().map(|block| block.size() == 0).collect();
+        let mut sparse = sparse.into_iter();
+        if sparse.next() != Some(true) {
+            self.transformer.to(&mut read_buf, None)?;
+            writer.write_all(&read_buf)?;
+        }
         while let Some(block) = reader.next_block(&mut read_buf) {
             let block = block?;
+            if sparse.next() == Some(true) {
+                continue;
+            }
             if block.fragment {
                 reader.decompress(block, &mut read_buf, &mut decompress_buf)?;
-                // TODO: support tail-end fragments, for now just treat it like a block
-                let cb =
-                    self.kind.compress(&decompress_buf, self.fs_compressor, self.block_size)?;
-                // compression didn't reduce size
-                if cb.len() > decompress_buf.len() {
-                    // store uncompressed
-                    block_sizes.push(DataSize::new_uncompressed(decompress_buf.len() as u32));
-                    writer.write_all(&decompress_buf)?;
-                } else {
-                    // store compressed
-                    block_sizes.push(DataSize::new_compressed(cb.len() as u32));
-                    writer.write_all(&cb)?;
+                if self.tail_ends {
+                    let (frag_index, block_offset) =
+                        self.add_fragment(&decompress_buf, &mut writer)?;
+                    let file_size = reader.file.file.file_len();
+                    let added = Added::DataAndFragment {
+                        blocks_start,
+                        block_sizes,
+                        frag_index,
+                        block_offset,
+                    };
+                    return Ok((file_size, added));
                 }
+                // without tail-ends, store it as a block
+                block_sizes.push(self.write_block(&decompress_buf, &mut writer)?);
             } else {
                 //if is a block, just copy it
+                self.transformer.to(&mut read_buf, None)?;
                 writer.write_all(&read_buf)?;
             }
         }
@@ -198,7 +290,6 @@ impl<'a> DataWriter<'a> {
     ///
     /// If `self.dup_cache` is on, return alrady added `(usize, Added)` if duplicate
     /// is found
-    // TODO: support tail-end fragments (off by default in squashfs-tools/mksquashfs)
     pub(crate) fn add_bytes<W: WriteSeek>(
         &mut self,
         reader: impl Read,
@@ -214,23 +305,13 @@ impl<'a> DataWriter<'a> {
         let mut chunk = chunk_reader.read_chunk()?;
 
         // chunk size not exactly the size of the block
-        if chunk.len() != self.block_size as usize {
-            // if this doesn't fit in the current fragment bytes
-            // compress the current fragment bytes and add to data_bytes
-            if (chunk.len() + self.fragment_bytes.len()) > self.block_size as usize {
-                self.finalize(writer)?;
-            }
-
-            // add to fragment bytes
-            let frag_index = self.fragment_table.len() as u32;
-            let block_offset = self.fragment_bytes.len() as u32;
-            self.fragment_bytes.write_all(chunk)?;
-
+        if chunk.len() != self.block_size as usize && !self.no_fragments {
+            let (frag_index, block_offset) = self.add_fragment(chunk, writer)?;
             return Ok((chunk_reader.file_len, Added::Fragment { frag_index, block_offset }));
         }
 
         // Add to data bytes
-        let blocks_start = writer.stream_position()? as u32;
+        let blocks_start = writer.stream_position()?;
         let mut block_sizes = vec![];
 
         // If duplicate file checking is enabled, use the old data position as this file if it hashes the same
@@ -247,25 +328,34 @@ impl<'a> DataWriter<'a> {
         // Save information needed to add to duplicate_cache later
         let chunk_len = chunk.len();
         let hash = xxh64(chunk, 0);
+        let mut tail = None;
 
         while !chunk.is_empty() {
-            let cb = self.kind.compress(chunk, self.fs_compressor, self.block_size)?;
+            // sparse file, store all zero blocks as holes
+            if chunk.iter().all(|b| *b == 0) {
+                block_sizes.push(DataSize::new_compressed(0));
+                chunk = chunk_reader.read_chunk()?;
+                continue;
+            }
 
-            // compression didn't reduce size
-            if cb.len() > chunk.len() {
-                // store uncompressed
-                block_sizes.push(DataSize::new_uncompressed(chunk.len() as u32));
-                writer.write_all(chunk)?;
-            } else {
-                // store compressed
-                block_sizes.push(DataSize::new_compressed(cb.len() as u32));
-                writer.write_all(&cb)?;
+            // last partial block, store as a tail-end fragment
+            if self.tail_ends && chunk.len() != self.block_size as usize {
+                tail = Some(self.add_fragment(chunk, &mut writer)?);
+                break;
             }
+
+            block_sizes.push(self.write_block(chunk, &mut writer)?);
             chunk = chunk_reader.read_chunk()?;
         }
 
         // Add to duplicate information cache
-        let added = (chunk_reader.file_len, Added::Data { blocks_start, block_sizes });
+        let added = match tail {
+            Some((frag_index, block_offset)) => {
+                Added::DataAndFragment { blocks_start, block_sizes, frag_index, block_offset }
+            }
+            None => Added::Data { blocks_start, block_sizes },
+        };
+        let added = (chunk_reader.file_len, added);
 
         // If duplicate files checking is enbaled, then add this to it's memory
         if let Some(dup_cache) = &mut self.dup_cache {
@@ -284,15 +374,21 @@ impl<'a> DataWriter<'a> {
     /// current fragment_bytes
     pub fn finalize<W: Write + Seek>(&mut self, mut writer: W) -> Result<(), BackhandError> {
         let start = writer.stream_position()?;
-        let cb = self.kind.compress(&self.fragment_bytes, self.fs_compressor, self.block_size)?;
+        let mut cb = if self.uncompressed_fragments {
+            vec![]
+        } else {
+            self.kind.compress(&self.fragment_bytes, self.fs_compressor, self.block_size)?
+        };
 
         // compression didn't reduce size
-        let size = if cb.len() > self.fragment_bytes.len() {
+        let size = if self.uncompressed_fragments || cb.len() > self.fragment_bytes.len() {
             // store uncompressed
+            self.transformer.to(&mut self.fragment_bytes, None)?;
             writer.write_all(&self.fragment_bytes)?;
             DataSize::new_uncompressed(self.fragment_bytes.len() as u32)
         } else {
             // store compressed
+            self.transformer.to(&mut cb, None)?;
             writer.write_all(&cb)?;
             DataSize::new_compressed(cb.len() as u32)
         };
@@ -309,6 +405,7 @@ mod tests {
     use super::*;
     use crate::{
         compression::{Compressor, DefaultCompressor},
+        transformation::DefaultTransformer,
         DEFAULT_BLOCK_SIZE,
     };
 
@@ -317,9 +414,14 @@ mod tests {
     fn test_duplicate_check() {
         let mut data_writer = DataWriter::new(
             &DefaultCompressor,
+            &DefaultTransformer,
             FilesystemCompressor::new(Compressor::Gzip, None).unwrap(),
             DEFAULT_BLOCK_SIZE,
             true,
+            false,
+            false,
+            false,
+            false,
         );
         let bytes = [0xff_u8; DEFAULT_BLOCK_SIZE as usize * 2];
         let mut writer = Cursor::new(vec![]);
@@ -333,9 +435,14 @@ mod tests {
     fn test_no_duplicate_check() {
         let mut data_writer = DataWriter::new(
             &DefaultCompressor,
+            &DefaultTransformer,
             FilesystemCompressor::new(Compressor::Gzip, None).unwrap(),
             DEFAULT_BLOCK_SIZE,
             false,
+            false,
+            false,
+            false,
+            false,
         );
         let bytes = [0xff_u8; DEFAULT_BLOCK_SIZE as usize * 2];
         let mut writer = Cursor::new(vec![]);
@@ -343,4 +450,28 @@ mod tests {
         let added_2 = data_writer.add_bytes(&bytes[..], &mut writer).unwrap();
         assert_ne!(added_1, added_2);
     }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_sparse() {
+        let mut data_writer = DataWriter::new(
+            &DefaultCompressor,
+            &DefaultTransformer,
+            FilesystemCompressor::new(Compressor::Gzip, None).unwrap(),
+            DEFAULT_BLOCK_SIZE,
+            false,
+            false,
+            false,
+            false,
+            false,
+        );
+        let mut bytes = vec![0_u8; DEFAULT_BLOCK_SIZE as usize * 3 + 1];
+        bytes[DEFAULT_BLOCK_SIZE as usize] = 0xff;
+        let mut writer = Cursor::new(vec![]);
+        let (_, added) = data_writer.add_bytes(&bytes[..], &mut writer).unwrap();
+        let Added::Data { block_sizes, .. } = added else { panic!("expected data") };
+        let sparse: Vec<_> = block_sizes.iter().map(|block| block.size() == 0).collect();
+        assert_eq!(sparse, [true, false, true, true]);
+        assert_eq!(writer.get_ref().len() as u32, block_sizes[1].size());
+    }
 }
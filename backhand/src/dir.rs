@@ -3,6 +3,7 @@
 //! For each directory inode, the directory table stores a linear list of all entries,
 //! with references back to the inodes that describe those entries.
 
+use std::cmp::Ordering;
 use std::ffi::OsStr;
 use std::path::{Component, Path};
 
@@ -45,6 +46,34 @@ impl Dir {
         self.dir_entries.push(entry);
         self.count = (self.dir_entries.len() - 1) as u32;
     }
+
+    /// Inode number of `entry`, stored as a difference to the header
+    pub(crate) fn inode_number(&self, entry: &DirEntry) -> Result<u32, BackhandError> {
+        (self.inode_num as i32 + entry.inode_offset as i32)
+            .try_into()
+            .map_err(|_| BackhandError::CorruptedOrInvalidSquashfs)
+    }
+
+    /// Inode reference of `entry`, with the metadata block start relative to the inode table
+    /// in the upper bits and the offset into the uncompressed block in the lower 16 bits
+    pub(crate) fn inode_ref(&self, entry: &DirEntry) -> u64 {
+        (u64::from(self.start) << 16) | u64::from(entry.offset)
+    }
+}
+
+/// Find the entry `name` in the sorted directory listing `dirs`
+pub(crate) fn find<'a>(dirs: &'a [Dir], name: &[u8]) -> Option<(&'a Dir, &'a DirEntry)> {
+    for d in dirs {
+        for entry in &d.dir_entries {
+            // entries are sorted, stop once past `name`
+            match entry.name.as_slice().cmp(name) {
+                Ordering::Less => (),
+                Ordering::Equal => return Some((d, entry)),
+                Ordering::Greater => return None,
+            }
+        }
+    }
+    None
 }
 
 #[derive(Debug, DekuRead, DekuWrite, Clone, PartialEq, Eq)]
@@ -95,8 +124,8 @@ pub struct DirectoryIndex {
 }
 
 impl DirectoryIndex {
-    pub fn name(&self) -> String {
-        std::str::from_utf8(&self.name).unwrap().to_string()
+    pub fn name(&self) -> &OsStr {
+        OsStr::from_bytes(&self.name)
     }
 }
 
@@ -2,15 +2,17 @@ use std::ffi::OsStr;
 use std::fmt;
 
 use crate::data::Added;
-use crate::dir::{Dir, DirEntry};
+use crate::dir::{Dir, DirEntry, DirectoryIndex};
 use crate::inode::{
-    BasicDeviceSpecialFile, BasicDirectory, BasicFile, BasicSymlink, ExtendedDirectory, IPCNode,
-    Inode, InodeHeader, InodeId, InodeInner,
+    BasicDeviceSpecialFile, BasicDirectory, BasicFile, BasicSymlink, ExtendedDeviceSpecialFile,
+    ExtendedDirectory, ExtendedFile, ExtendedIPCNode, ExtendedSymlink, IPCNode, Inode, InodeHeader,
+    InodeId, InodeInner,
 };
 use crate::kinds::Kind;
 use crate::metadata::MetadataWriter;
 use crate::squashfs::SuperBlock;
 use crate::unix_string::OsStrExt;
+use crate::xattr::NO_XATTR;
 use crate::{Id, NodeHeader, SquashfsBlockDevice, SquashfsCharacterDevice, SquashfsSymlink};
 
 #[derive(Clone)]
@@ -24,8 +26,14 @@ pub(crate) struct Entry<'a> {
 }
 
 impl<'a> Entry<'a> {
-    pub fn name(&self) -> String {
-        std::str::from_utf8(self.name).unwrap().to_string()
+    pub fn name(&self) -> &OsStr {
+        OsStr::from_bytes(self.name)
+    }
+
+    /// Additional entry named `name` for the already written inode of `self`
+    pub fn hardlink(&self, name: &'a OsStr) -> Self {
+        let name = name.as_bytes();
+        Self { name_size: name.len() as u16 - 1, name, ..self.clone() }
     }
 
     /// Write data and metadata for path node (Basic Directory or ExtendedDirectory)
@@ -40,6 +48,8 @@ impl<'a> Entry<'a> {
         file_size: usize,
         block_offset: u16,
         block_index: u32,
+        dir_index: Vec<DirectoryIndex>,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -53,38 +63,37 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        // if entry won't fit in file_size of regular dir entry, create extended directory
-        let dir_inode = if file_size > u16::MAX as usize {
-            Inode::new(
-                InodeId::ExtendedDirectory,
-                header,
-                InodeInner::ExtendedDirectory(ExtendedDirectory {
-                    link_count: 2 + u32::try_from(children_num).unwrap(),
-                    file_size: file_size.try_into().unwrap(), // u32
-                    block_index,
-                    parent_inode,
-                    // TODO: Support Directory Index
-                    index_count: 0,
-                    block_offset,
-                    // TODO(#32): Support xattr
-                    xattr_index: 0xffff_ffff,
-                    // TODO: Support Directory Index
-                    dir_index: vec![],
-                }),
-            )
-        } else {
-            Inode::new(
-                InodeId::BasicDirectory,
-                header,
-                InodeInner::BasicDirectory(BasicDirectory {
-                    block_index,
-                    link_count: 2 + u32::try_from(children_num).unwrap(),
-                    file_size: file_size.try_into().unwrap(), // u16
-                    block_offset,
-                    parent_inode,
-                }),
-            )
-        };
+        // if entry won't fit in file_size of regular dir entry, or has an index or xattrs, create
+        // extended directory
+        let dir_inode =
+            if file_size > u16::MAX as usize || !dir_index.is_empty() || xattr_index != NO_XATTR {
+                Inode::new(
+                    InodeId::ExtendedDirectory,
+                    header,
+                    InodeInner::ExtendedDirectory(ExtendedDirectory {
+                        link_count: 2 + u32::try_from(children_num).unwrap(),
+                        file_size: file_size.try_into().unwrap(), // u32
+                        block_index,
+                        parent_inode,
+                        index_count: dir_index.len().try_into().unwrap(),
+                        block_offset,
+                        xattr_index,
+                        dir_index,
+                    }),
+                )
+            } else {
+                Inode::new(
+                    InodeId::BasicDirectory,
+                    header,
+                    InodeInner::BasicDirectory(BasicDirectory {
+                        block_index,
+                        link_count: 2 + u32::try_from(children_num).unwrap(),
+                        file_size: file_size.try_into().unwrap(), // u16
+                        block_offset,
+                        parent_inode,
+                    }),
+                )
+            };
 
         dir_inode.to_bytes(name.as_bytes(), inode_writer, superblock, kind)
     }
@@ -95,9 +104,11 @@ impl<'a> Entry<'a> {
         node_path: &'a OsStr,
         header: NodeHeader,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
         file_size: usize,
         added: &Added,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -111,26 +122,61 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        let basic_file = match added {
+        let (blocks_start, frag_index, block_offset, block_sizes) = match added {
+            // no fragment
             Added::Data { blocks_start, block_sizes } => {
-                BasicFile {
-                    blocks_start: *blocks_start,
-                    frag_index: 0xffffffff, // <- no fragment
-                    block_offset: 0x0,      // <- no fragment
-                    file_size: file_size.try_into().unwrap(),
-                    block_sizes: block_sizes.to_vec(),
-                }
+                (*blocks_start, 0xffffffff, 0x0, block_sizes.to_vec())
+            }
+            Added::Fragment { frag_index, block_offset } => (0, *frag_index, *block_offset, vec![]),
+            // tail-end fragment
+            Added::DataAndFragment { blocks_start, block_sizes, frag_index, block_offset } => {
+                (*blocks_start, *frag_index, *block_offset, block_sizes.to_vec())
             }
-            Added::Fragment { frag_index, block_offset } => BasicFile {
-                blocks_start: 0,
-                frag_index: *frag_index,
-                block_offset: *block_offset,
-                file_size: file_size.try_into().unwrap(),
-                block_sizes: vec![],
-            },
         };
 
-        let file_inode = Inode::new(InodeId::BasicFile, header, InodeInner::BasicFile(basic_file));
+        // bytes of the file stored as holes
+        let block_size = u64::from(superblock.block_size);
+        let sparse = block_sizes
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.size() == 0)
+            .map(|(index, _)| block_size.min(file_size as u64 - index as u64 * block_size))
+            .sum();
+
+        // xattrs, link count, sparse and 64-bit sizes can only be stored in an extended file
+        let file_inode = if xattr_index != NO_XATTR
+            || link_count > 1
+            || sparse != 0
+            || blocks_start > u64::from(u32::MAX)
+            || file_size > u32::MAX as usize
+        {
+            Inode::new(
+                InodeId::ExtendedFile,
+                header,
+                InodeInner::ExtendedFile(ExtendedFile {
+                    blocks_start,
+                    file_size: file_size as u64,
+                    sparse,
+                    link_count,
+                    frag_index,
+                    block_offset,
+                    xattr_index,
+                    block_sizes,
+                }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicFile,
+                header,
+                InodeInner::BasicFile(BasicFile {
+                    blocks_start: blocks_start as u32,
+                    frag_index,
+                    block_offset,
+                    file_size: file_size.try_into().unwrap(),
+                    block_sizes,
+                }),
+            )
+        };
 
         file_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -142,7 +188,9 @@ impl<'a> Entry<'a> {
         header: NodeHeader,
         symlink: &SquashfsSymlink,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -157,15 +205,28 @@ impl<'a> Entry<'a> {
             mtime: header.mtime,
         };
         let link = symlink.link.as_os_str().as_bytes();
-        let sym_inode = Inode::new(
-            InodeId::BasicSymlink,
-            header,
-            InodeInner::BasicSymlink(BasicSymlink {
-                link_count: 0x1,
-                target_size: link.len().try_into().unwrap(),
-                target_path: link.to_vec(),
-            }),
-        );
+        let sym_inode = if xattr_index != NO_XATTR {
+            Inode::new(
+                InodeId::ExtendedSymlink,
+                header,
+                InodeInner::ExtendedSymlink(ExtendedSymlink {
+                    link_count,
+                    target_size: link.len().try_into().unwrap(),
+                    target_path: link.to_vec(),
+                    xattr_index,
+                }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicSymlink,
+                header,
+                InodeInner::BasicSymlink(BasicSymlink {
+                    link_count,
+                    target_size: link.len().try_into().unwrap(),
+                    target_path: link.to_vec(),
+                }),
+            )
+        };
 
         sym_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -177,7 +238,9 @@ impl<'a> Entry<'a> {
         header: NodeHeader,
         char_device: &SquashfsCharacterDevice,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -191,14 +254,26 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        let char_inode = Inode::new(
-            InodeId::BasicCharacterDevice,
-            header,
-            InodeInner::BasicCharacterDevice(BasicDeviceSpecialFile {
-                link_count: 0x1,
-                device_number: char_device.device_number,
-            }),
-        );
+        let char_inode = if xattr_index != NO_XATTR {
+            Inode::new(
+                InodeId::ExtendedCharacterDevice,
+                header,
+                InodeInner::ExtendedCharacterDevice(ExtendedDeviceSpecialFile {
+                    link_count,
+                    device_number: char_device.device_number,
+                    xattr_index,
+                }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicCharacterDevice,
+                header,
+                InodeInner::BasicCharacterDevice(BasicDeviceSpecialFile {
+                    link_count,
+                    device_number: char_device.device_number,
+                }),
+            )
+        };
 
         char_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -210,7 +285,9 @@ impl<'a> Entry<'a> {
         header: NodeHeader,
         block_device: &SquashfsBlockDevice,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -224,14 +301,26 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        let block_inode = Inode::new(
-            InodeId::BasicBlockDevice,
-            header,
-            InodeInner::BasicBlockDevice(BasicDeviceSpecialFile {
-                link_count: 0x1,
-                device_number: block_device.device_number,
-            }),
-        );
+        let block_inode = if xattr_index != NO_XATTR {
+            Inode::new(
+                InodeId::ExtendedBlockDevice,
+                header,
+                InodeInner::ExtendedBlockDevice(ExtendedDeviceSpecialFile {
+                    link_count,
+                    device_number: block_device.device_number,
+                    xattr_index,
+                }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicBlockDevice,
+                header,
+                InodeInner::BasicBlockDevice(BasicDeviceSpecialFile {
+                    link_count,
+                    device_number: block_device.device_number,
+                }),
+            )
+        };
 
         block_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -242,7 +331,9 @@ impl<'a> Entry<'a> {
         node_path: &'a OsStr,
         header: NodeHeader,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -256,11 +347,19 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        let char_inode = Inode::new(
-            InodeId::BasicNamedPipe,
-            header,
-            InodeInner::BasicNamedPipe(IPCNode { link_count: 0x1 }),
-        );
+        let char_inode = if xattr_index != NO_XATTR {
+            Inode::new(
+                InodeId::ExtendedNamedPipe,
+                header,
+                InodeInner::ExtendedNamedPipe(ExtendedIPCNode { link_count, xattr_index }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicNamedPipe,
+                header,
+                InodeInner::BasicNamedPipe(IPCNode { link_count }),
+            )
+        };
 
         char_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -271,7 +370,9 @@ impl<'a> Entry<'a> {
         node_path: &'a OsStr,
         header: NodeHeader,
         inode: u32,
+        link_count: u32,
         inode_writer: &mut MetadataWriter,
+        xattr_index: u32,
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
@@ -285,11 +386,19 @@ impl<'a> Entry<'a> {
             permissions: header.permissions,
             mtime: header.mtime,
         };
-        let char_inode = Inode::new(
-            InodeId::BasicSocket,
-            header,
-            InodeInner::BasicSocket(IPCNode { link_count: 0x1 }),
-        );
+        let char_inode = if xattr_index != NO_XATTR {
+            Inode::new(
+                InodeId::ExtendedSocket,
+                header,
+                InodeInner::ExtendedSocket(ExtendedIPCNode { link_count, xattr_index }),
+            )
+        } else {
+            Inode::new(
+                InodeId::BasicSocket,
+                header,
+                InodeInner::BasicSocket(IPCNode { link_count }),
+            )
+        };
 
         char_inode.to_bytes(node_path.as_bytes(), inode_writer, superblock, kind)
     }
@@ -338,6 +447,7 @@ impl Entry<'_> {
         let mut dirs = vec![];
         let mut creating_dir = vec![];
         let mut lowest_inode = u32::MAX;
+        let mut highest_inode = u32::MIN;
         let mut iter = entries.iter().peekable();
         let mut creating_start = if let Some(entry) = iter.peek() {
             entry.start
@@ -346,15 +456,16 @@ impl Entry<'_> {
         };
 
         while let Some(e) = iter.next() {
-            if e.inode < lowest_inode {
-                lowest_inode = e.inode;
-            }
+            lowest_inode = lowest_inode.min(e.inode);
+            highest_inode = highest_inode.max(e.inode);
             creating_dir.push(e);
 
             // last entry
             if let Some(next) = &iter.peek() {
-                // if the next entry would be > the lowest_inode
-                let max_inode = (next.inode as u64).abs_diff(lowest_inode as u64) > i16::MAX as u64;
+                // inodes are stored as an i16 offset from the lowest inode, which hard links can
+                // make any inode of the header
+                let max_inode =
+                    highest_inode.max(next.inode) - lowest_inode.min(next.inode) > i16::MAX as u32;
                 // make sure entries have the correct start and amount of directories
                 if next.start != creating_start || creating_dir.len() >= 255 || max_inode {
                     let dir = Self::create_dir(&creating_dir, creating_start, lowest_inode);
@@ -362,6 +473,7 @@ impl Entry<'_> {
                     creating_dir = vec![];
                     creating_start = next.start;
                     lowest_inode = u32::MAX;
+                    highest_inode = u32::MIN;
                 }
             }
             // last entry
@@ -448,4 +560,69 @@ mod tests {
             dir
         );
     }
+
+    #[test]
+    fn test_file_over_4gib() {
+        use std::sync::Arc;
+
+        use crate::compressor::Compressor;
+        use crate::data::DataSize;
+        use crate::filesystem::writer::FilesystemCompressor;
+        use crate::kinds::LE_V4_0;
+        use crate::DEFAULT_BLOCK_SIZE;
+
+        let kind = Kind { inner: Arc::new(LE_V4_0) };
+        let mut superblock = SuperBlock::new(Compressor::None, Kind { inner: Arc::new(LE_V4_0) });
+        superblock.block_size = DEFAULT_BLOCK_SIZE;
+        superblock.block_log = 17;
+        let mut inode_writer = MetadataWriter::new(
+            FilesystemCompressor::default(),
+            DEFAULT_BLOCK_SIZE,
+            Kind { inner: kind.inner.clone() },
+        );
+        let id_table = Id::root();
+        let mut file = |blocks_start, file_size| {
+            let block_count = file_size / DEFAULT_BLOCK_SIZE as usize;
+            let block_sizes = vec![DataSize::new_uncompressed(DEFAULT_BLOCK_SIZE); block_count];
+            let added = Added::Data { blocks_start, block_sizes };
+            Entry::file(
+                OsStr::new("file"),
+                NodeHeader::default(),
+                1,
+                1,
+                &mut inode_writer,
+                file_size,
+                &added,
+                NO_XATTR,
+                &superblock,
+                &kind,
+                &id_table,
+            )
+            .t
+        };
+
+        assert_eq!(file(0x100, 0x2_0000), InodeId::BasicFile);
+        // data starts past 4GiB
+        assert_eq!(file(0x1_0000_0000, 0x2_0000), InodeId::ExtendedFile);
+        // file larger than 4GiB
+        assert_eq!(file(0x100, 0x1_0002_0000), InodeId::ExtendedFile);
+    }
+
+    #[test]
+    fn test_entry_unordered_inodes() {
+        // hard links can point to any inode
+        let entry = |inode, name| Entry {
+            start: 0,
+            offset: 0,
+            inode,
+            t: InodeId::BasicFile,
+            name_size: 0,
+            name,
+        };
+        let entries = vec![entry(40000, b"a"), entry(20000, b"b"), entry(1, b"c")];
+
+        let dirs = Entry::into_dir(entries);
+        let inodes: Vec<_> = dirs.iter().map(|dir| dir.inode_num).collect();
+        assert_eq!(inodes, [20000, 1]);
+    }
 }
@@ -7,6 +7,7 @@ use thiserror::Error;
 
 use crate::compressor::Compressor;
 use crate::inode::InodeInner;
+use crate::kinds::Candidate;
 
 /// Errors generated from library
 #[derive(Error, Debug)]
@@ -26,6 +27,9 @@ pub enum BackhandError {
     #[error("unsupported compression: {0:?}")]
     UnsupportedCompression(Compressor),
 
+    #[error("unsupported squashfs version: {0}.{1}")]
+    UnsupportedSquashfsVersion(u16, u16),
+
     #[error("file not found")]
     FileNotFound,
 
@@ -58,6 +62,16 @@ pub enum BackhandError {
 
     #[error("invalid id_table for node")]
     InvalidIdTable,
+
+    #[error("xattr name does not start with a supported prefix")]
+    InvalidXattr,
+
+    #[error("{source}, the image is valid as another kind")]
+    OtherKind {
+        source: Box<BackhandError>,
+        /// Kinds the image is valid as
+        candidates: Vec<Candidate>,
+    },
 }
 
 impl From<BackhandError> for io::Error {
@@ -67,8 +81,11 @@ impl From<BackhandError> for io::Error {
             StdIo(io) => io,
             StringUtf8(_) => Self::from(io::ErrorKind::InvalidData),
             StrUtf8(_) => Self::from(io::ErrorKind::InvalidData),
-            UnsupportedCompression(_) => Self::from(io::ErrorKind::Unsupported),
+            UnsupportedCompression(_) | UnsupportedSquashfsVersion(..) => {
+                Self::from(io::ErrorKind::Unsupported)
+            }
             FileNotFound => Self::from(io::ErrorKind::NotFound),
+            OtherKind { source, .. } => Self::from(*source),
             Unreachable
             | Deku(_)
             | UnexpectedInode(_)
@@ -79,6 +96,7 @@ impl From<BackhandError> for io::Error {
             | UndefineFileName
             | DuplicatedFileName
             | InvalidIdTable
+            | InvalidXattr
             | TryReserveError(_) => Self::from(io::ErrorKind::InvalidData),
         }
     }
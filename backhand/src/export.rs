@@ -6,3 +6,7 @@ use deku::prelude::*;
 pub struct Export {
     pub num: u64,
 }
+
+impl Export {
+    pub const SIZE: usize = 8;
+}
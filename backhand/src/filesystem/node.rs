@@ -7,7 +7,7 @@ use std::sync::{Arc, Mutex};
 use super::normalize_squashfs_path;
 use crate::data::Added;
 use crate::inode::{BasicFile, ExtendedFile, InodeHeader};
-use crate::{BackhandError, DataSize, FilesystemReaderFile, Id};
+use crate::{BackhandError, DataSize, FilesystemReaderFile, Id, Xattr};
 
 /// File information for Node
 #[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
@@ -45,6 +45,8 @@ pub struct Node<T> {
     pub fullpath: PathBuf,
     pub header: NodeHeader,
     pub inner: InnerNode<T>,
+    /// Extended attributes
+    pub xattrs: Vec<Xattr>,
 }
 
 impl<T> PartialEq for Node<T> {
@@ -66,13 +68,13 @@ impl<T> Ord for Node<T> {
 
 impl<T> Node<T> {
     pub(crate) fn new(fullpath: PathBuf, header: NodeHeader, inner: InnerNode<T>) -> Self {
-        Self { fullpath, header, inner }
+        Self { fullpath, header, inner, xattrs: vec![] }
     }
 
     pub fn new_root(header: NodeHeader) -> Self {
         let fullpath = PathBuf::from("/");
         let inner = InnerNode::Dir(SquashfsDir::default());
-        Self { fullpath, header, inner }
+        Self { fullpath, header, inner, xattrs: vec![] }
     }
 }
 
@@ -87,6 +89,8 @@ pub enum InnerNode<T> {
     BlockDevice(SquashfsBlockDevice),
     NamedPipe,
     Socket,
+    /// Additional directory entry for the inode of another, non-directory, node
+    Hardlink(SquashfsHardlink),
 }
 
 /// Unread file for filesystem
@@ -152,6 +156,13 @@ pub struct SquashfsSymlink {
     pub link: PathBuf,
 }
 
+/// Hard link for filesystem
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SquashfsHardlink {
+    /// Full path of the node sharing its inode
+    pub link: PathBuf,
+}
+
 /// Directory for filesystem
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub struct SquashfsDir {}
@@ -196,6 +207,14 @@ impl<T> Nodes<T> {
             .map(|found| &mut self.nodes[found])
     }
 
+    pub(crate) fn node_index<S: AsRef<Path>>(&self, path: S) -> Option<NonZeroUsize> {
+        let find_path = normalize_squashfs_path(path.as_ref()).ok()?;
+        self.nodes
+            .binary_search_by(|node| node.fullpath.cmp(&find_path))
+            .ok()
+            .map(|found| NonZeroUsize::new(found + 1).unwrap())
+    }
+
     pub fn insert(&mut self, node: Node<T>) -> Result<(), BackhandError> {
         let path = &node.fullpath;
         let parent = node.fullpath.parent().ok_or(BackhandError::InvalidFilePath)?;
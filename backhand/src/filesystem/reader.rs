@@ -1,5 +1,5 @@
 use std::io::{Read, SeekFrom};
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use super::node::Nodes;
 use crate::compressor::{CompressionOptions, Compressor};
@@ -7,10 +7,10 @@ use crate::data::DataSize;
 use crate::error::BackhandError;
 use crate::fragment::Fragment;
 use crate::id::Id;
-use crate::kinds::Kind;
+use crate::kinds::{Kind, LE_V4_0};
 use crate::reader::BufReadSeek;
-use crate::squashfs::Cache;
-use crate::{Node, Squashfs, SquashfsFileReader};
+use crate::squashfs::{Cache, SuperBlock};
+use crate::{LazySquashfs, Node, Squashfs, SquashfsFileReader};
 
 /// Representation of SquashFS filesystem after read from image
 /// - Use [`Self::from_reader`] to read into `Self` from a `reader`
@@ -41,6 +41,7 @@ use crate::{Node, Squashfs, SquashfsFileReader};
 ///         InnerNode::BlockDevice(_) => (),
 ///         InnerNode::NamedPipe => (),
 ///         InnerNode::Socket => (),
+///         InnerNode::Hardlink(_) => (),
 ///     }
 /// }
 /// ```
@@ -92,18 +93,64 @@ pub struct FilesystemReader<'b> {
     pub(crate) cache: RwLock<Cache>,
     /// Superblock Flag to remove duplicate flags
     pub(crate) no_duplicate_files: bool,
+    /// Superblock Flag for the NFS export table
+    pub(crate) export_table: bool,
+    /// Superblock Flag for tail-end fragments
+    pub(crate) tail_ends: bool,
+    /// Superblock Flag for not using fragments
+    pub(crate) no_fragments: bool,
+    /// Superblock Flag for inodes and directories stored uncompressed
+    pub(crate) uncompressed_inodes: bool,
+    /// Superblock Flag for data blocks stored uncompressed
+    pub(crate) uncompressed_data: bool,
+    /// Superblock Flag for fragments stored uncompressed
+    pub(crate) uncompressed_fragments: bool,
+    /// Superblock Flag for xattrs stored uncompressed
+    pub(crate) uncompressed_xattrs: bool,
 }
 
 impl<'b> FilesystemReader<'b> {
-    /// Call [`Squashfs::from_reader`], then [`Squashfs::into_filesystem_reader`]
+    /// Filesystem of the image read with `superblock`, containing the nodes of `root`
+    pub(crate) fn new(
+        kind: Kind,
+        superblock: &SuperBlock,
+        compression_options: Option<CompressionOptions>,
+        id_table: Vec<Id>,
+        fragments: Option<Vec<Fragment>>,
+        root: Nodes<SquashfsFileReader>,
+        reader: Box<dyn BufReadSeek + 'b>,
+    ) -> Self {
+        Self {
+            kind,
+            block_size: superblock.block_size,
+            block_log: superblock.block_log,
+            compressor: superblock.compressor,
+            compression_options,
+            mod_time: superblock.mod_time,
+            id_table,
+            fragments,
+            root,
+            reader: Mutex::new(reader),
+            cache: RwLock::new(Cache::default()),
+            no_duplicate_files: superblock.data_has_been_deduplicated(),
+            export_table: superblock.nfs_export_table_exists(),
+            tail_ends: superblock.fragments_are_always_generated(),
+            no_fragments: superblock.fragments_are_not_used(),
+            uncompressed_inodes: superblock.inodes_uncompressed(),
+            uncompressed_data: superblock.data_block_stored_uncompressed(),
+            uncompressed_fragments: superblock.fragments_stored_uncompressed(),
+            uncompressed_xattrs: superblock.xattrs_are_stored_uncompressed(),
+        }
+    }
+
+    /// Call [`LazySquashfs::from_reader`], then [`LazySquashfs::into_filesystem_reader`]
     ///
     /// With default kind: [`crate::kind::LE_V4_0`] and offset `0`.
     pub fn from_reader<R>(reader: R) -> Result<Self, BackhandError>
     where
         R: BufReadSeek + 'b,
     {
-        let squashfs = Squashfs::from_reader_with_offset(reader, 0)?;
-        squashfs.into_filesystem_reader()
+        Self::from_reader_with_offset(reader, 0)
     }
 
     /// Same as [`Self::from_reader`], but seek'ing to `offset` in `reader` before reading
@@ -111,11 +158,13 @@ impl<'b> FilesystemReader<'b> {
     where
         R: BufReadSeek + 'b,
     {
-        let squashfs = Squashfs::from_reader_with_offset(reader, offset)?;
-        squashfs.into_filesystem_reader()
+        Self::from_reader_with_offset_and_kind(reader, offset, Kind { inner: Arc::new(LE_V4_0) })
     }
 
     /// Same as [`Self::from_reader_with_offset`], but setting custom `kind`
+    ///
+    /// Images older than v4.0 are read with [`Squashfs::from_reader_with_offset_and_kind`], then
+    /// [`Squashfs::into_filesystem_reader`].
     pub fn from_reader_with_offset_and_kind<R>(
         reader: R,
         offset: u64,
@@ -124,7 +173,11 @@ impl<'b> FilesystemReader<'b> {
     where
         R: BufReadSeek + 'b,
     {
-        let squashfs = Squashfs::from_reader_with_offset_and_kind(reader, offset, kind)?;
+        if kind.inner.version_major < 4 {
+            let squashfs = Squashfs::from_reader_with_offset_and_kind(reader, offset, kind)?;
+            return squashfs.into_filesystem_reader();
+        }
+        let squashfs = LazySquashfs::from_reader_with_offset_and_kind(reader, offset, kind)?;
         squashfs.into_filesystem_reader()
     }
 
@@ -275,6 +328,7 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
                     reader.read_exact(data)?;
                     self.pos = reader.stream_position()?;
                 }
+                self.file.system.kind.inner.transformer.from(data, None)?;
                 Ok(RawDataBlock { fragment: false, uncompressed: block.uncompressed() })
             }
             BlockFragment::Fragment(fragment) => {
@@ -304,6 +358,7 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
                     reader.seek(SeekFrom::Start(fragment.start))?;
                     reader.read_exact(data)?;
                 }
+                self.file.system.kind.inner.transformer.from(data, None)?;
 
                 // if already decompressed, store
                 if fragment.size.uncompressed() {
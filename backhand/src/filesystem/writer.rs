@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroUsize;
@@ -11,21 +12,25 @@ use tracing::{error, info, trace};
 
 use super::node::{InnerNode, Nodes};
 use super::normalize_squashfs_path;
-use crate::compressor::{CompressionOptions, Compressor};
+use crate::compressor::{CompressionOptions, Compressor, LzoAlgorithm, Xz, XzFilter};
 use crate::data::DataWriter;
+use crate::dir::DirectoryIndex;
 use crate::entry::Entry;
 use crate::error::BackhandError;
+use crate::export::Export;
 use crate::filesystem::node::SquashfsSymlink;
 use crate::id::Id;
+use crate::inode::SYMLINK_MAX;
 use crate::kind::Kind;
-use crate::kinds::LE_V4_0;
+use crate::kinds::{BE_V4_0, LE_V4_0};
 use crate::metadata::{self, MetadataWriter, METADATA_MAXSIZE};
 use crate::reader::WriteSeek;
 use crate::squashfs::SuperBlock;
+use crate::xattr::{XattrIdTable, XattrWriter};
 use crate::{
     fragment, FilesystemReader, Flags, Node, NodeHeader, SquashfsBlockDevice,
-    SquashfsCharacterDevice, SquashfsDir, SquashfsFileWriter, DEFAULT_BLOCK_SIZE, DEFAULT_PAD_LEN,
-    MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    SquashfsCharacterDevice, SquashfsDir, SquashfsFileWriter, SquashfsHardlink, Xattr, XattrId,
+    DEFAULT_BLOCK_SIZE, DEFAULT_PAD_LEN, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
 
 /// Representation of SquashFS filesystem to be written back to an image
@@ -81,6 +86,20 @@ pub struct FilesystemWriter<'a, 'b, 'c> {
     /// Superblock Flag to remove duplicate flags
     pub(crate) no_duplicate_files: bool,
     pub(crate) emit_compression_options: bool,
+    /// Write the NFS export table
+    pub(crate) export_table: bool,
+    /// Store the tail-end of files larger than a block in fragments
+    pub(crate) tail_ends: bool,
+    /// Store all file data in blocks
+    pub(crate) no_fragments: bool,
+    /// Store the inode and directory tables uncompressed
+    pub(crate) uncompressed_inodes: bool,
+    /// Store data blocks uncompressed
+    pub(crate) uncompressed_data: bool,
+    /// Store fragment blocks uncompressed
+    pub(crate) uncompressed_fragments: bool,
+    /// Store the xattr table uncompressed
+    pub(crate) uncompressed_xattrs: bool,
 }
 
 impl Default for FilesystemWriter<'_, '_, '_> {
@@ -101,10 +120,31 @@ impl Default for FilesystemWriter<'_, '_, '_> {
             pad_len: DEFAULT_PAD_LEN,
             no_duplicate_files: true,
             emit_compression_options: true,
+            export_table: false,
+            tail_ends: false,
+            no_fragments: false,
+            uncompressed_inodes: false,
+            uncompressed_data: false,
+            uncompressed_fragments: false,
+            uncompressed_xattrs: false,
         }
     }
 }
 
+/// Kind written for `kind`
+///
+/// Images older than v4.0 are read-only, and written as the standard v4.0 of the same endian.
+fn writable_kind(kind: &Kind) -> Kind {
+    if kind.inner.version_major < 4 {
+        match kind.inner.type_endian {
+            deku::ctx::Endian::Little => Kind { inner: Arc::new(LE_V4_0) },
+            deku::ctx::Endian::Big => Kind { inner: Arc::new(BE_V4_0) },
+        }
+    } else {
+        Kind { inner: kind.inner.clone() }
+    }
+}
+
 impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
     /// Set block size
     ///
@@ -137,6 +177,9 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
 
     /// Set kind as `kind`
     ///
+    /// Kinds of images older than v4.0 are read-only, and set as [`LE_V4_0`] or [`BE_V4_0`] of the
+    /// same endian.
+    ///
     /// # Example: Set kind to default V4.0
     /// ```rust
     /// # use backhand::{FilesystemWriter, kind::Kind, kind};
@@ -144,7 +187,7 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
     /// fs.set_kind(Kind::from_const(kind::LE_V4_0).unwrap());
     /// ```
     pub fn set_kind(&mut self, kind: Kind) {
-        self.kind = kind;
+        self.kind = writable_kind(&kind);
     }
 
     /// Set root mode as `mode`
@@ -203,6 +246,62 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         self.no_duplicate_files = value;
     }
 
+    /// Set if the NFS export table is written, off by default
+    ///
+    /// Inherited from the image with [`Self::from_fs_reader`].
+    pub fn set_export_table(&mut self, value: bool) {
+        self.export_table = value;
+    }
+
+    /// Set if the last partial block of files larger than a block is stored in a fragment, off
+    /// by default
+    ///
+    /// This is `-tailends` or `-always-use-fragments` in mksquashfs, and is inherited from the
+    /// image with [`Self::from_fs_reader`]. Has no effect with [`Self::set_no_fragments`].
+    pub fn set_tail_ends(&mut self, value: bool) {
+        self.tail_ends = value;
+    }
+
+    /// Set if files are only stored in data blocks, never in fragments, off by default
+    ///
+    /// This is `-no-fragments` in mksquashfs, and is inherited from the image with
+    /// [`Self::from_fs_reader`].
+    pub fn set_no_fragments(&mut self, value: bool) {
+        self.no_fragments = value;
+    }
+
+    /// Set if the inode and directory tables are stored uncompressed, off by default
+    ///
+    /// This is `-noI` in mksquashfs, and is inherited from the image with
+    /// [`Self::from_fs_reader`].
+    pub fn set_uncompressed_inodes(&mut self, value: bool) {
+        self.uncompressed_inodes = value;
+    }
+
+    /// Set if data blocks are stored uncompressed, off by default
+    ///
+    /// This is `-noD` in mksquashfs, and is inherited from the image with
+    /// [`Self::from_fs_reader`].
+    pub fn set_uncompressed_data(&mut self, value: bool) {
+        self.uncompressed_data = value;
+    }
+
+    /// Set if fragment blocks are stored uncompressed, off by default
+    ///
+    /// This is `-noF` in mksquashfs, and is inherited from the image with
+    /// [`Self::from_fs_reader`].
+    pub fn set_uncompressed_fragments(&mut self, value: bool) {
+        self.uncompressed_fragments = value;
+    }
+
+    /// Set if the xattr table is stored uncompressed, off by default
+    ///
+    /// This is `-noX` in mksquashfs, and is inherited from the image with
+    /// [`Self::from_fs_reader`].
+    pub fn set_uncompressed_xattrs(&mut self, value: bool) {
+        self.uncompressed_xattrs = value;
+    }
+
     /// Set if compression options are written
     pub fn set_emit_compression_options(&mut self, value: bool) {
         self.emit_compression_options = value;
@@ -226,13 +325,20 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
                     InnerNode::BlockDevice(x) => InnerNode::BlockDevice(*x),
                     InnerNode::NamedPipe => InnerNode::NamedPipe,
                     InnerNode::Socket => InnerNode::Socket,
+                    InnerNode::Hardlink(x) => InnerNode::Hardlink(x.clone()),
                 };
-                Node { fullpath: node.fullpath.clone(), header: node.header, inner }
+                Node {
+                    fullpath: node.fullpath.clone(),
+                    header: node.header,
+                    inner,
+                    xattrs: node.xattrs.clone(),
+                }
             })
             .collect();
         root.sort();
+
         Ok(Self {
-            kind: Kind { inner: reader.kind.inner.clone() },
+            kind: writable_kind(&reader.kind),
             block_size: reader.block_size,
             block_log: reader.block_log,
             fs_compressor: FilesystemCompressor::new(
@@ -245,6 +351,13 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
             pad_len: DEFAULT_PAD_LEN,
             no_duplicate_files: reader.no_duplicate_files,
             emit_compression_options: true,
+            export_table: reader.export_table,
+            tail_ends: reader.tail_ends,
+            no_fragments: reader.no_fragments,
+            uncompressed_inodes: reader.uncompressed_inodes,
+            uncompressed_data: reader.uncompressed_data,
+            uncompressed_fragments: reader.uncompressed_fragments,
+            uncompressed_xattrs: reader.uncompressed_xattrs,
         })
     }
 
@@ -325,9 +438,25 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         Ok(())
     }
 
+    /// Set extended attributes of existing node at `find_path`, replacing any existing
+    ///
+    /// Names must start with one of the `user.`, `trusted.` or `security.` prefixes.
+    pub fn set_xattrs<S>(&mut self, find_path: S, xattrs: Vec<Xattr>) -> Result<(), BackhandError>
+    where
+        S: AsRef<Path>,
+    {
+        for xattr in &xattrs {
+            xattr.prefix_id()?;
+        }
+        let node = self.mut_node(find_path).ok_or(BackhandError::FileNotFound)?;
+        node.xattrs = xattrs;
+        Ok(())
+    }
+
     /// Insert symlink `path` -> `link`
     ///
-    /// The `uid` and `gid` in `header` are added to FilesystemWriters id's
+    /// The `uid` and `gid` in `header` are added to FilesystemWriters id's. `link` can be up to
+    /// 4096 bytes, the PATH_MAX of Linux
     pub fn push_symlink<P, S>(
         &mut self,
         link: S,
@@ -338,11 +467,43 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         P: AsRef<Path>,
         S: Into<PathBuf>,
     {
-        let new_symlink = InnerNode::Symlink(SquashfsSymlink { link: link.into() });
+        let link = link.into();
+        if link.as_os_str().len() > SYMLINK_MAX as usize {
+            error!("symlink target longer than {SYMLINK_MAX} bytes: {}", link.display());
+            return Err(BackhandError::InvalidFilePath);
+        }
+        let new_symlink = InnerNode::Symlink(SquashfsSymlink { link });
         self.insert_node(path, header, new_symlink)?;
         Ok(())
     }
 
+    /// Insert hard link `path` to the existing node at `link`, sharing its inode
+    ///
+    /// The header and xattrs of `link` are used, and `link` can't be a directory.
+    pub fn push_hardlink<P, S>(&mut self, link: S, path: P) -> Result<(), BackhandError>
+    where
+        P: AsRef<Path>,
+        S: AsRef<Path>,
+    {
+        let target = self
+            .root
+            .node_index(link)
+            .and_then(|index| self.root.node(index))
+            .ok_or(BackhandError::FileNotFound)?;
+        let (link, header, xattrs) = match &target.inner {
+            InnerNode::Dir(_) => return Err(BackhandError::InvalidFilePath),
+            // link to the original inode
+            InnerNode::Hardlink(hardlink) => {
+                (hardlink.link.clone(), target.header, target.xattrs.clone())
+            }
+            _ => (target.fullpath.clone(), target.header, target.xattrs.clone()),
+        };
+        let new_hardlink = InnerNode::Hardlink(SquashfsHardlink { link });
+        self.insert_node(&path, header, new_hardlink)?;
+        self.mut_node(path).unwrap().xattrs = xattrs;
+        Ok(())
+    }
+
     /// Insert empty `dir` at `path`
     ///
     /// The `uid` and `gid` in `header` are added to FilesystemWriters id's
@@ -482,9 +643,12 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
                     // if the source file and the destination files are both
                     // squashfs files and use the same compressor and block_size
                     // just copy the data, don't compress->decompress
+                    // images older than v4.0 may be compressed by a vendor, so always recompress
                     if file.system.compressor == compressor.id
                         && file.system.compression_options == compressor.options
                         && file.system.block_size == block_size
+                        && file.system.kind.inner.version_major >= 4
+                        && !self.uncompressed_data
                     {
                         data_writer.just_copy_it(file.raw_data_reader(), &mut writer)?
                     } else {
@@ -503,12 +667,16 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
     /// This works by recursively creating Inodes and Dirs for each node in the tree. This also
     /// keeps track of parent directories by calling this function on all nodes of a dir to get only
     /// the nodes, but going into the child dirs in the case that it contains a child dir.
+    ///
+    /// Hard links reuse the entry of their target, writing the target first if required.
     #[allow(clippy::too_many_arguments)]
     fn write_inode_dir<'slf>(
         &'slf self,
         inode_writer: &'_ mut MetadataWriter,
         dir_writer: &'_ mut MetadataWriter,
-        parent_node_id: u32,
+        xattr_writer: &'_ mut XattrWriter,
+        inodes: &'_ mut InodeLinks<'slf>,
+        parent_inode: u32,
         node_id: NonZeroUsize,
         superblock: &SuperBlock,
         kind: &Kind,
@@ -516,83 +684,111 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
     ) -> Result<Entry<'slf>, BackhandError> {
         let node = &self.root.node(node_id).unwrap();
         let filename = node.fullpath.file_name().unwrap_or(OsStr::new("/"));
+        if let InnerNode::Hardlink(SquashfsHardlink { link }) = &node.inner {
+            let target = self.root.node_index(link).ok_or(BackhandError::FileNotFound)?;
+            let entry = self.write_inode_dir(
+                inode_writer,
+                dir_writer,
+                xattr_writer,
+                inodes,
+                parent_inode,
+                target,
+                superblock,
+                kind,
+                id_table,
+            )?;
+            return Ok(entry.hardlink(filename));
+        }
+        let index = node_id.get() - 1;
+        if let Some(entry) = inodes.written.get(&index) {
+            return Ok(entry.hardlink(filename));
+        }
+
+        let inode = inodes.numbers[index];
+        let link_count = inodes.link_counts[index];
+        let xattr_index = xattr_writer.add(&node.xattrs)?;
         //if not a dir, return the entry
-        match &node.inner {
-            InnerNode::File(SquashfsFileWriter::Consumed(filesize, added)) => {
-                return Ok(Entry::file(
-                    filename,
-                    node.header,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    *filesize,
-                    added,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
+        let entry = match &node.inner {
+            InnerNode::File(SquashfsFileWriter::Consumed(filesize, added)) => Some(Entry::file(
+                filename,
+                node.header,
+                inode,
+                link_count,
+                inode_writer,
+                *filesize,
+                added,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
             InnerNode::File(_) => unreachable!(),
-            InnerNode::Symlink(symlink) => {
-                return Ok(Entry::symlink(
-                    filename,
-                    node.header,
-                    symlink,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::CharacterDevice(char) => {
-                return Ok(Entry::char(
-                    filename,
-                    node.header,
-                    char,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::BlockDevice(block) => {
-                return Ok(Entry::block_device(
-                    filename,
-                    node.header,
-                    block,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::NamedPipe => {
-                return Ok(Entry::named_pipe(
-                    filename,
-                    node.header,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::Socket => {
-                return Ok(Entry::socket(
-                    filename,
-                    node.header,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
+            InnerNode::Symlink(symlink) => Some(Entry::symlink(
+                filename,
+                node.header,
+                symlink,
+                inode,
+                link_count,
+                inode_writer,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::CharacterDevice(char) => Some(Entry::char(
+                filename,
+                node.header,
+                char,
+                inode,
+                link_count,
+                inode_writer,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::BlockDevice(block) => Some(Entry::block_device(
+                filename,
+                node.header,
+                block,
+                inode,
+                link_count,
+                inode_writer,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::NamedPipe => Some(Entry::named_pipe(
+                filename,
+                node.header,
+                inode,
+                link_count,
+                inode_writer,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::Socket => Some(Entry::socket(
+                filename,
+                node.header,
+                inode,
+                link_count,
+                inode_writer,
+                xattr_index,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::Hardlink(_) => unreachable!(),
             // if dir, fall through
-            InnerNode::Dir(_) => (),
+            InnerNode::Dir(_) => None,
         };
+        if let Some(entry) = entry {
+            inodes.add(index, &entry);
+            return Ok(entry);
+        }
 
         // ladies and gentlemen, we have a directory
         let entries: Vec<_> = self
@@ -606,7 +802,9 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
                 self.write_inode_dir(
                     inode_writer,
                     dir_writer,
-                    node_id.get().try_into().unwrap(),
+                    xattr_writer,
+                    inodes,
+                    inode,
                     child_id,
                     superblock,
                     kind,
@@ -621,7 +819,20 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         let block_offset = dir_writer.uncompressed_bytes.len() as u16;
         trace!("WRITING DIR: {block_offset:#02x?}");
         let mut total_size: usize = 3;
+        // index the first header in each metadata block after the first, for faster lookups
+        let mut dir_index = vec![];
+        let mut index_block = block_index;
         for dir in Entry::into_dir(entries) {
+            if dir_writer.metadata_start != index_block {
+                let first = &dir.dir_entries[0];
+                dir_index.push(DirectoryIndex {
+                    index: (total_size - 3) as u32,
+                    start: dir_writer.metadata_start,
+                    name_size: u32::from(first.name_size),
+                    name: first.name.clone(),
+                });
+                index_block = dir_writer.metadata_start;
+            }
             let mut bytes = Cursor::new(vec![]);
             let mut writer = Writer::new(&mut bytes);
             dir.to_writer(&mut writer, kind.inner.type_endian)?;
@@ -631,18 +842,21 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         let entry = Entry::path(
             filename,
             node.header,
-            node_id.get().try_into().unwrap(),
+            inode,
             children_num,
-            parent_node_id,
+            parent_inode,
             inode_writer,
             total_size,
             block_offset,
             block_index,
+            dir_index,
+            xattr_index,
             superblock,
             kind,
             id_table,
         );
         trace!("[{:?}] entries: {:#02x?}", filename, &entry);
+        inodes.add(index, &entry);
         Ok(entry)
     }
 
@@ -658,11 +872,47 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
             superblock.flags |= Flags::DataHasBeenDeduplicated as u16;
         }
 
+        if self.uncompressed_inodes {
+            superblock.flags |= Flags::InodesStoredUncompressed as u16;
+        }
+        if self.uncompressed_data {
+            superblock.flags |= Flags::DataBlockStoredUncompressed as u16;
+        }
+        if self.uncompressed_fragments {
+            superblock.flags |= Flags::FragmentsStoredUncompressed as u16;
+        }
+        if self.uncompressed_xattrs {
+            superblock.flags |= Flags::XattrsAreStoredUncompressed as u16;
+        }
+
+        if self.no_fragments {
+            superblock.flags |= Flags::FragmentsAreNotUsed as u16;
+        } else if self.tail_ends {
+            superblock.flags |= Flags::FragmentsAreAlwaysGenerated as u16;
+        }
+
         trace!("{:#02x?}", self.root);
 
         // Empty Squashfs Superblock
         w.write_all(&[0x00; 96])?;
 
+        // record the filters selected from for each block
+        if let Some(CompressionExtra::Xz(ExtraXz { filters: Some(filters), .. })) =
+            self.fs_compressor.extra
+        {
+            let xz = match self.fs_compressor.options {
+                Some(CompressionOptions::Xz(xz)) => Xz { filters, ..xz },
+                _ => Xz {
+                    dictionary_size: self.block_size,
+                    filters,
+                    bit_opts: None,
+                    fb: None,
+                    dictionary_size_last: false,
+                },
+            };
+            self.fs_compressor.options = Some(CompressionOptions::Xz(xz));
+        }
+
         if self.emit_compression_options {
             trace!("writing compression options, if exists");
             let options = self.kind.inner.compressor.compression_options(
@@ -675,33 +925,52 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
 
         let mut data_writer = DataWriter::new(
             self.kind.inner.compressor,
+            self.kind.inner.transformer,
             self.fs_compressor,
             self.block_size,
             self.no_duplicate_files,
+            self.tail_ends,
+            self.no_fragments,
+            self.uncompressed_data,
+            self.uncompressed_fragments,
         );
         let mut inode_writer = MetadataWriter::new(
             self.fs_compressor,
             self.block_size,
             Kind { inner: self.kind.inner.clone() },
         );
+        inode_writer.uncompressed = self.uncompressed_inodes;
         let mut dir_writer = MetadataWriter::new(
             self.fs_compressor,
             self.block_size,
             Kind { inner: self.kind.inner.clone() },
         );
+        dir_writer.uncompressed = self.uncompressed_inodes;
+        let mut kv_writer = MetadataWriter::new(
+            self.fs_compressor,
+            self.block_size,
+            Kind { inner: self.kind.inner.clone() },
+        );
+        kv_writer.uncompressed = self.uncompressed_xattrs;
+        let mut xattr_writer = XattrWriter::new(kv_writer);
 
         info!("Creating Inodes and Dirs");
         //trace!("TREE: {:#02x?}", &self.root);
         info!("Writing Data");
         self.write_data(self.fs_compressor, self.block_size, &mut w, &mut data_writer)?;
-        info!("Writing Data Fragments");
-        // Compress fragments and write
-        data_writer.finalize(&mut w)?;
+        if !self.no_fragments {
+            info!("Writing Data Fragments");
+            // Compress fragments and write
+            data_writer.finalize(&mut w)?;
+        }
 
         info!("Writing Other stuff");
+        let mut inodes = self.inode_links()?;
         let root = self.write_inode_dir(
             &mut inode_writer,
             &mut dir_writer,
+            &mut xattr_writer,
+            &mut inodes,
             0,
             1.try_into().unwrap(),
             &superblock,
@@ -709,7 +978,7 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
             &self.id_table,
         )?;
         superblock.root_inode = ((root.start as u64) << 16) | ((root.offset as u64) & 0xffff);
-        superblock.inode_count = self.root.nodes.len().try_into().unwrap();
+        superblock.inode_count = inodes.count;
         superblock.block_size = self.block_size;
         superblock.block_log = self.block_log;
         superblock.mod_time = self.mod_time;
@@ -728,11 +997,24 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         superblock.frag_table = table_position;
         superblock.frag_count = count;
 
+        if self.export_table {
+            info!("Writing Export Lookup Table");
+            let (table_position, _) =
+                self.write_lookup_table(&mut w, &inodes.exports, Export::SIZE)?;
+            superblock.export_table = table_position;
+            superblock.flags |= Flags::NFSExportTableExists as u16;
+        }
+
         info!("Writing Id Lookup Table");
         let (table_position, count) = self.write_lookup_table(&mut w, &self.id_table, Id::SIZE)?;
         superblock.id_table = table_position;
         superblock.id_count = count.try_into().unwrap();
 
+        if !xattr_writer.ids.is_empty() {
+            info!("Writing Xattr Table");
+            superblock.xattr_table = self.write_xattr_table(&mut w, &mut xattr_writer)?;
+        }
+
         info!("Finalize Superblock and End Bytes");
         let bytes_written = self.finalize(w, &mut superblock)?;
 
@@ -751,13 +1033,14 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         if self.pad_len != 0 {
             // Pad out block_size to 4K
             info!("Writing Padding");
-            let blocks_used: u32 = u32::try_from(superblock.bytes_used).unwrap() / self.pad_len;
-            let total_pad_len = (blocks_used + 1) * self.pad_len;
-            pad_len = total_pad_len - u32::try_from(superblock.bytes_used).unwrap();
+            let pad = u64::from(self.pad_len);
+            let blocks_used = superblock.bytes_used / pad;
+            let total_pad_len = (blocks_used + 1) * pad;
+            pad_len = total_pad_len - superblock.bytes_used;
 
             // Write 1K at a time
             let mut total_written = 0;
-            while w.stream_position()? < (superblock.bytes_used + u64::from(pad_len)) {
+            while w.stream_position()? < (superblock.bytes_used + pad_len) {
                 let arr = &[0x00; 1024];
 
                 // check if last block to write
@@ -768,7 +1051,7 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
                     1024
                 };
 
-                w.write_all(&arr[..len.try_into().unwrap()])?;
+                w.write_all(&arr[..len as usize])?;
                 total_written += len;
             }
         }
@@ -790,7 +1073,7 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
 
         //clean any cache, make sure the output is on disk
         w.flush()?;
-        Ok(superblock.bytes_used + u64::from(pad_len))
+        Ok(superblock.bytes_used + pad_len)
     }
 
     /// For example, writing a fragment table:
@@ -826,6 +1109,34 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
         table: &[D],
         element_size: usize,
     ) -> Result<(u64, u32), BackhandError>
+    where
+        D: DekuWriter<deku::ctx::Endian>,
+        W: Write + Seek,
+    {
+        let ptrs = self.write_lookup_blocks(&mut w, table, element_size)?;
+
+        let table_position = w.stream_position()?;
+        let count = table.len() as u32;
+
+        // write ptr
+        for ptr in ptrs {
+            let mut bytes = Cursor::new(vec![]);
+            ptr.to_writer(&mut Writer::new(&mut bytes), self.kind.inner.type_endian)?;
+            let mut bytes = bytes.into_inner();
+            self.kind.inner.transformer.to(&mut bytes, None)?;
+            w.write_all(&bytes)?;
+        }
+
+        Ok((table_position, count))
+    }
+
+    /// Write `table` as metadata blocks, returning the position of each block
+    fn write_lookup_blocks<D, W>(
+        &self,
+        mut w: W,
+        table: &[D],
+        element_size: usize,
+    ) -> Result<Vec<u64>, BackhandError>
     where
         D: DekuWriter<deku::ctx::Endian>,
         W: Write + Seek,
@@ -849,6 +1160,7 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
                 let mut writer = Writer::new(&mut w);
                 len.to_writer(&mut writer, self.kind.inner.data_endian)?;
                 // write metadata bytes
+                self.kind.inner.transformer.to(table_bytes.get_mut(), None)?;
                 w.write_all(table_bytes.get_ref())?;
 
                 table_bytes.get_mut().clear();
@@ -856,16 +1168,86 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
             }
         }
 
-        let table_position = w.stream_position()?;
-        let count = table.len() as u32;
+        Ok(ptrs)
+    }
 
-        // write ptr
+    /// Write the xattr key/value pairs, followed by the xattr lookup table
+    ///
+    /// Unlike the other lookup tables, the pointers are preceded by a [`XattrIdTable`] header,
+    /// the position of which is returned for `superblock.xattr_table`.
+    fn write_xattr_table<W>(
+        &self,
+        mut w: W,
+        xattr_writer: &mut XattrWriter,
+    ) -> Result<u64, BackhandError>
+    where
+        W: Write + Seek,
+    {
+        let xattr_table_start = w.stream_position()?;
+        xattr_writer.kv_writer.finalize(&mut w)?;
+
+        let ptrs = self.write_lookup_blocks(&mut w, &xattr_writer.ids, XattrId::SIZE)?;
+
+        let table_position = w.stream_position()?;
+        let table =
+            XattrIdTable { xattr_table_start, xattr_ids: xattr_writer.ids.len() as u32, unused: 0 };
+        let mut bytes = Cursor::new(vec![]);
+        table.to_writer(&mut Writer::new(&mut bytes), self.kind.inner.type_endian)?;
         for ptr in ptrs {
-            let mut writer = Writer::new(&mut w);
-            ptr.to_writer(&mut writer, self.kind.inner.type_endian)?;
+            ptr.to_writer(&mut Writer::new(&mut bytes), self.kind.inner.type_endian)?;
+        }
+        // transformed as read, the header together with the first pointer
+        let mut bytes = bytes.into_inner();
+        let header_len = bytes.len().min(XattrIdTable::SIZE + 8);
+        self.kind.inner.transformer.to(&mut bytes[..header_len], None)?;
+        for ptr in bytes[header_len..].chunks_mut(8) {
+            self.kind.inner.transformer.to(ptr, None)?;
         }
+        w.write_all(&bytes)?;
 
-        Ok((table_position, count))
+        Ok(table_position)
+    }
+
+    /// Number the inodes of all nodes, hard links sharing the inode of their target
+    fn inode_links(&self) -> Result<InodeLinks<'_>, BackhandError> {
+        let mut targets = vec![None; self.root.nodes.len()];
+        let mut link_counts = vec![1; self.root.nodes.len()];
+        for (index, node) in self.root.nodes.iter().enumerate() {
+            if let InnerNode::Hardlink(SquashfsHardlink { link }) = &node.inner {
+                let target = self.root.node_index(link).ok_or(BackhandError::FileNotFound)?;
+                match &self.root.node(target).unwrap().inner {
+                    InnerNode::Dir(_) | InnerNode::Hardlink(_) => {
+                        error!("invalid hard link target: {}", link.display());
+                        return Err(BackhandError::InvalidFilePath);
+                    }
+                    _ => (),
+                }
+                targets[index] = Some(target.get() - 1);
+                link_counts[target.get() - 1] += 1;
+            }
+        }
+
+        let mut count = 0;
+        let mut numbers = vec![0; self.root.nodes.len()];
+        for (index, target) in targets.iter().enumerate() {
+            if target.is_none() {
+                count += 1;
+                numbers[index] = count;
+            }
+        }
+        for (index, target) in targets.iter().enumerate() {
+            if let Some(target) = target {
+                numbers[index] = numbers[*target];
+            }
+        }
+
+        Ok(InodeLinks {
+            numbers,
+            link_counts,
+            count,
+            written: HashMap::new(),
+            exports: vec![Export { num: 0 }; count as usize],
+        })
     }
 
     /// Return index of id, adding if required
@@ -882,6 +1264,29 @@ impl<'a, 'b, 'c> FilesystemWriter<'a, 'b, 'c> {
     }
 }
 
+/// Inode numbering of the nodes, by node index
+struct InodeLinks<'a> {
+    /// Inode number of each node, shared between a hard link and its target
+    numbers: Vec<u32>,
+    /// Number of directory entries pointing to the inode of each node
+    link_counts: Vec<u32>,
+    /// Total number of inodes
+    count: u32,
+    /// Entries of the inodes already written
+    written: HashMap<usize, Entry<'a>>,
+    /// Reference to each written inode, by inode number
+    exports: Vec<Export>,
+}
+
+impl<'a> InodeLinks<'a> {
+    /// Record that the inode of node `index` was written as `entry`
+    fn add(&mut self, index: usize, entry: &Entry<'a>) {
+        let reference = (u64::from(entry.start) << 16) | u64::from(entry.offset);
+        self.exports[entry.inode as usize - 1] = Export { num: reference };
+        self.written.insert(index, entry.clone());
+    }
+}
+
 struct WriterWithOffset<W: WriteSeek> {
     w: W,
     offset: u64,
@@ -938,6 +1343,22 @@ impl FilesystemCompressor {
             }
             //others having no options is always valid
             (_, None) => {}
+            // like mksquashfs, only lzo1x_999 has a compression level
+            (Compressor::Lzo, Some(CompressionOptions::Lzo(lzo)))
+                if (lzo.algorithm == LzoAlgorithm::Lzo1x999)
+                    != (1..=9).contains(&lzo.compression_level) =>
+            {
+                error!("invalid lzo compression level");
+                return Err(BackhandError::InvalidCompressionOption);
+            }
+            // liblzma only accepts lc + lp <= 4, pb <= 4 and a nice_len of 2..=273
+            (Compressor::Xz, Some(CompressionOptions::Xz(xz)))
+                if xz.lc_lp_pb().is_some_and(|(lc, lp, pb)| lc + lp > 4 || pb > 4)
+                    || xz.fb.is_some_and(|fb| fb != 0 && !(2..=273).contains(&fb)) =>
+            {
+                error!("invalid xz lc, lp, pb or fb");
+                return Err(BackhandError::InvalidCompressionOption);
+            }
             //only the corresponding option are valid
             (Compressor::Gzip, Some(CompressionOptions::Gzip(_)))
             | (Compressor::Lzma, Some(CompressionOptions::Lzma))
@@ -958,7 +1379,7 @@ impl FilesystemCompressor {
     /// These options will be written to the image when
     /// <https://github.com/wcampbell0x2a/backhand/issues/53> is fixed.
     pub fn options(&mut self, options: CompressionOptions) -> Result<(), BackhandError> {
-        self.options = Some(options);
+        self.options = Self::new(self.id, Some(options))?.options;
         Ok(())
     }
 
@@ -985,6 +1406,7 @@ pub enum CompressionExtra {
 #[derive(Debug, Copy, Clone, Default)]
 pub struct ExtraXz {
     pub(crate) level: Option<u32>,
+    pub(crate) filters: Option<XzFilter>,
 }
 
 impl ExtraXz {
@@ -997,4 +1419,15 @@ impl ExtraXz {
 
         Ok(())
     }
+
+    /// Compress each data block with every one of `filters` on its own and with no filter, keeping
+    /// the smallest, like mksquashfs `-Xbcj`. `filters` are recorded in the compression options
+    pub fn filters(&mut self, filters: XzFilter) -> Result<(), BackhandError> {
+        if filters.bits() & !XzFilter::ALL != 0 {
+            return Err(BackhandError::InvalidCompressionOption);
+        }
+        self.filters = Some(filters);
+
+        Ok(())
+    }
 }
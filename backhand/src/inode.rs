@@ -8,9 +8,11 @@ use deku::prelude::*;
 use crate::data::DataSize;
 use crate::dir::DirectoryIndex;
 use crate::entry::Entry;
+use crate::error::BackhandError;
 use crate::kind::Kind;
 use crate::metadata::MetadataWriter;
 use crate::squashfs::SuperBlock;
+use crate::xattr::NO_XATTR;
 
 #[derive(Debug, DekuRead, DekuWrite, Clone, PartialEq, Eq)]
 #[deku(ctx = "bytes_used: u64, block_size: u32, block_log: u16, type_endian: deku::ctx::Endian")]
@@ -27,6 +29,20 @@ impl Inode {
         Inode { id, header, inner }
     }
 
+    /// Index into the xattr lookup table, or `0xffff_ffff` if the inode has no xattrs
+    pub fn xattr_index(&self) -> u32 {
+        match &self.inner {
+            InodeInner::ExtendedDirectory(dir) => dir.xattr_index,
+            InodeInner::ExtendedFile(file) => file.xattr_index,
+            InodeInner::ExtendedSymlink(symlink) => symlink.xattr_index,
+            InodeInner::ExtendedBlockDevice(device) => device.xattr_index,
+            InodeInner::ExtendedCharacterDevice(device) => device.xattr_index,
+            InodeInner::ExtendedNamedPipe(ipc) => ipc.xattr_index,
+            InodeInner::ExtendedSocket(ipc) => ipc.xattr_index,
+            _ => NO_XATTR,
+        }
+    }
+
     /// Write to `m_writer`, creating Entry
     pub(crate) fn to_bytes<'a>(
         &self,
@@ -67,21 +83,20 @@ impl Inode {
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 #[rustfmt::skip]
 pub enum InodeId {
-    BasicDirectory       = 1,
-    BasicFile            = 2,
-    BasicSymlink         = 3,
-    BasicBlockDevice     = 4,
-    BasicCharacterDevice = 5,
-    BasicNamedPipe       = 6, // aka FIFO
-    BasicSocket          = 7,
-    ExtendedDirectory    = 8,
-    ExtendedFile         = 9,
-    // TODO:
-    // Extended Symlink = 10
-    // Extended Block Device = 11
-    // Extended Character Device = 12
-    // Extended Named Pipe (FIFO) = 13
-    // Extended Socked = 14
+    BasicDirectory          = 1,
+    BasicFile               = 2,
+    BasicSymlink            = 3,
+    BasicBlockDevice        = 4,
+    BasicCharacterDevice    = 5,
+    BasicNamedPipe          = 6, // aka FIFO
+    BasicSocket             = 7,
+    ExtendedDirectory       = 8,
+    ExtendedFile            = 9,
+    ExtendedSymlink         = 10,
+    ExtendedBlockDevice     = 11,
+    ExtendedCharacterDevice = 12,
+    ExtendedNamedPipe       = 13, // aka FIFO
+    ExtendedSocket          = 14,
 }
 
 impl InodeId {
@@ -89,6 +104,11 @@ impl InodeId {
         match self {
             Self::ExtendedDirectory => InodeId::BasicDirectory,
             Self::ExtendedFile => InodeId::BasicFile,
+            Self::ExtendedSymlink => InodeId::BasicSymlink,
+            Self::ExtendedBlockDevice => InodeId::BasicBlockDevice,
+            Self::ExtendedCharacterDevice => InodeId::BasicCharacterDevice,
+            Self::ExtendedNamedPipe => InodeId::BasicNamedPipe,
+            Self::ExtendedSocket => InodeId::BasicSocket,
             _ => self,
         }
     }
@@ -127,6 +147,21 @@ pub enum InodeInner {
 
     #[deku(id = "InodeId::ExtendedFile")]
     ExtendedFile(#[deku(ctx = "bytes_used, block_size, block_log")] ExtendedFile),
+
+    #[deku(id = "InodeId::ExtendedSymlink")]
+    ExtendedSymlink(ExtendedSymlink),
+
+    #[deku(id = "InodeId::ExtendedBlockDevice")]
+    ExtendedBlockDevice(ExtendedDeviceSpecialFile),
+
+    #[deku(id = "InodeId::ExtendedCharacterDevice")]
+    ExtendedCharacterDevice(ExtendedDeviceSpecialFile),
+
+    #[deku(id = "InodeId::ExtendedNamedPipe")]
+    ExtendedNamedPipe(ExtendedIPCNode),
+
+    #[deku(id = "InodeId::ExtendedSocket")]
+    ExtendedSocket(ExtendedIPCNode),
 }
 
 #[derive(Debug, DekuRead, DekuWrite, Clone, Copy, PartialEq, Eq, Default)]
@@ -187,10 +222,15 @@ pub struct BasicFile {
 )]
 pub struct ExtendedFile {
     pub blocks_start: u64,
-    #[deku(assert = "((*file_size as u128) < TiB1) && (*file_size < bytes_used)")]
+    #[deku(assert = "((*file_size as u128) < TiB1)")]
     pub file_size: u64,
+    /// Bytes of `file_size` stored as holes, not present in the image
     pub sparse: u64,
     pub link_count: u32,
+    // block sizes must be present in the image
+    #[deku(
+        assert = "block_count(block_size, block_log, *frag_index, *file_size) * 4 < bytes_used"
+    )]
     pub frag_index: u32,
     pub block_offset: u32,
     pub xattr_index: u32,
@@ -198,7 +238,7 @@ pub struct ExtendedFile {
     pub block_sizes: Vec<DataSize>,
 }
 
-fn block_count(block_size: u32, block_log: u16, fragment: u32, file_size: u64) -> u64 {
+pub(crate) fn block_count(block_size: u32, block_log: u16, fragment: u32, file_size: u64) -> u64 {
     const NO_FRAGMENT: u32 = 0xffffffff;
 
     if fragment == NO_FRAGMENT {
@@ -208,11 +248,14 @@ fn block_count(block_size: u32, block_log: u16, fragment: u32, file_size: u64) -
     }
 }
 
+/// Longest symlink target, PATH_MAX as accepted by Linux
+pub(crate) const SYMLINK_MAX: u32 = 4096;
+
 #[derive(DekuRead, DekuWrite, Clone, PartialEq, Eq)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct BasicSymlink {
     pub link_count: u32,
-    #[deku(assert = "*target_size < 256")]
+    #[deku(assert = "*target_size <= SYMLINK_MAX")]
     pub target_size: u32,
     #[deku(count = "target_size")]
     pub target_path: Vec<u8>,
@@ -223,13 +266,42 @@ impl fmt::Debug for BasicSymlink {
         f.debug_struct("BasicSymlink")
             .field("link_count", &self.link_count)
             .field("target_size", &self.target_size)
-            .field("target_path", &self.target())
+            .field("target_path", &String::from_utf8_lossy(&self.target_path))
             .finish()
     }
 }
 impl BasicSymlink {
-    pub fn target(&self) -> String {
-        std::str::from_utf8(&self.target_path).unwrap().to_string()
+    /// Target of the symlink, failing if not UTF-8
+    pub fn target(&self) -> Result<String, BackhandError> {
+        Ok(std::str::from_utf8(&self.target_path)?.to_string())
+    }
+}
+
+#[derive(DekuRead, DekuWrite, Clone, PartialEq, Eq)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct ExtendedSymlink {
+    pub link_count: u32,
+    #[deku(assert = "*target_size <= SYMLINK_MAX")]
+    pub target_size: u32,
+    #[deku(count = "target_size")]
+    pub target_path: Vec<u8>,
+    pub xattr_index: u32,
+}
+
+impl fmt::Debug for ExtendedSymlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtendedSymlink")
+            .field("link_count", &self.link_count)
+            .field("target_size", &self.target_size)
+            .field("target_path", &String::from_utf8_lossy(&self.target_path))
+            .field("xattr_index", &self.xattr_index)
+            .finish()
+    }
+}
+impl ExtendedSymlink {
+    /// Target of the symlink, failing if not UTF-8
+    pub fn target(&self) -> Result<String, BackhandError> {
+        Ok(std::str::from_utf8(&self.target_path)?.to_string())
     }
 }
 
@@ -245,3 +317,18 @@ pub struct BasicDeviceSpecialFile {
 pub struct IPCNode {
     pub link_count: u32,
 }
+
+#[derive(Debug, DekuRead, DekuWrite, Clone, PartialEq, Eq)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct ExtendedDeviceSpecialFile {
+    pub link_count: u32,
+    pub device_number: u32,
+    pub xattr_index: u32,
+}
+
+#[derive(Debug, DekuRead, DekuWrite, Clone, PartialEq, Eq)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct ExtendedIPCNode {
+    pub link_count: u32,
+    pub xattr_index: u32,
+}
@@ -1,13 +1,40 @@
 //! Types of image formats
 
 use core::fmt;
+use std::cmp::Reverse;
+use std::io::{Read, Seek, SeekFrom};
 use std::sync::Arc;
 
 use crate::{
-    compressor::{CompressionAction, DefaultCompressor},
+    compressor::{CompressionAction, DefaultCompressor, VendorLzmaCompressor},
+    error::BackhandError,
+    metadata::{self, METADATA_MAXSIZE},
+    reader::{BufReadSeek, SquashfsReaderWithOffset},
+    squashfs::read_superblock,
     transformer::{DefaultTransformer, TransformAction},
 };
 
+type DynInnerKind =
+    InnerKind<dyn CompressionAction + Send + Sync, dyn TransformAction + Send + Sync>;
+
+/// All known kinds by name, in the order tried by [`Kind::detect`]
+const KINDS: [(&str, DynInnerKind); 14] = [
+    ("le_v4_0", LE_V4_0),
+    ("be_v4_0", BE_V4_0),
+    ("avm_be_v4_0", AVM_BE_V4_0),
+    ("le_v3_0", LE_V3_0),
+    ("be_v3_0", BE_V3_0),
+    ("le_v2_0", LE_V2_0),
+    ("be_v2_0", BE_V2_0),
+    ("le_v1_0", LE_V1_0),
+    ("be_v1_0", BE_V1_0),
+    ("shsq_le_v3_0", SHSQ_LE_V3_0),
+    ("qshs_be_v3_0", QSHS_BE_V3_0),
+    ("sqlz_be_v3_0", SQLZ_BE_V3_0),
+    ("brcm_le_v3_0", BRCM_LE_V3_0),
+    ("brcm_be_v3_0", BRCM_BE_V3_0),
+];
+
 /// Kind Magic - First 4 bytes of image
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[non_exhaustive]
@@ -27,6 +54,32 @@ impl Magic {
     }
 }
 
+/// How closely an image found by [`Kind::detect`] matches its [`Kind`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// Only the magic matches, the superblock can't be parsed as this kind
+    Magic,
+    /// The superblock was parsed, but the block size, the position of a table or the length of
+    /// the first inode metadata block is invalid
+    SuperBlock,
+    /// The superblock was parsed and is valid
+    Valid,
+}
+
+/// Image found by [`Kind::detect`]
+#[derive(Debug)]
+pub struct Candidate {
+    /// Offset of the image from the start of the reader
+    pub offset: u64,
+    /// Name of the kind, as used by [`Kind::from_target`], or `None` if no known kind has the
+    /// endian of the metadata lengths of `kind`
+    pub target: Option<&'static str>,
+    pub kind: Kind,
+    pub confidence: Confidence,
+    /// Why the confidence isn't higher, or the version and compressor of a valid image
+    pub reason: String,
+}
+
 /// Kind Endian
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Endian {
@@ -163,15 +216,168 @@ impl Kind {
     /// - `"le_v4_0"`: [`LE_V4_0`]
     /// - `"be_v4_0"`: [`BE_V4_0`]
     /// - `"avm_be_v4_0"`: [`AVM_BE_V4_0`]
+    /// - `"le_v3_0"`: [`LE_V3_0`]
+    /// - `"be_v3_0"`: [`BE_V3_0`]
+    /// - `"le_v2_0"`: [`LE_V2_0`]
+    /// - `"be_v2_0"`: [`BE_V2_0`]
+    /// - `"le_v1_0"`: [`LE_V1_0`]
+    /// - `"be_v1_0"`: [`BE_V1_0`]
+    /// - `"shsq_le_v3_0"`: [`SHSQ_LE_V3_0`]
+    /// - `"qshs_be_v3_0"`: [`QSHS_BE_V3_0`]
+    /// - `"sqlz_be_v3_0"`: [`SQLZ_BE_V3_0`]
+    /// - `"brcm_le_v3_0"`: [`BRCM_LE_V3_0`]
+    /// - `"brcm_be_v3_0"`: [`BRCM_BE_V3_0`]
+    ///
+    /// Use [`Kind::detect`] to find the kind of an image
     pub fn from_target(s: &str) -> Result<Kind, String> {
-        let kind = match s {
-            "avm_be_v4_0" => AVM_BE_V4_0,
-            "be_v4_0" => BE_V4_0,
-            "le_v4_0" => LE_V4_0,
-            _ => return Err("not a valid kind".to_string()),
+        KINDS
+            .into_iter()
+            .find(|(target, _)| *target == s)
+            .map(|(_, kind)| Kind { inner: Arc::new(kind) })
+            .ok_or_else(|| "not a valid kind".to_string())
+    }
+
+    /// Find images of all known kinds in `reader`, at any byte offset
+    ///
+    /// Every offset starting with the magic of a kind is checked by parsing the superblock of
+    /// that kind, then its block size, the position of its tables and the length of the first
+    /// inode metadata block. Each kind is also checked with the endian of the metadata lengths
+    /// swapped, the way AVM images differ from [`BE_V4_0`]. Candidates are ranked by
+    /// [`Confidence`], then by offset, then in the order of [`Kind::from_target`]. Vendor kinds
+    /// sharing the magic of a standard kind are ranked after it.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # use std::fs::File;
+    /// # use std::io::BufReader;
+    /// # use backhand::{kind::{Confidence, Kind}, Squashfs};
+    /// let mut file = BufReader::new(File::open("firmware.bin").unwrap());
+    /// let candidates = Kind::detect(&mut file).unwrap();
+    /// let best = candidates.iter().find(|c| c.confidence == Confidence::Valid).unwrap();
+    /// let kind = Kind::from_kind(&best.kind);
+    /// let squashfs = Squashfs::from_reader_with_offset_and_kind(file, best.offset, kind).unwrap();
+    /// ```
+    pub fn detect<R: BufReadSeek>(reader: &mut R) -> Result<Vec<Candidate>, BackhandError> {
+        let magics: Vec<[u8; 4]> = KINDS.iter().map(|(_, kind)| kind.magic).collect();
+
+        // scan for all magics, keeping the last bytes that could start one in the next read
+        reader.rewind()?;
+        let mut offsets = vec![];
+        let mut window = vec![];
+        let mut start = 0;
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            window.extend_from_slice(buf);
+            let len = buf.len();
+            reader.consume(len);
+
+            for (index, bytes) in window.windows(4).enumerate() {
+                if magics.iter().any(|magic| magic == bytes) {
+                    offsets.push(start + index as u64);
+                }
+            }
+            let consumed = window.len() - window.len().min(3);
+            start += consumed as u64;
+            window.drain(..consumed);
+        }
+
+        let mut candidates = vec![];
+        for offset in offsets {
+            candidates.extend(Self::probe(reader, offset)?);
+        }
+        candidates.sort_by_key(|candidate| Reverse(candidate.confidence));
+        reader.rewind()?;
+        Ok(candidates)
+    }
+
+    /// Check all known kinds with a magic matching the image at `offset`, and each of them with
+    /// the endian of the metadata lengths swapped
+    pub(crate) fn probe<R: BufReadSeek>(
+        reader: &mut R,
+        offset: u64,
+    ) -> Result<Vec<Candidate>, BackhandError> {
+        let mut reader = SquashfsReaderWithOffset::new(reader, offset)?;
+        let total_length = reader.seek(SeekFrom::End(0))?;
+        let mut magic = [0; 4];
+        reader.rewind()?;
+        if reader.read_exact(&mut magic).is_err() {
+            return Ok(vec![]);
+        }
+
+        let mut candidates = vec![];
+        for (target, inner) in KINDS {
+            if inner.magic != magic {
+                continue;
+            }
+            // skip the swapped endian if it is a known kind, such as AVM
+            let swapped_known = KINDS.iter().any(|(_, known)| {
+                known.magic == inner.magic
+                    && known.type_endian == inner.type_endian
+                    && known.data_endian != inner.data_endian
+                    && known.version_major == inner.version_major
+            });
+            let swapped = (!swapped_known).then(|| {
+                let data_endian = match inner.data_endian {
+                    deku::ctx::Endian::Little => deku::ctx::Endian::Big,
+                    deku::ctx::Endian::Big => deku::ctx::Endian::Little,
+                };
+                (None, Kind { inner: Arc::new(InnerKind { data_endian, ..inner }) })
+            });
+            let kind = Kind { inner: Arc::new(inner) };
+
+            for (target, kind) in [(Some(target), kind)].into_iter().chain(swapped) {
+                reader.rewind()?;
+                let (confidence, reason) = Self::check(&mut reader, &kind, total_length);
+                candidates.push(Candidate { offset, target, kind, confidence, reason });
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// [`Confidence`] of the image in `reader` being `kind`, and the reason
+    fn check<R: BufReadSeek>(
+        reader: &mut R,
+        kind: &Kind,
+        total_length: u64,
+    ) -> (Confidence, String) {
+        let superblock = match read_superblock(reader, kind) {
+            Ok(superblock) => superblock,
+            Err(e) => return (Confidence::Magic, e.to_string()),
+        };
+        if let Err(field) =
+            superblock.check_block_size().and_then(|_| superblock.check_tables(total_length))
+        {
+            return (Confidence::SuperBlock, format!("invalid {field}"));
+        }
+
+        // the first inode metadata block must end before the directory table
+        let mut bytes = [0; 2];
+        if let Err(e) = reader
+            .seek(SeekFrom::Start(superblock.inode_table))
+            .and_then(|_| reader.read_exact(&mut bytes))
+        {
+            return (Confidence::SuperBlock, format!("invalid inode_table: {e}"));
+        }
+        let metadata_len = match kind.inner.data_endian {
+            deku::ctx::Endian::Little => u16::from_le_bytes(bytes),
+            deku::ctx::Endian::Big => u16::from_be_bytes(bytes),
         };
+        let len = u64::from(metadata::len(metadata_len));
+        if len == 0
+            || len > METADATA_MAXSIZE as u64
+            || superblock.inode_table + 2 + len > superblock.dir_table
+        {
+            return (Confidence::SuperBlock, "invalid metadata length".to_string());
+        }
 
-        Ok(Kind { inner: Arc::new(kind) })
+        let reason = format!(
+            "v{}.{} {:?}",
+            superblock.version_major, superblock.version_minor, superblock.compressor
+        );
+        (Confidence::Valid, reason)
     }
 
     /// From a known Squashfs image Kind, return a [`Kind`]
@@ -299,3 +505,252 @@ pub const AVM_BE_V4_0: InnerKind<
     compressor: &DefaultCompressor,
     transformer: &DefaultTransformer,
 };
+
+/// Little-Endian Superblock v3.0, also reading v3.1 images. Read-only, written as [`LE_V4_0`]
+pub const LE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"hsqs",
+    type_endian: deku::ctx::Endian::Little,
+    data_endian: deku::ctx::Endian::Little,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Big-Endian Superblock v3.0, also reading v3.1 images. Read-only, written as [`BE_V4_0`]
+pub const BE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"sqsh",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Little-Endian Superblock v2.0, also reading v2.1 images. Read-only, written as [`LE_V4_0`]
+pub const LE_V2_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"hsqs",
+    type_endian: deku::ctx::Endian::Little,
+    data_endian: deku::ctx::Endian::Little,
+    version_major: 2,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Big-Endian Superblock v2.0, also reading v2.1 images. Read-only, written as [`BE_V4_0`]
+pub const BE_V2_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"sqsh",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 2,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Little-Endian Superblock v1.0. Read-only, written as [`LE_V4_0`]
+pub const LE_V1_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"hsqs",
+    type_endian: deku::ctx::Endian::Little,
+    data_endian: deku::ctx::Endian::Little,
+    version_major: 1,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Big-Endian Superblock v1.0. Read-only, written as [`BE_V4_0`]
+pub const BE_V1_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"sqsh",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 1,
+    version_minor: 0,
+    compressor: &DefaultCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Realtek firmware, with the `shsq` magic of sqlzma. Blocks are zlib or LZMA,
+/// see [`VendorLzmaCompressor`]. Read-only, written as [`LE_V4_0`]
+pub const SHSQ_LE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"shsq",
+    type_endian: deku::ctx::Endian::Little,
+    data_endian: deku::ctx::Endian::Little,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &VendorLzmaCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Big-Endian sqlzma firmware, with the `qshs` magic. Blocks are zlib or LZMA,
+/// see [`VendorLzmaCompressor`]. Read-only, written as [`BE_V4_0`]
+pub const QSHS_BE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"qshs",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &VendorLzmaCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// TP-Link and Netgear firmware, with the `sqlz` magic of sqlzma. Blocks are zlib or LZMA,
+/// see [`VendorLzmaCompressor`]. Read-only, written as [`BE_V4_0`]
+pub const SQLZ_BE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"sqlz",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &VendorLzmaCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Broadcom and DD-WRT firmware, with LZMA in place of zlib. Blocks are zlib or LZMA,
+/// see [`VendorLzmaCompressor`]. Read-only, written as [`LE_V4_0`]
+pub const BRCM_LE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"hsqs",
+    type_endian: deku::ctx::Endian::Little,
+    data_endian: deku::ctx::Endian::Little,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &VendorLzmaCompressor,
+    transformer: &DefaultTransformer,
+};
+
+/// Big-Endian Broadcom firmware, with LZMA in place of zlib. Blocks are zlib or LZMA,
+/// see [`VendorLzmaCompressor`]. Read-only, written as [`BE_V4_0`]
+pub const BRCM_BE_V3_0: InnerKind<
+    dyn CompressionAction + Send + Sync,
+    dyn TransformAction + Send + Sync,
+> = InnerKind {
+    magic: *b"sqsh",
+    type_endian: deku::ctx::Endian::Big,
+    data_endian: deku::ctx::Endian::Big,
+    version_major: 3,
+    version_minor: 0,
+    compressor: &VendorLzmaCompressor,
+    transformer: &DefaultTransformer,
+};
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{FilesystemWriter, NodeHeader, Squashfs};
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_detect() {
+        let image = |kind: Kind| {
+            let mut fs = FilesystemWriter::default();
+            fs.set_kind(kind);
+            fs.push_file(Cursor::new(b"hello"), "hello", NodeHeader::default()).unwrap();
+            let mut bytes = Cursor::new(vec![]);
+            fs.write(&mut bytes).unwrap();
+            bytes.into_inner()
+        };
+
+        // a bare magic, then images at unaligned offsets
+        let mut bytes = b"hsqs..".to_vec();
+        let mut offsets = vec![];
+        let mixed = Kind::from_const(LE_V4_0).unwrap().with_data_endian(Endian::Big);
+        for kind in [LE_V4_0, BE_V4_0, AVM_BE_V4_0] {
+            offsets.push(bytes.len() as u64);
+            bytes.extend(image(Kind::from_const(kind).unwrap()));
+            bytes.extend(b"...");
+        }
+        offsets.push(bytes.len() as u64);
+        bytes.extend(image(mixed));
+
+        // a stray superblock with its tables at the end of the reader
+        let mut stray = image(Kind::from_const(LE_V4_0).unwrap())[..96].to_vec();
+        // bytes_used, id_table, xattr_table, inode_table, dir_table, frag_table, export_table
+        for (field, value) in
+            [(40, 96), (48, 96), (56, u64::MAX), (64, 96), (72, 96), (80, u64::MAX), (88, u64::MAX)]
+        {
+            stray[field..field + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        let stray_offset = bytes.len() as u64;
+        bytes.extend(stray);
+
+        let mut reader = Cursor::new(bytes);
+        let candidates = Kind::detect(&mut reader).unwrap();
+        let valid: Vec<_> = candidates
+            .iter()
+            .filter(|c| c.confidence == Confidence::Valid)
+            .map(|c| (c.offset, c.target))
+            .collect();
+        // kinds only differing in the endian of metadata lengths are told apart
+        assert_eq!(
+            valid,
+            [
+                (offsets[0], Some("le_v4_0")),
+                (offsets[1], Some("be_v4_0")),
+                (offsets[2], Some("avm_be_v4_0")),
+                (offsets[3], None),
+            ]
+        );
+        let mixed = candidates.iter().find(|c| c.offset == offsets[3]).unwrap();
+        assert_eq!(mixed.kind.inner.data_endian, deku::ctx::Endian::Big);
+        let bare = candidates.iter().find(|c| c.offset == 0).unwrap();
+        assert_eq!(bare.confidence, Confidence::Magic);
+        let stray = candidates.iter().find(|c| c.offset == stray_offset).unwrap();
+        assert_eq!(stray.confidence, Confidence::SuperBlock);
+
+        let best = &candidates[0];
+        let kind = Kind::from_kind(&best.kind);
+        Squashfs::from_reader_with_offset_and_kind(reader, best.offset, kind).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_other_kind() {
+        let mut fs = FilesystemWriter::default();
+        fs.push_file(Cursor::new(b"hello"), "hello", NodeHeader::default()).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+
+        let kind = Kind::from_const(BE_V4_0).unwrap();
+        let Err(BackhandError::OtherKind { candidates, .. }) =
+            Squashfs::from_reader_with_offset_and_kind(bytes, 0, kind)
+        else {
+            panic!("expected other kind");
+        };
+        let targets: Vec<_> = candidates.iter().map(|c| c.target).collect();
+        assert_eq!(targets, [Some("le_v4_0")]);
+    }
+}
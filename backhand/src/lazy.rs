@@ -0,0 +1,409 @@
+//! Read from on-disk image on demand
+
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+
+use deku::prelude::*;
+use tracing::{error, info, trace};
+
+use crate::dir::{self, Dir, DirEntry};
+use crate::error::BackhandError;
+use crate::filesystem::node::Nodes;
+use crate::inode::{Inode, InodeId};
+use crate::kinds::{Kind, LE_V4_0};
+use crate::metadata::{MetadataCache, MetadataReader};
+use crate::reader::{BufReadSeek, SquashFsReader, SquashfsReaderWithOffset};
+use crate::squashfs::{dir_location, DirTree};
+use crate::unix_string::OsStrExt;
+use crate::xattr::XattrTable;
+use crate::{
+    FilesystemReader, FilesystemReaderFile, Id, Node, NodeHeader, Squashfs, SquashfsFileReader,
+    SuperBlock, Xattr,
+};
+
+type Metadata<'a, 'b> = MetadataReader<'a, Box<dyn BufReadSeek + 'b>>;
+
+/// Default count of uncompressed metadata blocks kept in memory by [`LazySquashfs`]
+pub const DEFAULT_METADATA_CACHE_SIZE: usize = 64;
+
+/// Squashfs Image read on demand
+///
+/// Unlike [`Squashfs`], only the Superblock and the Fragment, Id and Xattr tables are read
+/// up front. Inodes and directories are read when visited, keeping a bounded cache of
+/// uncompressed metadata blocks, see [`Self::set_metadata_cache_size`].
+///
+/// Only images of v4.0 are supported.
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use std::io::{BufReader, Read};
+/// # use backhand::{InnerNode, LazySquashfs};
+/// let file = BufReader::new(File::open("image.squashfs").unwrap());
+/// let squashfs = LazySquashfs::from_reader(file).unwrap();
+///
+/// // list a directory
+/// for node in squashfs.read_dir("/etc").unwrap().unwrap() {
+///     println!("{}", node.fullpath.display());
+/// }
+///
+/// // read a file
+/// let node = squashfs.node("/etc/passwd").unwrap().unwrap();
+/// if let InnerNode::File(file) = &node.inner {
+///     let mut bytes = vec![];
+///     squashfs.file(file).reader().read_to_end(&mut bytes).unwrap();
+/// }
+///
+/// // or read everything into a filesystem
+/// let filesystem = squashfs.into_filesystem_reader().unwrap();
+/// ```
+pub struct LazySquashfs<'b> {
+    pub superblock: SuperBlock,
+    /// Xattr Table Cache
+    xattr_table: Option<XattrTable>,
+    /// Uncompressed Inode and Directory Table blocks
+    metadata: Mutex<MetadataCache>,
+    /// Filesystem with only the root node, holding the file reader
+    fs: FilesystemReader<'b>,
+}
+
+impl<'b> LazySquashfs<'b> {
+    /// Create `LazySquashfs` from `Read`er, reading only the Superblock and lookup tables.
+    /// `reader` needs to start with the beginning of the Image.
+    pub fn from_reader(reader: impl BufReadSeek + 'b) -> Result<Self, BackhandError> {
+        Self::from_reader_with_offset(reader, 0)
+    }
+
+    /// Same as [`Self::from_reader`], but seek'ing to `offset` in `reader` before Reading
+    ///
+    /// Uses default [`Kind`]: [`LE_V4_0`]
+    pub fn from_reader_with_offset(
+        reader: impl BufReadSeek + 'b,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        Self::from_reader_with_offset_and_kind(reader, offset, Kind { inner: Arc::new(LE_V4_0) })
+    }
+
+    /// Same as [`Self::from_reader_with_offset`], but including custom `kind`
+    pub fn from_reader_with_offset_and_kind(
+        reader: impl BufReadSeek + 'b,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let mut reader: Box<dyn BufReadSeek + 'b> = if offset == 0 {
+            Box::new(reader)
+        } else {
+            let reader = SquashfsReaderWithOffset::new(reader, offset)?;
+            Box::new(reader)
+        };
+
+        if kind.inner.version_major < 4 {
+            error!("inodes of images older than v4.0 can't be read on demand");
+            return Err(BackhandError::UnsupportedSquashfsVersion(
+                kind.inner.version_major,
+                kind.inner.version_minor,
+            ));
+        }
+
+        let (superblock, compression_options) = Squashfs::checked_superblock(&mut reader, &kind)?;
+
+        info!("Reading Fragments");
+        let fragments = reader.fragments(&superblock, &kind)?.map(|a| a.1);
+
+        info!("Reading Ids");
+        let id = reader.id(&superblock, &kind)?.1;
+
+        info!("Reading Xattrs");
+        let xattr_table = reader.xattrs(&superblock, &kind)?;
+
+        let root = Nodes::new_root(NodeHeader::default());
+        let fs = FilesystemReader::new(
+            kind,
+            &superblock,
+            compression_options,
+            id,
+            fragments,
+            root,
+            reader,
+        );
+        let mut squashfs = Self {
+            superblock,
+            xattr_table,
+            metadata: Mutex::new(MetadataCache::new(DEFAULT_METADATA_CACHE_SIZE)),
+            fs,
+        };
+
+        let root_inode = squashfs.root_inode()?;
+        let header = NodeHeader::from_inode(root_inode.header, &squashfs.fs.id_table)?;
+        squashfs.fs.root.root_mut().header = header;
+        squashfs.fs.root.root_mut().xattrs = squashfs.xattrs(&root_inode)?;
+
+        info!("Successful Read");
+        Ok(squashfs)
+    }
+
+    /// Set the count of uncompressed metadata blocks kept in memory, at least one.
+    ///
+    /// Default: [`DEFAULT_METADATA_CACHE_SIZE`]
+    pub fn set_metadata_cache_size(&mut self, blocks: usize) {
+        self.metadata.get_mut().unwrap().set_capacity(blocks);
+    }
+
+    /// Run `f` with a reader of the metadata at `offset` into the block `start` bytes past `table`
+    fn with_metadata<T>(
+        &self,
+        table: u64,
+        start: u64,
+        offset: usize,
+        f: impl FnOnce(&mut Metadata<'_, 'b>) -> Result<T, BackhandError>,
+    ) -> Result<T, BackhandError> {
+        let Some(position) = table.checked_add(start) else {
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        };
+        let mut cache = self.metadata.lock().unwrap();
+        let mut reader = self.fs.reader.lock().unwrap();
+        let mut metadata = MetadataReader::new(
+            &mut *reader,
+            &mut cache,
+            &self.superblock,
+            &self.fs.kind,
+            position,
+            offset,
+        )?;
+        f(&mut metadata)
+    }
+
+    /// Inode at `inode_ref`
+    ///
+    /// Like [`SuperBlock::root_inode`], `inode_ref` holds the metadata block start relative to the
+    /// inode table in the upper bits and the offset into the uncompressed block in the lower 16
+    /// bits.
+    pub fn inode(&self, inode_ref: u64) -> Result<Inode, BackhandError> {
+        trace!("inode: {inode_ref:02x?}");
+        let superblock = &self.superblock;
+        let ctx = (
+            superblock.bytes_used,
+            superblock.block_size,
+            superblock.block_log,
+            self.fs.kind.inner.type_endian,
+        );
+        let offset = (inode_ref & 0xffff) as usize;
+        self.with_metadata(superblock.inode_table, inode_ref >> 16, offset, |metadata| {
+            let mut reader = Reader::new(metadata);
+            Ok(Inode::from_reader_with_ctx(&mut reader, ctx)?)
+        })
+    }
+
+    /// Root Inode
+    pub fn root_inode(&self) -> Result<Inode, BackhandError> {
+        self.inode(self.superblock.root_inode)
+    }
+
+    /// Directory listing of `dir_inode`, starting from the last indexed header before `name`
+    fn dirs(&self, dir_inode: &Inode, name: Option<&[u8]>) -> Result<Vec<Dir>, BackhandError> {
+        let (block_index, file_size, block_offset) = dir_location(dir_inode, name)?;
+        if file_size < 4 {
+            return Ok(vec![]);
+        }
+
+        let len = u64::from(file_size - 3);
+        let endian = self.fs.kind.inner.type_endian;
+        self.with_metadata(self.superblock.dir_table, block_index, block_offset, |metadata| {
+            let mut bytes = vec![];
+            if metadata.take(len).read_to_end(&mut bytes)? as u64 != len {
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
+
+            let mut dirs = vec![];
+            // Read until we fail to turn bytes into `T`
+            let mut cursor = Cursor::new(bytes);
+            let mut reader = Reader::new(&mut cursor);
+            while let Ok(dir) = Dir::from_reader_with_ctx(&mut reader, endian) {
+                dirs.push(dir);
+            }
+            Ok(dirs)
+        })
+    }
+
+    /// Inode at `path`, found by walking the directories from the root inode
+    ///
+    /// # Returns
+    /// `Ok(None)` if `path` doesn't exist
+    pub fn inode_from_path<P: AsRef<Path>>(&self, path: P) -> Result<Option<Inode>, BackhandError> {
+        let mut inode = self.root_inode()?;
+        for component in path.as_ref().components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => name.as_bytes(),
+                _ => return Err(BackhandError::InvalidFilePath),
+            };
+            if !matches!(inode.id, InodeId::BasicDirectory | InodeId::ExtendedDirectory) {
+                return Ok(None);
+            }
+            let dirs = self.dirs(&inode, Some(name))?;
+            let Some((d, entry)) = dir::find(&dirs, name) else {
+                return Ok(None);
+            };
+            inode = self.inode(d.inode_ref(entry))?;
+        }
+        Ok(Some(inode))
+    }
+
+    /// Node at `path`
+    ///
+    /// Directories are returned without their entries, see [`Self::read_dir`].
+    ///
+    /// # Returns
+    /// `Ok(None)` if `path` doesn't exist
+    pub fn node<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<Node<SquashfsFileReader>>, BackhandError> {
+        let Some(inode) = self.inode_from_path(&path)? else {
+            return Ok(None);
+        };
+        let fullpath = Path::new("/").join(path);
+        DirTree::node(self, fullpath, inode.id, &inode).map(Some)
+    }
+
+    /// Nodes of the entries of the directory at `path`, sorted by name
+    ///
+    /// Hard links are returned as the node they link to, and directories without their entries.
+    ///
+    /// # Returns
+    /// `Ok(None)` if `path` doesn't exist, and an error if it isn't a directory
+    pub fn read_dir<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<Vec<Node<SquashfsFileReader>>>, BackhandError> {
+        let Some(dir_inode) = self.inode_from_path(&path)? else {
+            return Ok(None);
+        };
+        let fullpath = Path::new("/").join(path);
+        let mut nodes = vec![];
+        for d in &self.dirs(&dir_inode, None)? {
+            for entry in &d.dir_entries {
+                let inode = self.inode(d.inode_ref(entry))?;
+                nodes.push(DirTree::node(self, fullpath.join(entry.name()?), inode.id, &inode)?);
+            }
+        }
+        Ok(Some(nodes))
+    }
+
+    /// Extended attributes of `inode`
+    ///
+    /// # Returns
+    /// `Ok(xattrs)`, empty if the inode has none
+    pub fn xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, BackhandError> {
+        match &self.xattr_table {
+            Some(table) => table.lookup(inode.xattr_index(), &self.fs.kind),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Return a file handler for this file
+    pub fn file<'a>(&'a self, file: &'a SquashfsFileReader) -> FilesystemReaderFile<'a, 'b> {
+        self.fs.file(file)
+    }
+
+    /// Convert into [`FilesystemReader`] by reading all inodes and directories
+    pub fn into_filesystem_reader(mut self) -> Result<FilesystemReader<'b>, BackhandError> {
+        self.fs.root = self.tree()?;
+        Ok(self.fs)
+    }
+}
+
+impl DirTree for LazySquashfs<'_> {
+    fn id_table(&self) -> &[Id] {
+        &self.fs.id_table
+    }
+
+    fn root(&self) -> Result<Cow<'_, Inode>, BackhandError> {
+        self.root_inode().map(Cow::Owned)
+    }
+
+    fn listing(&self, dir_inode: &Inode) -> Result<Vec<Dir>, BackhandError> {
+        self.dirs(dir_inode, None)
+    }
+
+    fn entry_inode(&self, dir: &Dir, entry: &DirEntry) -> Result<Cow<'_, Inode>, BackhandError> {
+        self.inode(dir.inode_ref(entry)).map(Cow::Owned)
+    }
+
+    fn inode_xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, BackhandError> {
+        self.xattrs(inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::kinds::LE_V3_0;
+    use crate::{FilesystemWriter, InnerNode};
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_lazy() {
+        let header = NodeHeader::default();
+        let mut fs = FilesystemWriter::default();
+        fs.push_dir("big", header).unwrap();
+        let names: Vec<_> = (0..600).map(|i| format!("a_rather_long_file_name_{i:05}")).collect();
+        for name in &names {
+            fs.push_file(Cursor::new(name.clone()), format!("big/{name}"), header).unwrap();
+        }
+        fs.push_dir_all("a/b/c", header).unwrap();
+        let data = vec![0x42; 0x30000];
+        fs.push_file(Cursor::new(data.clone()), "a/b/c/data", header).unwrap();
+        fs.push_symlink("b/c/data", "a/link", header).unwrap();
+        fs.push_hardlink("a/b/c/data", "a/hardlink").unwrap();
+        fs.push_char_device(1, "a/dev", header).unwrap();
+        fs.set_xattrs("a/b", vec![Xattr::new("user.lazy", "yes")]).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        let bytes = bytes.into_inner();
+
+        let mut squashfs = LazySquashfs::from_reader(Cursor::new(bytes.clone())).unwrap();
+        squashfs.set_metadata_cache_size(2);
+
+        // lookup through the directory index
+        let read = |node: Node<SquashfsFileReader>| {
+            let InnerNode::File(file) = &node.inner else {
+                panic!("expected file");
+            };
+            let mut out = vec![];
+            squashfs.file(file).reader().read_to_end(&mut out).unwrap();
+            out
+        };
+        for name in &names {
+            let node = squashfs.node(format!("/big/{name}")).unwrap().unwrap();
+            assert_eq!(read(node), name.as_bytes());
+        }
+        assert_eq!(read(squashfs.node("a/hardlink").unwrap().unwrap()), data);
+        assert!(squashfs.node("/big/a_rather_long_file_name_1").unwrap().is_none());
+        assert!(squashfs.node("/a/link/data").unwrap().is_none());
+        assert!(squashfs.metadata.lock().unwrap().len() <= 2);
+
+        // listing
+        let dir = squashfs.read_dir("/a").unwrap().unwrap();
+        let paths: Vec<_> = dir.iter().map(|node| node.fullpath.to_str().unwrap()).collect();
+        assert_eq!(paths, ["/a/b", "/a/dev", "/a/hardlink", "/a/link"]);
+        assert_eq!(dir[0].xattrs, vec![Xattr::new("user.lazy", "yes")]);
+        assert_eq!(squashfs.read_dir("big").unwrap().unwrap().len(), names.len());
+        assert!(squashfs.read_dir("/missing").unwrap().is_none());
+        assert!(squashfs.read_dir("/a/dev").is_err());
+
+        // the eager filesystem is the same
+        let eager = Squashfs::from_reader_with_offset(Cursor::new(bytes.clone()), 0).unwrap();
+        let eager = eager.into_filesystem_reader().unwrap();
+        let filesystem = squashfs.into_filesystem_reader().unwrap();
+        assert_eq!(format!("{:?}", filesystem.root), format!("{:?}", eager.root));
+
+        let kind = Kind { inner: Arc::new(LE_V3_0) };
+        assert!(matches!(
+            LazySquashfs::from_reader_with_offset_and_kind(Cursor::new(bytes), 0, kind),
+            Err(BackhandError::UnsupportedSquashfsVersion(3, 0))
+        ));
+    }
+}
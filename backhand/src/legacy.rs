@@ -0,0 +1,1114 @@
+//! Reading of SquashFS images older than v4.0, converted into the v4.0 types
+//!
+//! These images have the superblock, inodes and directories packed as C bitfields, and separate
+//! uid and gid tables. Only zlib compression is used.
+//!
+//! Inodes of v1.x and v2.x images have no inode number, these are numbered by their position in
+//! the uncompressed inode table instead.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use deku::ctx::{Endian, Limit};
+use deku::prelude::*;
+use solana_nohash_hasher::IntMap;
+use tracing::error;
+
+use crate::compressor::Compressor;
+use crate::data::DataSize;
+use crate::dir::{Dir, DirEntry, DirectoryIndex};
+use crate::error::BackhandError;
+use crate::fragment::Fragment;
+use crate::id::Id;
+use crate::inode::{
+    self, BasicDeviceSpecialFile, BasicDirectory, BasicFile, BasicSymlink, ExtendedDirectory,
+    ExtendedFile, IPCNode, Inode, InodeHeader, InodeId, InodeInner,
+};
+use crate::kinds::Kind;
+use crate::metadata::{self, METADATA_MAXSIZE};
+use crate::reader::SquashFsReader;
+use crate::squashfs::{Flags, SuperBlock, NOT_SET};
+use crate::xattr::NO_XATTR;
+
+/// Inode gid index meaning "same as the uid"
+const GUID_IS_UID: u64 = 255;
+
+/// Inode gid index meaning "same as the uid" in v1.x images
+const GUID_IS_UID_V1: u64 = 15;
+
+/// Inode types of v1.x images, each repeated for every 16 uids
+const TYPES_V1: u64 = 5;
+
+/// Inode type of v1.x named pipes and sockets, the actual type following the base header
+const IPC_TYPE_V1: u64 = 0;
+
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+
+/// Superblock of v1.x, v2.x and v3.x images
+///
+/// Fields only present in later versions default to the fields of the earlier versions.
+#[derive(Debug, Copy, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+pub(crate) struct LegacySuperBlock {
+    pub magic: [u8; 4],
+    pub inode_count: u32,
+    pub bytes_used_2: u32,
+    pub uid_start_2: u32,
+    pub guid_start_2: u32,
+    pub inode_table_start_2: u32,
+    pub directory_table_start_2: u32,
+    pub version_major: u16,
+    pub version_minor: u16,
+    pub block_size_1: u16,
+    pub block_log: u16,
+    pub flags: u8,
+    pub no_uids: u8,
+    pub no_guids: u8,
+    pub mkfs_time: u32,
+    pub root_inode: u64,
+    #[deku(cond = "*version_major >= 2", default = "u32::from(*block_size_1)")]
+    pub block_size: u32,
+    #[deku(cond = "*version_major >= 2", default = "0")]
+    pub fragments: u32,
+    #[deku(cond = "*version_major >= 2", default = "0")]
+    pub fragment_table_start_2: u32,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*bytes_used_2)")]
+    pub bytes_used: u64,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*uid_start_2)")]
+    pub uid_start: u64,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*guid_start_2)")]
+    pub guid_start: u64,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*inode_table_start_2)")]
+    pub inode_table_start: u64,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*directory_table_start_2)")]
+    pub directory_table_start: u64,
+    #[deku(cond = "*version_major >= 3", default = "u64::from(*fragment_table_start_2)")]
+    pub fragment_table_start: u64,
+    /// Only present in v3.1 images
+    #[deku(cond = "*version_major >= 3", default = "NOT_SET")]
+    pub lookup_table_start: u64,
+}
+
+impl LegacySuperBlock {
+    /// Highest supported minor version
+    const MAX_VERSION_MINOR: u16 = 1;
+
+    /// Read from the start of the image
+    pub(crate) fn read<R: Read + Seek>(reader: &mut R, kind: &Kind) -> Result<Self, BackhandError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut reader = Reader::new(reader);
+        Ok(Self::from_reader_with_ctx(&mut reader, kind.inner.type_endian)?)
+    }
+
+    /// Convert into the v4.0 [`SuperBlock`], keeping the version of the image
+    pub(crate) fn to_superblock(self, kind: &Kind) -> Result<SuperBlock, BackhandError> {
+        if self.magic != kind.inner.magic
+            || self.version_major != kind.inner.version_major
+            || self.version_minor > Self::MAX_VERSION_MINOR
+        {
+            error!("unsupported magic or version: {:02x?}", self);
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        }
+
+        let mut flags = u16::from(self.flags) | Flags::NoXattrsInArchive as u16;
+        let exportable = (self.version_major, self.version_minor) >= (3, 1)
+            && flags & Flags::NFSExportTableExists as u16 != 0;
+        if !exportable {
+            flags &= !(Flags::NFSExportTableExists as u16);
+        }
+
+        Ok(SuperBlock {
+            magic: self.magic,
+            inode_count: self.inode_count,
+            mod_time: self.mkfs_time,
+            block_size: self.block_size,
+            frag_count: self.fragments,
+            compressor: Compressor::Gzip,
+            block_log: self.block_log,
+            flags,
+            id_count: u16::from(self.no_uids) + u16::from(self.no_guids),
+            version_major: self.version_major,
+            version_minor: self.version_minor,
+            root_inode: self.root_inode,
+            bytes_used: self.bytes_used,
+            id_table: self.uid_start,
+            xattr_table: NOT_SET,
+            inode_table: self.inode_table_start,
+            dir_table: self.directory_table_start,
+            frag_table: self.fragment_table_start,
+            export_table: if exportable { self.lookup_table_start } else { NOT_SET },
+        })
+    }
+
+    /// Read the uid table followed by the gid table, as one id table
+    ///
+    /// # Returns
+    /// - `(uid_start, ids)`
+    pub(crate) fn ids<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        kind: &Kind,
+    ) -> Result<(u64, Vec<Id>), BackhandError> {
+        let mut ids = vec![];
+        for (start, count) in [(self.uid_start, self.no_uids), (self.guid_start, self.no_guids)] {
+            reader.seek(SeekFrom::Start(start))?;
+            let mut buf = vec![0u8; usize::from(count) * Id::SIZE];
+            reader.read_exact(&mut buf)?;
+            kind.inner.transformer.from(&mut buf, None)?;
+            for num in buf.chunks_exact(Id::SIZE) {
+                let num = num.try_into().unwrap();
+                let num = match kind.inner.type_endian {
+                    Endian::Little => u32::from_le_bytes(num),
+                    Endian::Big => u32::from_be_bytes(num),
+                };
+                ids.push(Id::new(num));
+            }
+        }
+        Ok((self.uid_start, ids))
+    }
+}
+
+/// Fragment table entry of v2.x images
+#[derive(Debug, Copy, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+struct FragmentV2 {
+    start: u32,
+    size: DataSize,
+}
+
+impl FragmentV2 {
+    const SIZE: u64 = 8;
+}
+
+/// Parse the fragment table of v2.x images, which has 32 bit pointers and entries
+///
+/// # Returns
+/// - `(first_metadata_block, fragments)`
+pub(crate) fn fragments<R: SquashFsReader>(
+    reader: &mut R,
+    superblock: &SuperBlock,
+    kind: &Kind,
+) -> Result<(u64, Vec<Fragment>), BackhandError> {
+    reader.seek(SeekFrom::Start(superblock.frag_table))?;
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    kind.inner.transformer.from(&mut buf, None)?;
+    let ptr = u64::from(match kind.inner.type_endian {
+        Endian::Little => u32::from_le_bytes(buf),
+        Endian::Big => u32::from_be_bytes(buf),
+    });
+
+    let size = u64::from(superblock.frag_count) * FragmentV2::SIZE;
+    let block_count = size.div_ceil(METADATA_MAXSIZE as u64);
+    let mut table = reader.metadata_with_count::<FragmentV2>(superblock, ptr, block_count, kind)?;
+    table.truncate(superblock.frag_count as usize);
+    let fragments = table.iter().map(|f| Fragment::new(u64::from(f.start), f.size, 0)).collect();
+    Ok((ptr, fragments))
+}
+
+/// C bitfields packed into consecutive bytes
+///
+/// Little-Endian images fill the fields from the least significant bit, Big-Endian images from
+/// the most significant bit.
+struct Bits {
+    value: u64,
+    len: u32,
+    used: u32,
+    endian: Endian,
+}
+
+impl Bits {
+    fn read<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        bytes: usize,
+        endian: Endian,
+    ) -> Result<Self, DekuError> {
+        let mut buf = [0u8; 8];
+        match endian {
+            Endian::Little => reader.read_bytes(bytes, &mut buf[..bytes])?,
+            Endian::Big => reader.read_bytes(bytes, &mut buf[8 - bytes..])?,
+        };
+        let value = match endian {
+            Endian::Little => u64::from_le_bytes(buf),
+            Endian::Big => u64::from_be_bytes(buf),
+        };
+        Ok(Self { value, len: bytes as u32 * 8, used: 0, endian })
+    }
+
+    /// Next field of `width` bits
+    fn take(&mut self, width: u32) -> u64 {
+        let shift = match self.endian {
+            Endian::Little => self.used,
+            Endian::Big => self.len - self.used - width,
+        };
+        self.used += width;
+        (self.value >> shift) & ((1 << width) - 1)
+    }
+}
+
+fn bytes<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    count: usize,
+    endian: Endian,
+) -> Result<Vec<u8>, DekuError> {
+    Vec::from_reader_with_ctx(reader, (Limit::new_count(count), endian))
+}
+
+/// Inode type stored in directory entries, which only has room for the basic types
+fn base_type(t: u64) -> Result<InodeId, DekuError> {
+    let id = match t {
+        1 => InodeId::BasicDirectory,
+        2 => InodeId::BasicFile,
+        3 => InodeId::BasicSymlink,
+        4 => InodeId::BasicBlockDevice,
+        5 => InodeId::BasicCharacterDevice,
+        6 => InodeId::BasicNamedPipe,
+        7 => InodeId::BasicSocket,
+        _ => return Err(DekuError::Parse(format!("invalid inode type: {t}").into())),
+    };
+    Ok(id)
+}
+
+/// Parse an inode into the v4.0 [`Inode`]
+///
+/// The uid and gid tables are joined into one id table, see [`LegacySuperBlock::ids`].
+/// `position` in the uncompressed inode table numbers inodes of v1.x and v2.x images.
+pub(crate) fn inode<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    superblock: &SuperBlock,
+    legacy: &LegacySuperBlock,
+    position: u64,
+    endian: Endian,
+) -> Result<Inode, DekuError> {
+    if legacy.version_major >= 3 {
+        inode_v3(reader, superblock, legacy, endian)
+    } else {
+        inode_v2(reader, superblock, legacy, position, endian)
+    }
+}
+
+fn inode_v3<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    superblock: &SuperBlock,
+    legacy: &LegacySuperBlock,
+    endian: Endian,
+) -> Result<Inode, DekuError> {
+    let mut base = Bits::read(reader, 4, endian)?;
+    let t = base.take(4);
+    let mode = base.take(12);
+    let uid = base.take(8);
+    let guid = base.take(8);
+    let gid = if guid == GUID_IS_UID { uid } else { u64::from(legacy.no_uids) + guid };
+    let header = InodeHeader {
+        permissions: mode as u16,
+        uid: uid as u16,
+        gid: gid as u16,
+        mtime: u32::from_reader_with_ctx(reader, endian)?,
+        inode_number: u32::from_reader_with_ctx(reader, endian)?,
+    };
+
+    let inner = match t {
+        1 => {
+            let link_count = u32::from_reader_with_ctx(reader, endian)?;
+            let mut bits = Bits::read(reader, 4, endian)?;
+            let file_size = bits.take(19) as u32;
+            let block_offset = bits.take(13) as u16;
+            let block_index = u32::from_reader_with_ctx(reader, endian)?;
+            let parent_inode = u32::from_reader_with_ctx(reader, endian)?;
+            match u16::try_from(file_size) {
+                Ok(file_size) => InodeInner::BasicDirectory(BasicDirectory {
+                    block_index,
+                    link_count,
+                    file_size,
+                    block_offset,
+                    parent_inode,
+                }),
+                Err(_) => InodeInner::ExtendedDirectory(ExtendedDirectory {
+                    link_count,
+                    file_size,
+                    block_index,
+                    parent_inode,
+                    index_count: 0,
+                    block_offset,
+                    xattr_index: NO_XATTR,
+                    dir_index: vec![],
+                }),
+            }
+        }
+        2 => {
+            let blocks_start = u64::from_reader_with_ctx(reader, endian)?;
+            let frag_index = u32::from_reader_with_ctx(reader, endian)?;
+            let block_offset = u32::from_reader_with_ctx(reader, endian)?;
+            let file_size = u32::from_reader_with_ctx(reader, endian)?;
+            let file = (1, blocks_start, frag_index, block_offset, u64::from(file_size));
+            self::file(reader, superblock, file, false, endian)?
+        }
+        3 => {
+            let link_count = u32::from_reader_with_ctx(reader, endian)?;
+            let target_size = u16::from_reader_with_ctx(reader, endian)?;
+            let target_path = bytes(reader, usize::from(target_size), endian)?;
+            InodeInner::BasicSymlink(BasicSymlink {
+                link_count,
+                target_size: u32::from(target_size),
+                target_path,
+            })
+        }
+        4 | 5 => {
+            let device = BasicDeviceSpecialFile {
+                link_count: u32::from_reader_with_ctx(reader, endian)?,
+                device_number: u32::from(u16::from_reader_with_ctx(reader, endian)?),
+            };
+            if t == 4 {
+                InodeInner::BasicBlockDevice(device)
+            } else {
+                InodeInner::BasicCharacterDevice(device)
+            }
+        }
+        6 | 7 => {
+            let ipc = IPCNode { link_count: u32::from_reader_with_ctx(reader, endian)? };
+            if t == 6 {
+                InodeInner::BasicNamedPipe(ipc)
+            } else {
+                InodeInner::BasicSocket(ipc)
+            }
+        }
+        8 => {
+            let link_count = u32::from_reader_with_ctx(reader, endian)?;
+            let mut bits = Bits::read(reader, 5, endian)?;
+            let file_size = bits.take(27) as u32;
+            let block_offset = bits.take(13) as u16;
+            let block_index = u32::from_reader_with_ctx(reader, endian)?;
+            let index_count = u16::from_reader_with_ctx(reader, endian)?;
+            let parent_inode = u32::from_reader_with_ctx(reader, endian)?;
+            let mut dir_index = vec![];
+            for _ in 0..index_count {
+                let index = u32::from_reader_with_ctx(reader, endian)?;
+                let start = u32::from_reader_with_ctx(reader, endian)?;
+                let name_size = u8::from_reader_with_ctx(reader, endian)?;
+                let name = bytes(reader, usize::from(name_size) + 1, endian)?;
+                dir_index.push(DirectoryIndex {
+                    index,
+                    start,
+                    name_size: u32::from(name_size),
+                    name,
+                });
+            }
+            InodeInner::ExtendedDirectory(ExtendedDirectory {
+                link_count,
+                file_size,
+                block_index,
+                parent_inode,
+                index_count,
+                block_offset,
+                xattr_index: NO_XATTR,
+                dir_index,
+            })
+        }
+        9 => {
+            let link_count = u32::from_reader_with_ctx(reader, endian)?;
+            let blocks_start = u64::from_reader_with_ctx(reader, endian)?;
+            let frag_index = u32::from_reader_with_ctx(reader, endian)?;
+            let block_offset = u32::from_reader_with_ctx(reader, endian)?;
+            let file_size = u64::from_reader_with_ctx(reader, endian)?;
+            let file = (link_count, blocks_start, frag_index, block_offset, file_size);
+            self::file(reader, superblock, file, false, endian)?
+        }
+        _ => return Err(DekuError::Parse(format!("invalid inode type: {t}").into())),
+    };
+
+    let id = match &inner {
+        InodeInner::ExtendedDirectory(_) => InodeId::ExtendedDirectory,
+        InodeInner::ExtendedFile(_) => InodeId::ExtendedFile,
+        _ => base_type(t)?,
+    };
+    Ok(Inode::new(id, header, inner))
+}
+
+/// Parse a v1.x or v2.x inode into the v4.0 [`Inode`]
+fn inode_v2<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    superblock: &SuperBlock,
+    legacy: &LegacySuperBlock,
+    position: u64,
+    endian: Endian,
+) -> Result<Inode, DekuError> {
+    let v1 = legacy.version_major == 1;
+    let (t, mode, uid, gid) = if v1 {
+        let mut base = Bits::read(reader, 3, endian)?;
+        let (t, mode, uid, guid) = (base.take(4), base.take(12), base.take(4), base.take(4));
+        // the high bits of the uid are stored in the type
+        let (t, uid_high) = if t == IPC_TYPE_V1 {
+            let mut ipc = Bits::read(reader, 1, endian)?;
+            (ipc.take(4), ipc.take(4))
+        } else {
+            ((t - 1) % TYPES_V1 + 1, (t - 1) / TYPES_V1)
+        };
+        let uid = uid_high * 16 + uid;
+        let gid = if guid == GUID_IS_UID_V1 { uid } else { u64::from(legacy.no_uids) + guid };
+        (t, mode, uid, gid)
+    } else {
+        let mut base = Bits::read(reader, 4, endian)?;
+        let (t, mode, uid, guid) = (base.take(4), base.take(12), base.take(8), base.take(8));
+        let gid = if guid == GUID_IS_UID { uid } else { u64::from(legacy.no_uids) + guid };
+        (t, mode, uid, gid)
+    };
+
+    // only directories and files have a mtime
+    let mut mtime = superblock.mod_time;
+    let inner = match t {
+        1 => {
+            let mut bits = Bits::read(reader, 4, endian)?;
+            let file_size = bits.take(19) as u16;
+            let block_offset = bits.take(13) as u16;
+            mtime = u32::from_reader_with_ctx(reader, endian)?;
+            let block_index = Bits::read(reader, 3, endian)?.take(24) as u32;
+            InodeInner::BasicDirectory(BasicDirectory {
+                block_index,
+                link_count: 2,
+                file_size,
+                block_offset,
+                parent_inode: 0,
+            })
+        }
+        2 => {
+            mtime = u32::from_reader_with_ctx(reader, endian)?;
+            let blocks_start = u32::from_reader_with_ctx(reader, endian)?;
+            let (frag_index, block_offset) = if v1 {
+                (NO_FRAGMENT, 0)
+            } else {
+                (
+                    u32::from_reader_with_ctx(reader, endian)?,
+                    u32::from_reader_with_ctx(reader, endian)?,
+                )
+            };
+            let file_size = u32::from_reader_with_ctx(reader, endian)?;
+            let file = (1, u64::from(blocks_start), frag_index, block_offset, u64::from(file_size));
+            self::file(reader, superblock, file, v1, endian)?
+        }
+        3 => {
+            let target_size = u16::from_reader_with_ctx(reader, endian)?;
+            let target_path = bytes(reader, usize::from(target_size), endian)?;
+            InodeInner::BasicSymlink(BasicSymlink {
+                link_count: 1,
+                target_size: u32::from(target_size),
+                target_path,
+            })
+        }
+        4 | 5 => {
+            let device = BasicDeviceSpecialFile {
+                link_count: 1,
+                device_number: u32::from(u16::from_reader_with_ctx(reader, endian)?),
+            };
+            if t == 4 {
+                InodeInner::BasicBlockDevice(device)
+            } else {
+                InodeInner::BasicCharacterDevice(device)
+            }
+        }
+        6 => InodeInner::BasicNamedPipe(IPCNode { link_count: 1 }),
+        7 => InodeInner::BasicSocket(IPCNode { link_count: 1 }),
+        8 if !v1 => {
+            let mut bits = Bits::read(reader, 5, endian)?;
+            let file_size = bits.take(27) as u32;
+            let block_offset = bits.take(13) as u16;
+            mtime = u32::from_reader_with_ctx(reader, endian)?;
+            let block_index = Bits::read(reader, 3, endian)?.take(24) as u32;
+            let index_count = u16::from_reader_with_ctx(reader, endian)?;
+            let mut dir_index = vec![];
+            for _ in 0..index_count {
+                let mut bits = Bits::read(reader, 8, endian)?;
+                let index = bits.take(27) as u32;
+                let start = bits.take(29) as u32;
+                let name_size = bits.take(8) as u32;
+                let name = bytes(reader, name_size as usize + 1, endian)?;
+                dir_index.push(DirectoryIndex { index, start, name_size, name });
+            }
+            InodeInner::ExtendedDirectory(ExtendedDirectory {
+                link_count: 2,
+                file_size,
+                block_index,
+                parent_inode: 0,
+                index_count,
+                block_offset,
+                xattr_index: NO_XATTR,
+                dir_index,
+            })
+        }
+        _ => return Err(DekuError::Parse(format!("invalid inode type: {t}").into())),
+    };
+
+    let header = InodeHeader {
+        permissions: mode as u16,
+        uid: uid as u16,
+        gid: gid as u16,
+        mtime,
+        inode_number: position as u32 + 1,
+    };
+    let id = match &inner {
+        InodeInner::ExtendedDirectory(_) => InodeId::ExtendedDirectory,
+        _ => base_type(t)?,
+    };
+    Ok(Inode::new(id, header, inner))
+}
+
+/// Read the block list of a file, `(link_count, blocks_start, frag_index, block_offset, file_size)`
+///
+/// v1.x images store each block size in 16 bits, with the uncompressed bit of metadata lengths.
+fn file<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    superblock: &SuperBlock,
+    (link_count, blocks_start, frag_index, block_offset, file_size): (u32, u64, u32, u32, u64),
+    short_block_sizes: bool,
+    endian: Endian,
+) -> Result<InodeInner, DekuError> {
+    let count =
+        inode::block_count(superblock.block_size, superblock.block_log, frag_index, file_size);
+    // block sizes must be present in the image
+    if count * 4 >= superblock.bytes_used {
+        return Err(DekuError::Parse(format!("invalid block count: {count}").into()));
+    }
+    let block_sizes = (0..count)
+        .map(|_| {
+            if short_block_sizes {
+                let size = u16::from_reader_with_ctx(reader, endian)?;
+                let uncompressed = !metadata::is_compressed(size);
+                Ok(DataSize::new(u32::from(metadata::len(size)), uncompressed))
+            } else {
+                DataSize::from_reader_with_ctx(reader, endian)
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
+    let basic = (u32::try_from(blocks_start), u32::try_from(file_size));
+    let inner = match basic {
+        (Ok(blocks_start), Ok(file_size)) if link_count == 1 => InodeInner::BasicFile(BasicFile {
+            blocks_start,
+            frag_index,
+            block_offset,
+            file_size,
+            block_sizes,
+        }),
+        _ => InodeInner::ExtendedFile(ExtendedFile {
+            blocks_start,
+            file_size,
+            sparse: 0,
+            link_count,
+            frag_index,
+            block_offset,
+            xattr_index: NO_XATTR,
+            block_sizes,
+        }),
+    };
+    Ok(inner)
+}
+
+/// Parse a directory header and its entries into the v4.0 [`Dir`]
+///
+/// Entries of v1.x and v2.x images are numbered like [`inode`], from `inode_blocks`: the offset of
+/// each inode table metadata block in the uncompressed inode table.
+pub(crate) fn dir<R: Read + Seek>(
+    reader: &mut Reader<R>,
+    version_major: u16,
+    inode_blocks: &IntMap<u64, u64>,
+    endian: Endian,
+) -> Result<Dir, DekuError> {
+    let (count, start, inode_num) = if version_major >= 3 {
+        let count = u8::from_reader_with_ctx(reader, endian)?;
+        let start = u32::from_reader_with_ctx(reader, endian)?;
+        (count, start, u32::from_reader_with_ctx(reader, endian)?)
+    } else {
+        let mut bits = Bits::read(reader, 4, endian)?;
+        let (count, start) = (bits.take(8) as u8, bits.take(24) as u32);
+        let Some(position) = inode_blocks.get(&u64::from(start)) else {
+            return Err(DekuError::Parse(format!("invalid inode block: {start}").into()));
+        };
+        (count, start, *position as u32 + 1)
+    };
+
+    let mut dir_entries = vec![];
+    for _ in 0..=count {
+        let mut bits = Bits::read(reader, 3, endian)?;
+        let offset = bits.take(13) as u16;
+        let t = base_type(bits.take(3))?;
+        let name_size = bits.take(8) as u16;
+        let inode_offset = if version_major >= 3 {
+            i16::from_reader_with_ctx(reader, endian)?
+        } else {
+            offset as i16
+        };
+        let name = bytes(reader, usize::from(name_size) + 1, endian)?;
+        dir_entries.push(DirEntry { offset, inode_offset, t, name_size, name });
+    }
+
+    Ok(Dir { count: u32::from(count), start, inode_num, dir_entries })
+}
+
+// The test images are assembled field by field from the same layout the reader uses, so they
+// check the reading and conversion logic, not the layout against images of mksquashfs 3.x, sqlzma
+// or vendor tools.
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::kinds::{
+        BE_V1_0, BE_V2_0, BE_V3_0, BE_V4_0, BRCM_BE_V3_0, BRCM_LE_V3_0, LE_V1_0, LE_V2_0, LE_V3_0,
+        LE_V4_0, QSHS_BE_V3_0, SHSQ_LE_V3_0, SQLZ_BE_V3_0,
+    };
+    use crate::{FilesystemReader, FilesystemWriter, InnerNode, Squashfs};
+
+    /// Compression of the data and metadata blocks of a test image
+    type Compress = fn(&[u8]) -> Vec<u8>;
+
+    /// Pack `(value, width)` fields into `len` bytes, the way [`Bits`] reads them
+    fn bits(endian: Endian, len: usize, fields: &[(u64, u32)]) -> Vec<u8> {
+        let total = len as u32 * 8;
+        let mut value = 0;
+        let mut used = 0;
+        for (field, width) in fields {
+            value |= field
+                << match endian {
+                    Endian::Little => used,
+                    Endian::Big => total - used - width,
+                };
+            used += width;
+        }
+        match endian {
+            Endian::Little => value.to_le_bytes()[..len].to_vec(),
+            Endian::Big => value.to_be_bytes()[8 - len..].to_vec(),
+        }
+    }
+
+    /// Image with a file, symlink, char device and an empty directory, listed in a root directory
+    /// with an index. Data and metadata are stored uncompressed, unless `compress` is set
+    fn image(
+        endian: Endian,
+        magic: [u8; 4],
+        version_minor: u16,
+        check: bool,
+        compress: Option<Compress>,
+    ) -> Vec<u8> {
+        let int = |value: u64, len: usize| match endian {
+            Endian::Little => value.to_le_bytes()[..len].to_vec(),
+            Endian::Big => value.to_be_bytes()[8 - len..].to_vec(),
+        };
+        let metadata = |bytes: &[u8]| {
+            let (size, bytes) = match compress {
+                Some(compress) => (0, compress(bytes)),
+                None => (0x8000, bytes.to_vec()),
+            };
+            let mut block = int(bytes.len() as u64 | size, 2);
+            if check {
+                block.push(0xff);
+            }
+            block.extend(bytes);
+            block
+        };
+        let base = |t, mode, guid, inode_number| {
+            [
+                bits(endian, 4, &[(t, 4), (mode, 12), (0, 8), (guid, 8)]),
+                int(0, 4),
+                int(inode_number, 4),
+            ]
+            .concat()
+        };
+        // 3.0 superblocks end before lookup_table_start
+        let superblock_len = if version_minor == 0 { 111 } else { 119 };
+        let data = match compress {
+            Some(compress) => compress(b"hello world"),
+            None => b"hello world".to_vec(),
+        };
+        let block_size = data.len() as u64 | if compress.is_some() { 0 } else { 1 << 24 };
+
+        // two headers, with the second one in the index of the root
+        let header =
+            |count: u64, inode_number| [int(count - 1, 1), int(0, 4), int(inode_number, 4)];
+        let mut dir = header(2, 1).concat();
+        let mut inodes = vec![];
+        let entry = |dir: &mut Vec<u8>, t: u64, name: &[u8], inodes: &[u8], inode_offset: u64| {
+            dir.extend(bits(
+                endian,
+                3,
+                &[(inodes.len() as u64, 13), (t, 3), (name.len() as u64 - 1, 8)],
+            ));
+            dir.extend(int(inode_offset, 2));
+            dir.extend_from_slice(name);
+        };
+        entry(&mut dir, 2, b"hello", &inodes, 0);
+        inodes.extend(base(2, 0o644, GUID_IS_UID, 1));
+        inodes
+            .extend([int(superblock_len, 8), int(0xffff_ffff, 4), int(0, 4), int(11, 4)].concat());
+        inodes.extend(int(block_size, 4));
+        entry(&mut dir, 3, b"link", &inodes, 1);
+        inodes.extend(base(3, 0o777, 0, 2));
+        inodes.extend([int(1, 4), int(5, 2), b"hello".to_vec()].concat());
+        let index = dir.len() as u64;
+        dir.extend(header(2, 3).concat());
+        entry(&mut dir, 5, b"null", &inodes, 0);
+        inodes.extend(base(5, 0o666, 0, 3));
+        inodes.extend([int(1, 4), int(1 << 8 | 3, 2)].concat());
+        entry(&mut dir, 1, b"sub", &inodes, 1);
+        inodes.extend(base(1, 0o755, 0, 4));
+        inodes.extend([int(2, 4), bits(endian, 4, &[(3, 19), (0, 13)])].concat());
+        inodes.extend([int(0, 4), int(5, 4)].concat());
+        let root_inode = inodes.len() as u64;
+        inodes.extend(base(8, 0o755, 0, 5));
+        inodes.extend(int(3, 4));
+        inodes.extend(bits(endian, 5, &[(dir.len() as u64 + 3, 27), (0, 13)]));
+        inodes.extend([int(0, 4), int(1, 2), int(6, 4)].concat());
+        inodes.extend([int(index, 4), int(0, 4), int(3, 1), b"null".to_vec()].concat());
+
+        let mut image = vec![0; superblock_len as usize];
+        image.extend(data);
+        let inode_table_start = image.len() as u64;
+        image.extend(metadata(&inodes));
+        let directory_table_start = image.len() as u64;
+        image.extend(metadata(&dir));
+        let uid_start = image.len() as u64;
+        image.extend(int(1000, 4));
+        let guid_start = image.len() as u64;
+        image.extend(int(100, 4));
+
+        let superblock = LegacySuperBlock {
+            magic,
+            inode_count: 5,
+            bytes_used_2: 0,
+            uid_start_2: 0,
+            guid_start_2: 0,
+            inode_table_start_2: 0,
+            directory_table_start_2: 0,
+            version_major: 3,
+            version_minor,
+            block_size_1: 0,
+            block_log: 17,
+            flags: if check { Flags::Unused as u8 } else { 0 },
+            no_uids: 1,
+            no_guids: 1,
+            mkfs_time: 0,
+            root_inode,
+            block_size: 0x20000,
+            fragments: 0,
+            fragment_table_start_2: 0,
+            bytes_used: image.len() as u64,
+            uid_start,
+            guid_start,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start: uid_start,
+            lookup_table_start: NOT_SET,
+        };
+        let mut bytes = Cursor::new(vec![]);
+        superblock.to_writer(&mut Writer::new(&mut bytes), endian).unwrap();
+        image[..superblock_len as usize]
+            .copy_from_slice(&bytes.get_ref()[..superblock_len as usize]);
+        image
+    }
+
+    /// Image of v1.x or v2.x, with the same files as [`image`] and a named pipe. The file is stored
+    /// in a fragment and the root directory has an index for v2.x
+    fn image_v2(endian: Endian, magic: [u8; 4], version_major: u16, version_minor: u16) -> Vec<u8> {
+        let v1 = version_major == 1;
+        let int = |value: u64, len: usize| match endian {
+            Endian::Little => value.to_le_bytes()[..len].to_vec(),
+            Endian::Big => value.to_be_bytes()[8 - len..].to_vec(),
+        };
+        let metadata =
+            |bytes: &[u8]| [int(bytes.len() as u64 | 0x8000, 2), bytes.to_vec()].concat();
+        // v1.x stores the high bits of the uid in the type
+        let base = |t: u64, mode, uid: u64, guid| {
+            if v1 {
+                let t = if t == 6 { IPC_TYPE_V1 } else { uid / 16 * TYPES_V1 + t };
+                let mut base = bits(endian, 3, &[(t, 4), (mode, 12), (uid % 16, 4), (guid, 4)]);
+                if t == IPC_TYPE_V1 {
+                    base.extend(bits(endian, 1, &[(6, 4), (uid / 16, 4)]));
+                }
+                base
+            } else {
+                bits(endian, 4, &[(t, 4), (mode, 12), (uid, 8), (guid, 8)])
+            }
+        };
+        let superblock_len = if v1 { 51 } else { 63 };
+        let data = b"hello world";
+
+        // two headers, with the second one in the index of the root for v2.x
+        let header = |count: u64| bits(endian, 4, &[(count - 1, 8), (0, 24)]);
+        let mut dir = header(3);
+        let mut inodes = vec![];
+        let entry = |dir: &mut Vec<u8>, t: u64, name: &[u8], inodes: &[u8]| {
+            let name_size = name.len() as u64 - 1;
+            dir.extend(bits(endian, 3, &[(inodes.len() as u64, 13), (t, 3), (name_size, 8)]));
+            dir.extend_from_slice(name);
+        };
+        entry(&mut dir, 6, b"fifo", &inodes);
+        inodes.extend(base(6, 0o644, 0, 0));
+        entry(&mut dir, 2, b"hello", &inodes);
+        let guid_is_uid = if v1 { GUID_IS_UID_V1 } else { GUID_IS_UID };
+        inodes.extend([base(2, 0o644, 0, guid_is_uid), int(0, 4)].concat());
+        if v1 {
+            inodes.extend([int(superblock_len, 4), int(data.len() as u64, 4)].concat());
+            inodes.extend(int(data.len() as u64 | 0x8000, 2));
+        } else {
+            inodes.extend([int(0, 4), int(0, 4), int(0, 4), int(data.len() as u64, 4)].concat());
+        }
+        entry(&mut dir, 3, b"link", &inodes);
+        inodes.extend([base(3, 0o777, 16, 0), int(5, 2), b"hello".to_vec()].concat());
+        let index = dir.len() as u64;
+        dir.extend(header(2));
+        entry(&mut dir, 5, b"null", &inodes);
+        inodes.extend([base(5, 0o666, 0, 0), int(1 << 8 | 3, 2)].concat());
+        entry(&mut dir, 1, b"sub", &inodes);
+        inodes.extend([base(1, 0o755, 0, 0), bits(endian, 4, &[(3, 19), (0, 13)])].concat());
+        inodes.extend([int(0, 4), int(0, 3)].concat());
+        let root_inode = inodes.len() as u64;
+        if v1 {
+            inodes.extend(base(1, 0o755, 0, 0));
+            inodes.extend(bits(endian, 4, &[(dir.len() as u64 + 3, 19), (0, 13)]));
+            inodes.extend([int(0, 4), int(0, 3)].concat());
+        } else {
+            inodes.extend(base(8, 0o755, 0, 0));
+            inodes.extend(bits(endian, 5, &[(dir.len() as u64 + 3, 27), (0, 13)]));
+            inodes.extend([int(0, 4), int(0, 3), int(1, 2)].concat());
+            inodes.extend(
+                [bits(endian, 8, &[(index, 27), (0, 29), (3, 8)]), b"null".to_vec()].concat(),
+            );
+        }
+
+        let mut image = vec![0; superblock_len as usize];
+        image.extend_from_slice(data);
+        let inode_table_start = image.len() as u64;
+        image.extend(metadata(&inodes));
+        let directory_table_start = image.len() as u64;
+        image.extend(metadata(&dir));
+        let fragment_table_start = if v1 {
+            0
+        } else {
+            let fragments_start = image.len() as u64;
+            image.extend(metadata(&[int(superblock_len, 4), int(11 | 1 << 24, 4)].concat()));
+            let fragment_table_start = image.len() as u64;
+            image.extend(int(fragments_start, 4));
+            fragment_table_start
+        };
+        let uid_start = image.len() as u64;
+        for uid in 0..17 {
+            image.extend(int(1000 + uid, 4));
+        }
+        let guid_start = image.len() as u64;
+        image.extend(int(100, 4));
+
+        let superblock = LegacySuperBlock {
+            magic,
+            inode_count: 6,
+            bytes_used_2: image.len() as u32,
+            uid_start_2: uid_start as u32,
+            guid_start_2: guid_start as u32,
+            inode_table_start_2: inode_table_start as u32,
+            directory_table_start_2: directory_table_start as u32,
+            version_major,
+            version_minor,
+            block_size_1: 0x8000,
+            block_log: 15,
+            flags: 0,
+            no_uids: 17,
+            no_guids: 1,
+            mkfs_time: 0,
+            root_inode,
+            block_size: 0x8000,
+            fragments: if v1 { 0 } else { 1 },
+            fragment_table_start_2: fragment_table_start as u32,
+            bytes_used: 0,
+            uid_start: 0,
+            guid_start: 0,
+            inode_table_start: 0,
+            directory_table_start: 0,
+            fragment_table_start: 0,
+            lookup_table_start: 0,
+        };
+        let mut bytes = Cursor::new(vec![]);
+        superblock.to_writer(&mut Writer::new(&mut bytes), endian).unwrap();
+        // fields of later versions are also written
+        image[..superblock_len as usize]
+            .copy_from_slice(&bytes.get_ref()[..superblock_len as usize]);
+        image
+    }
+
+    fn check(reader: &FilesystemReader, link_uid: u32) {
+        let nodes: HashMap<_, _> =
+            reader.files().map(|node| (node.fullpath.clone(), node)).collect();
+        let node = |path: &str| nodes[&PathBuf::from(path)];
+
+        let hello = node("/hello");
+        assert_eq!(
+            (hello.header.permissions, hello.header.uid, hello.header.gid),
+            (0o644, 1000, 1000)
+        );
+        let InnerNode::File(file) = &hello.inner else { panic!("expected file") };
+        let mut data = vec![];
+        reader.file(file).reader().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world");
+
+        let link = node("/link");
+        assert_eq!((link.header.uid, link.header.gid), (link_uid, 100));
+        let InnerNode::Symlink(symlink) = &link.inner else { panic!("expected symlink") };
+        assert_eq!(symlink.link, PathBuf::from("hello"));
+
+        let InnerNode::CharacterDevice(device) = &node("/null").inner else {
+            panic!("expected char device")
+        };
+        assert_eq!(device.device_number, 0x103);
+        assert!(matches!(node("/sub").inner, InnerNode::Dir(_)));
+        if let Some(fifo) = nodes.get(&PathBuf::from("/fifo")) {
+            assert!(matches!(fifo.inner, InnerNode::NamedPipe));
+        }
+    }
+
+    /// Read `bytes` as `kind` and check, then check again after writing as `v4_kind`
+    fn read_and_convert(bytes: Vec<u8>, kind: Kind, v4_kind: Kind, link_uid: u32) {
+        let squashfs =
+            Squashfs::from_reader_with_offset_and_kind(Cursor::new(bytes), 0, kind).unwrap();
+        // found through the directory index of the root, if any
+        for path in ["/hello", "/null", "/sub"] {
+            assert!(squashfs.inode_from_path(path).unwrap().is_some());
+        }
+        assert!(squashfs.inode_from_path("/missing").unwrap().is_none());
+        let reader = squashfs.into_filesystem_reader().unwrap();
+        check(&reader, link_uid);
+
+        let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        let bytes = Cursor::new(bytes.into_inner());
+        let reader = FilesystemReader::from_reader_with_offset_and_kind(bytes, 0, v4_kind).unwrap();
+        check(&reader, link_uid);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_v3_read_and_convert() {
+        let images = [
+            (Endian::Little, LE_V3_0, LE_V4_0, 0, false),
+            (Endian::Little, LE_V3_0, LE_V4_0, 1, true),
+            (Endian::Big, BE_V3_0, BE_V4_0, 0, true),
+            (Endian::Big, BE_V3_0, BE_V4_0, 1, false),
+        ];
+        for (endian, kind, v4_kind, version_minor, check_flag) in images {
+            let kind = Kind::from_const(kind).unwrap();
+            let bytes = image(endian, kind.magic(), version_minor, check_flag, None);
+            read_and_convert(bytes, kind, Kind::from_const(v4_kind).unwrap(), 1000);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_v1_v2_read_and_convert() {
+        let images = [
+            (Endian::Little, LE_V1_0, LE_V4_0, 0),
+            (Endian::Big, BE_V1_0, BE_V4_0, 0),
+            (Endian::Little, LE_V2_0, LE_V4_0, 0),
+            (Endian::Little, LE_V2_0, LE_V4_0, 1),
+            (Endian::Big, BE_V2_0, BE_V4_0, 0),
+            (Endian::Big, BE_V2_0, BE_V4_0, 1),
+        ];
+        for (endian, kind, v4_kind, version_minor) in images {
+            let kind = Kind::from_const(kind).unwrap();
+            let bytes = image_v2(endian, kind.magic(), kind.inner.version_major, version_minor);
+            read_and_convert(bytes, kind, Kind::from_const(v4_kind).unwrap(), 1016);
+        }
+    }
+
+    /// Write a new image with read-only `kind` set, checking it is written as `v4_kind`
+    #[cfg(feature = "xz")]
+    fn set_kind_and_write(kind: Kind, v4_kind: Kind) {
+        let mut fs = FilesystemWriter::default();
+        fs.push_file(Cursor::new(b"data"), "file", Default::default()).unwrap();
+        fs.set_kind(Kind::from_kind(&kind));
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        let bytes = bytes.into_inner();
+
+        let squashfs =
+            Squashfs::from_reader_with_offset_and_kind(Cursor::new(bytes.clone()), 0, v4_kind)
+                .unwrap();
+        assert_eq!(squashfs.superblock.version_major, 4);
+        assert_eq!(squashfs.superblock.version_minor, 0);
+        assert!(Squashfs::from_reader_with_offset_and_kind(Cursor::new(bytes), 0, kind).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_set_legacy_kind() {
+        let kinds = [
+            (LE_V3_0, LE_V4_0),
+            (BE_V3_0, BE_V4_0),
+            (LE_V2_0, LE_V4_0),
+            (BE_V2_0, BE_V4_0),
+            (LE_V1_0, LE_V4_0),
+            (BE_V1_0, BE_V4_0),
+        ];
+        for (kind, v4_kind) in kinds {
+            set_kind_and_write(Kind::from_const(kind).unwrap(), Kind::from_const(v4_kind).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_set_vendor_kind() {
+        let kinds = [
+            (SHSQ_LE_V3_0, LE_V4_0),
+            (QSHS_BE_V3_0, BE_V4_0),
+            (SQLZ_BE_V3_0, BE_V4_0),
+            (BRCM_LE_V3_0, LE_V4_0),
+            (BRCM_BE_V3_0, BE_V4_0),
+        ];
+        for (kind, v4_kind) in kinds {
+            set_kind_and_write(Kind::from_const(kind).unwrap(), Kind::from_const(v4_kind).unwrap());
+        }
+    }
+
+    /// LZMA with the 13 byte header of lzma-alone, or only the 5 byte properties
+    #[cfg(feature = "lzma")]
+    fn lzma(bytes: &[u8], size: bool) -> Vec<u8> {
+        use xz2::stream::{LzmaOptions, Stream};
+
+        let options = LzmaOptions::new_preset(6).unwrap();
+        let stream = Stream::new_lzma_encoder(&options).unwrap();
+        let mut out = vec![];
+        xz2::read::XzEncoder::new_stream(bytes, stream).read_to_end(&mut out).unwrap();
+        if size {
+            out[5..13].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+        } else {
+            out.drain(5..13);
+        }
+        out
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "lzma"))]
+    fn test_vendor_read_and_convert() {
+        let zlib = |bytes: &[u8]| {
+            let mut out = vec![];
+            flate2::read::ZlibEncoder::new(bytes, flate2::Compression::default())
+                .read_to_end(&mut out)
+                .unwrap();
+            out
+        };
+        let compressors: [Compress; 3] =
+            [zlib, |bytes| lzma(bytes, true), |bytes| lzma(bytes, false)];
+
+        let images = [
+            (Endian::Little, SHSQ_LE_V3_0, LE_V4_0),
+            (Endian::Big, QSHS_BE_V3_0, BE_V4_0),
+            (Endian::Big, SQLZ_BE_V3_0, BE_V4_0),
+            (Endian::Little, BRCM_LE_V3_0, LE_V4_0),
+            (Endian::Big, BRCM_BE_V3_0, BE_V4_0),
+        ];
+        for (endian, kind, v4_kind) in images {
+            let kind = Kind::from_const(kind).unwrap();
+            let v4_kind = Kind::from_const(v4_kind).unwrap();
+            for compress in compressors {
+                let bytes = image(endian, kind.magic(), 0, false, Some(compress));
+                read_and_convert(bytes, Kind::from_kind(&kind), Kind::from_kind(&v4_kind), 1000);
+            }
+        }
+    }
+}
@@ -12,6 +12,8 @@
 //! For reading an image and extracting its details and contents, use
 //! [`FilesystemReader::from_reader`].
 //!
+//! For large images, [`LazySquashfs`] reads inodes and directories only when visited.
+//!
 //! ### Writing
 //! For creating a modified or new image, use [`FilesystemWriter::from_fs_reader`].
 //! [`FilesystemWriter`] can also be created from scratch, without a previous image to base itself
@@ -68,18 +70,25 @@ mod fragment;
 mod id;
 mod inode;
 mod kinds;
+mod lazy;
+mod legacy;
+#[cfg(feature = "lz4")]
+mod lz4hc;
+#[cfg(feature = "lzo")]
+mod lzo1x;
 mod metadata;
 mod reader;
 mod squashfs;
 mod transformer;
 mod unix_string;
+mod xattr;
 
 pub use crate::data::DataSize;
 pub use crate::error::BackhandError;
 pub use crate::export::Export;
 pub use crate::filesystem::node::{
     InnerNode, Node, NodeHeader, SquashfsBlockDevice, SquashfsCharacterDevice, SquashfsDir,
-    SquashfsFileReader, SquashfsFileWriter, SquashfsSymlink,
+    SquashfsFileReader, SquashfsFileWriter, SquashfsHardlink, SquashfsSymlink,
 };
 pub use crate::filesystem::reader::{FilesystemReader, FilesystemReaderFile, SquashfsReadFile};
 pub use crate::filesystem::writer::{
@@ -88,22 +97,28 @@ pub use crate::filesystem::writer::{
 pub use crate::fragment::Fragment;
 pub use crate::id::Id;
 pub use crate::inode::{BasicFile, Inode};
+pub use crate::lazy::{LazySquashfs, DEFAULT_METADATA_CACHE_SIZE};
 pub use crate::reader::BufReadSeek;
 pub use crate::squashfs::{
     Flags, Squashfs, SuperBlock, DEFAULT_BLOCK_SIZE, DEFAULT_PAD_LEN, MAX_BLOCK_SIZE,
     MIN_BLOCK_SIZE,
 };
+pub use crate::xattr::{Xattr, XattrId};
 
 /// Support the wonderful world of vendor formats
 pub mod kind {
-    pub use crate::kinds::{Endian, Kind, Magic, AVM_BE_V4_0, BE_V4_0, LE_V4_0};
+    pub use crate::kinds::{
+        Candidate, Confidence, Endian, Kind, Magic, AVM_BE_V4_0, BE_V1_0, BE_V2_0, BE_V3_0,
+        BE_V4_0, BRCM_BE_V3_0, BRCM_LE_V3_0, LE_V1_0, LE_V2_0, LE_V3_0, LE_V4_0, QSHS_BE_V3_0,
+        SHSQ_LE_V3_0, SQLZ_BE_V3_0,
+    };
 }
 
 /// Compression Choice and Options
 pub mod compression {
     pub use crate::compressor::{
-        CompressionAction, CompressionOptions, Compressor, DefaultCompressor, Gzip, Lz4, Lzo, Xz,
-        Zstd,
+        CompressionAction, CompressionOptions, Compressor, DefaultCompressor, Gzip, GzipStrategies,
+        Lz4, Lz4Flags, Lzo, LzoAlgorithm, VendorLzmaCompressor, Xz, XzFilter, Zstd,
     };
 }
 
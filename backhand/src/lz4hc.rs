@@ -0,0 +1,158 @@
+//! LZ4 high compression block encoder
+//!
+//! `lz4_flex` only implements the fast LZ4 encoder. This searches hash chains for the longest
+//! match at each position, like `LZ4_compress_HC`, and emits the normal LZ4 block format so any
+//! LZ4 decoder can read it.
+
+const MIN_MATCH: usize = 4;
+/// Last bytes of the block that are always literals
+const LAST_LITERALS: usize = 5;
+/// A match can't start within this many bytes of the end of the block
+const MF_LIMIT: usize = 12;
+const MAX_DISTANCE: usize = 0xffff;
+const HASH_LOG: u32 = 15;
+/// Candidates searched per position, from the default LZ4HC level 9
+const MAX_ATTEMPTS: usize = 256;
+const NONE: u32 = u32::MAX;
+
+struct Match {
+    offset: usize,
+    len: usize,
+}
+
+struct HashChain<'a> {
+    input: &'a [u8],
+    head: Vec<u32>,
+    chain: Vec<u32>,
+    next_to_insert: usize,
+}
+
+impl<'a> HashChain<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            head: vec![NONE; 1 << HASH_LOG],
+            chain: vec![NONE; input.len()],
+            next_to_insert: 0,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = u32::from_le_bytes(self.input[pos..pos + 4].try_into().unwrap());
+        (bytes.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+    }
+
+    /// Add all positions before `pos` to the chains
+    fn insert_until(&mut self, pos: usize) {
+        while self.next_to_insert < pos {
+            let hash = self.hash(self.next_to_insert);
+            self.chain[self.next_to_insert] = self.head[hash];
+            self.head[hash] = self.next_to_insert as u32;
+            self.next_to_insert += 1;
+        }
+    }
+
+    /// Longest match for `pos` not extending into the last literals
+    fn find(&mut self, pos: usize) -> Option<Match> {
+        self.insert_until(pos);
+        let max_len = self.input.len() - LAST_LITERALS - pos;
+        let mut best: Option<Match> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..MAX_ATTEMPTS {
+            if candidate == NONE || pos - candidate as usize > MAX_DISTANCE {
+                break;
+            }
+            let start = candidate as usize;
+            let len = self.input[start..]
+                .iter()
+                .zip(&self.input[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= MIN_MATCH && best.as_ref().map_or(true, |best| len > best.len) {
+                best = Some(Match { offset: pos - start, len });
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.chain[start];
+        }
+        best
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<&Match>) {
+    let lit_len = literals.len();
+    let match_len = m.map_or(0, |m| m.len - MIN_MATCH);
+    out.push(((lit_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    if lit_len >= 15 {
+        write_len(out, lit_len - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some(m) = m {
+        out.extend_from_slice(&(m.offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_len(out, match_len - 15);
+        }
+    }
+}
+
+/// Compress `input` into a single LZ4 block
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 255 + 16);
+    let mut chain = HashChain::new(input);
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < input.len() {
+        let Some(mut best) = chain.find(pos) else {
+            pos += 1;
+            continue;
+        };
+
+        // lazy matching: prefer a longer match starting at the next byte
+        while pos + 1 + MF_LIMIT < input.len() {
+            match chain.find(pos + 1) {
+                Some(next) if next.len > best.len => {
+                    pos += 1;
+                    best = next;
+                }
+                _ => break,
+            }
+        }
+
+        write_sequence(&mut out, &input[anchor..pos], Some(&best));
+        pos += best.len;
+        anchor = pos;
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut input = vec![];
+        for i in 0..0x4000_u32 {
+            input.extend_from_slice(format!("line {} of {}\n", i % 97, i % 13).as_bytes());
+        }
+        input.extend_from_slice(&[0; 300]);
+        input.extend((0..1000_u32).map(|i| (i * 7 % 251) as u8));
+
+        for input in [&input[..], &[], &b"short"[..], &b"0123456789abcdef"[..]] {
+            let compressed = compress(input);
+            let decompressed = lz4_flex::decompress(&compressed, input.len()).unwrap();
+            assert_eq!(input, decompressed);
+        }
+        assert!(compress(&input).len() <= lz4_flex::compress(&input).len());
+    }
+}
@@ -0,0 +1,265 @@
+//! LZO1X block encoder
+//!
+//! `rust-lzo` only implements `lzo1x_1`. The other algorithms of mksquashfs emit the same LZO1X
+//! format and only differ in the size of their match dictionary and how hard it is searched, so
+//! they are implemented here and read back by the usual `lzo1x_decompress_safe`.
+
+use crate::compressor::LzoAlgorithm;
+
+const MIN_MATCH: usize = 3;
+/// Longest match searched for, the `SWD_F` of lzo1x_999
+const MAX_MATCH: usize = 2048;
+const M2_MAX_LEN: usize = 8;
+const M2_MAX_OFFSET: usize = 0x0800;
+const M3_MAX_OFFSET: usize = 0x4000;
+const M4_MAX_OFFSET: usize = 0xbfff;
+const M3_MARKER: u8 = 0x20;
+const M4_MARKER: u8 = 0x10;
+const NONE: u32 = u32::MAX;
+
+/// Match finder settings of each algorithm
+struct Config {
+    hash_bits: u32,
+    /// Candidates searched per position
+    max_chain: usize,
+    /// Stop searching once a match of this length is found
+    nice_len: usize,
+    /// Prefer a longer match starting at the next byte
+    lazy: bool,
+}
+
+impl Config {
+    fn new(algorithm: LzoAlgorithm, compression_level: u32) -> Self {
+        // (max_chain, nice_len, lazy) of lzo1x_999 levels 1 to 9
+        const LEVELS: [(usize, usize, bool); 9] = [
+            (4, 8, false),
+            (8, 16, false),
+            (16, 32, false),
+            (16, 16, true),
+            (32, 32, true),
+            (128, 128, true),
+            (256, 128, true),
+            (2048, MAX_MATCH, true),
+            (4096, MAX_MATCH, true),
+        ];
+        let fast = |hash_bits| Self { hash_bits, max_chain: 1, nice_len: MAX_MATCH, lazy: false };
+        match algorithm {
+            LzoAlgorithm::Lzo1x1 => fast(14),
+            LzoAlgorithm::Lzo1x1_11 => fast(11),
+            LzoAlgorithm::Lzo1x1_12 => fast(12),
+            LzoAlgorithm::Lzo1x1_15 => fast(15),
+            LzoAlgorithm::Lzo1x999 => {
+                let level = compression_level.clamp(1, 9) as usize;
+                let (max_chain, nice_len, lazy) = LEVELS[level - 1];
+                Self { hash_bits: 16, max_chain, nice_len, lazy }
+            }
+        }
+    }
+}
+
+struct Match {
+    offset: usize,
+    len: usize,
+}
+
+impl Match {
+    /// Short matches are only worth it when they fit in a 2 byte `M2`
+    fn is_worth(&self) -> bool {
+        self.len > MIN_MATCH || self.offset <= M2_MAX_OFFSET
+    }
+}
+
+struct HashChain<'a> {
+    input: &'a [u8],
+    config: Config,
+    head: Vec<u32>,
+    chain: Vec<u32>,
+    next_to_insert: usize,
+}
+
+impl<'a> HashChain<'a> {
+    fn new(input: &'a [u8], config: Config) -> Self {
+        Self {
+            input,
+            head: vec![NONE; 1 << config.hash_bits],
+            chain: vec![NONE; input.len()],
+            config,
+            next_to_insert: 0,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes =
+            u32::from_le_bytes([self.input[pos], self.input[pos + 1], self.input[pos + 2], 0]);
+        (bytes.wrapping_mul(2654435761) >> (32 - self.config.hash_bits)) as usize
+    }
+
+    /// Add all positions before `pos` to the chains
+    fn insert_until(&mut self, pos: usize) {
+        while self.next_to_insert < pos {
+            let hash = self.hash(self.next_to_insert);
+            self.chain[self.next_to_insert] = self.head[hash];
+            self.head[hash] = self.next_to_insert as u32;
+            self.next_to_insert += 1;
+        }
+    }
+
+    /// Longest match for `pos`
+    fn find(&mut self, pos: usize) -> Option<Match> {
+        self.insert_until(pos);
+        let max_len = (self.input.len() - pos).min(MAX_MATCH);
+        let mut best: Option<Match> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..self.config.max_chain {
+            if candidate == NONE || pos - candidate as usize > M4_MAX_OFFSET {
+                break;
+            }
+            let start = candidate as usize;
+            let len = self.input[start..]
+                .iter()
+                .zip(&self.input[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            let m = Match { offset: pos - start, len };
+            if len >= MIN_MATCH && m.is_worth() && best.as_ref().map_or(true, |best| len > best.len)
+            {
+                best = Some(m);
+                if len >= self.config.nice_len || len == max_len {
+                    break;
+                }
+            }
+            candidate = self.chain[start];
+        }
+        best
+    }
+}
+
+struct Output {
+    bytes: Vec<u8>,
+    /// Byte of the last match holding the count of 1 to 3 literals following it
+    state: usize,
+}
+
+impl Output {
+    fn write_len(&mut self, mut len: usize) {
+        while len > 255 {
+            self.bytes.push(0);
+            len -= 255;
+        }
+        self.bytes.push(len as u8);
+    }
+
+    fn write_literals(&mut self, literals: &[u8]) {
+        let len = literals.len();
+        if len == 0 {
+            return;
+        }
+        if self.bytes.is_empty() && len <= 238 {
+            self.bytes.push(17 + len as u8);
+        } else if len <= 3 {
+            self.bytes[self.state] |= len as u8;
+        } else if len <= 18 {
+            self.bytes.push(len as u8 - 3);
+        } else {
+            self.bytes.push(0);
+            self.write_len(len - 18);
+        }
+        self.bytes.extend_from_slice(literals);
+    }
+
+    fn write_match(&mut self, m: &Match) {
+        if m.len <= M2_MAX_LEN && m.offset <= M2_MAX_OFFSET {
+            let distance = m.offset - 1;
+            self.bytes.push((((m.len - 1) << 5) | ((distance & 7) << 2)) as u8);
+            self.bytes.push((distance >> 3) as u8);
+        } else {
+            let (marker, max_len, distance) = if m.offset <= M3_MAX_OFFSET {
+                (M3_MARKER, 31, m.offset - 1)
+            } else {
+                let distance = m.offset - 0x4000;
+                (M4_MARKER | ((distance & 0x4000) >> 11) as u8, 7, distance & 0x3fff)
+            };
+            let len = m.len - 2;
+            if len <= max_len {
+                self.bytes.push(marker | len as u8);
+            } else {
+                self.bytes.push(marker);
+                self.write_len(len - max_len);
+            }
+            self.bytes.extend_from_slice(&((distance << 2) as u16).to_le_bytes());
+        }
+        self.state = self.bytes.len() - 2;
+    }
+}
+
+/// Compress `input` into a single LZO1X block with `algorithm`
+pub(crate) fn compress(input: &[u8], algorithm: LzoAlgorithm, compression_level: u32) -> Vec<u8> {
+    let mut chain = HashChain::new(input, Config::new(algorithm, compression_level));
+    let mut out =
+        Output { bytes: Vec::with_capacity(input.len() + input.len() / 16 + 64), state: 0 };
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let Some(mut best) = chain.find(pos) else {
+            pos += 1;
+            continue;
+        };
+
+        if chain.config.lazy {
+            while pos + 1 + MIN_MATCH <= input.len() {
+                match chain.find(pos + 1) {
+                    Some(next) if next.len > best.len => {
+                        pos += 1;
+                        best = next;
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        out.write_literals(&input[anchor..pos]);
+        out.write_match(&best);
+        pos += best.len;
+        anchor = pos;
+    }
+    out.write_literals(&input[anchor..]);
+
+    // end of stream: a M4 match with a distance of 0
+    out.bytes.extend_from_slice(&[M4_MARKER | 1, 0, 0]);
+    out.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut input = vec![];
+        for i in 0..0x4000_u32 {
+            input.extend_from_slice(format!("line {} of {}\n", i % 97, i % 13).as_bytes());
+        }
+        input.extend_from_slice(&[0; 3000]);
+        input.extend((0..0x10000_u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8));
+        input.extend_from_slice(&input[0x100..0x8000].to_vec());
+
+        let algorithms = [
+            (LzoAlgorithm::Lzo1x1, 0),
+            (LzoAlgorithm::Lzo1x1_11, 0),
+            (LzoAlgorithm::Lzo1x1_12, 0),
+            (LzoAlgorithm::Lzo1x1_15, 0),
+            (LzoAlgorithm::Lzo1x999, 1),
+            (LzoAlgorithm::Lzo1x999, 8),
+            (LzoAlgorithm::Lzo1x999, 9),
+        ];
+        for (algorithm, level) in algorithms {
+            for input in [&input[..], &[], &b"a"[..], &b"abcabcabcabcabc"[..]] {
+                let compressed = compress(input, algorithm, level);
+                let mut out = vec![0; input.len()];
+                let (out, error) = rust_lzo::LZOContext::decompress_to_slice(&compressed, &mut out);
+                assert!(error == rust_lzo::LZOError::OK);
+                assert_eq!(input, out);
+            }
+        }
+    }
+}
@@ -1,13 +1,16 @@
 use std::collections::VecDeque;
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
 
 use deku::prelude::*;
+use solana_nohash_hasher::IntMap;
 use tracing::trace;
 
+use crate::compressor::XzFilter;
 use crate::error::BackhandError;
-use crate::filesystem::writer::FilesystemCompressor;
+use crate::filesystem::writer::{CompressionExtra, FilesystemCompressor};
 use crate::kinds::Kind;
-use crate::squashfs::SuperBlock;
+use crate::squashfs::{Flags, SuperBlock};
 
 pub const METADATA_MAXSIZE: usize = 0x2000;
 
@@ -23,10 +26,18 @@ pub(crate) struct MetadataWriter {
     // All current bytes that are compressed or uncompressed
     pub(crate) final_bytes: Vec<(bool, Vec<u8>)>,
     pub kind: Kind,
+    /// Store all blocks uncompressed
+    pub(crate) uncompressed: bool,
 }
 
 impl MetadataWriter {
-    pub fn new(compressor: FilesystemCompressor, block_size: u32, kind: Kind) -> Self {
+    pub fn new(mut compressor: FilesystemCompressor, block_size: u32, kind: Kind) -> Self {
+        // only data blocks are tried with each of the selected xz filters
+        if let Some(CompressionExtra::Xz(xz)) = &mut compressor.extra {
+            if xz.filters.is_some() {
+                xz.filters = Some(XzFilter::new(0));
+            }
+        }
         Self {
             compressor,
             block_size,
@@ -34,6 +45,7 @@ impl MetadataWriter {
             uncompressed_bytes: VecDeque::new(),
             final_bytes: vec![],
             kind,
+            uncompressed: false,
         }
     }
 
@@ -52,11 +64,14 @@ impl MetadataWriter {
 
         trace!("time to compress");
         // "Write" the to the saved metablock
-        let compressed =
-            self.kind.inner.compressor.compress(uncompressed, self.compressor, self.block_size)?;
+        let compressed = if self.uncompressed {
+            vec![]
+        } else {
+            self.kind.inner.compressor.compress(uncompressed, self.compressor, self.block_size)?
+        };
 
         // Remove the data consumed, if the uncompressed data is smalled, use it.
-        let (compressed, metadata) = if compressed.len() > uncompressed_len {
+        let (compressed, metadata) = if self.uncompressed || compressed.len() > uncompressed_len {
             let uncompressed = self.uncompressed_bytes.drain(0..uncompressed_len).collect();
             (false, uncompressed)
         } else {
@@ -80,8 +95,9 @@ impl MetadataWriter {
         }
 
         // write all the metadata blocks
-        for (compressed, compressed_bytes) in &self.final_bytes {
+        for (compressed, compressed_bytes) in &mut self.final_bytes {
             trace!("len: {:02x?}", compressed_bytes.len());
+            self.kind.inner.transformer.to(compressed_bytes, None)?;
             // if uncompressed, set the highest bit of len
             let len =
                 compressed_bytes.len() as u16 | if *compressed { 0 } else { 1 << (u16::BITS - 1) };
@@ -112,6 +128,147 @@ impl Write for MetadataWriter {
     }
 }
 
+/// Bounded cache of uncompressed metadata blocks, evicting the least recently used block
+pub(crate) struct MetadataCache {
+    capacity: usize,
+    /// `<PositionInImage, (UncompressedBytes, PositionOfNextBlock)>`
+    blocks: IntMap<u64, (Arc<Vec<u8>>, u64)>,
+    /// Positions of `blocks`, least recently used first
+    order: VecDeque<u64>,
+}
+
+impl MetadataCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), blocks: IntMap::default(), order: VecDeque::new() }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(position) = self.order.pop_front() {
+                self.blocks.remove(&position);
+            }
+        }
+    }
+
+    /// Uncompressed bytes of the metadata block at `position` in the image, and the position of
+    /// the block following it
+    pub fn block<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        position: u64,
+        superblock: &SuperBlock,
+        kind: &Kind,
+    ) -> Result<(Arc<Vec<u8>>, u64), BackhandError> {
+        if let Some((bytes, next)) = self.blocks.get(&position) {
+            if let Some(index) = self.order.iter().position(|p| *p == position) {
+                self.order.remove(index);
+            }
+            self.order.push_back(position);
+            return Ok((bytes.clone(), *next));
+        }
+
+        trace!("metadata cache miss: {position:02x?}");
+        reader.seek(SeekFrom::Start(position))?;
+        let bytes = Arc::new(read_block(reader, superblock, kind)?);
+        let next = reader.stream_position()?;
+        self.blocks.insert(position, (bytes.clone(), next));
+        self.order.push_back(position);
+        self.evict();
+        Ok((bytes, next))
+    }
+}
+
+/// Reader of the uncompressed bytes of consecutive metadata blocks, starting at `offset` into
+/// the block at `position` in the image
+pub(crate) struct MetadataReader<'a, R> {
+    reader: &'a mut R,
+    cache: &'a mut MetadataCache,
+    superblock: &'a SuperBlock,
+    kind: &'a Kind,
+    /// Current block and the position of the block following it
+    bytes: Arc<Vec<u8>>,
+    next: u64,
+    /// Offset into `bytes`
+    offset: usize,
+    /// Bytes read since the start
+    read: u64,
+}
+
+impl<'a, R: Read + Seek> MetadataReader<'a, R> {
+    pub fn new(
+        reader: &'a mut R,
+        cache: &'a mut MetadataCache,
+        superblock: &'a SuperBlock,
+        kind: &'a Kind,
+        position: u64,
+        offset: usize,
+    ) -> Result<Self, BackhandError> {
+        let (bytes, next) = cache.block(reader, position, superblock, kind)?;
+        if offset > bytes.len() {
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        }
+        Ok(Self { reader, cache, superblock, kind, bytes, next, offset, read: 0 })
+    }
+}
+
+impl<R: Read + Seek> Read for MetadataReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offset == self.bytes.len() {
+            let (bytes, next) =
+                self.cache.block(self.reader, self.next, self.superblock, self.kind)?;
+            self.bytes = bytes;
+            self.next = next;
+            self.offset = 0;
+        }
+        let n = (&self.bytes[self.offset..]).read(buf)?;
+        self.offset += n;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for MetadataReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::Current(delta) => self.read.checked_add_signed(delta),
+            SeekFrom::End(_) => None,
+        };
+        let Some(target) = target else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+
+        if target < self.read {
+            // only seek back within the current block
+            let back = (self.read - target) as usize;
+            if back > self.offset {
+                return Err(io::ErrorKind::Unsupported.into());
+            }
+            self.offset -= back;
+            self.read = target;
+        } else {
+            let skip = target - self.read;
+            if io::copy(&mut self.by_ref().take(skip), &mut io::sink())? != skip {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.read)
+    }
+}
+
 pub fn read_block<R: Read + Seek>(
     reader: &mut R,
     superblock: &SuperBlock,
@@ -120,6 +277,11 @@ pub fn read_block<R: Read + Seek>(
     let mut deku_reader = Reader::new(reader);
     let metadata_len = u16::from_reader_with_ctx(&mut deku_reader, kind.inner.data_endian)?;
 
+    // images older than v4.0 with the check flag have a marker byte following the length
+    if superblock.version_major < 4 && superblock.flags & Flags::Unused as u16 != 0 {
+        reader.read_exact(&mut [0u8])?;
+    }
+
     let byte_len = len(metadata_len);
     tracing::trace!("len: 0x{:02x?}", byte_len);
     let mut buf = vec![0u8; byte_len as usize];
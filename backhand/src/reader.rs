@@ -13,8 +13,10 @@ use crate::fragment::Fragment;
 use crate::id::Id;
 use crate::inode::Inode;
 use crate::kinds::Kind;
+use crate::legacy::{self, LegacySuperBlock};
 use crate::metadata::METADATA_MAXSIZE;
 use crate::squashfs::{SuperBlock, NOT_SET};
+use crate::xattr::{XattrId, XattrIdTable, XattrTable};
 use crate::{fragment, metadata};
 
 /// Private struct containing logic to read the `Squashfs` section from a file
@@ -81,12 +83,13 @@ impl<T: BufReadSeek> SquashFsReader for T {}
 pub trait SquashFsReader: BufReadSeek + Sized {
     /// Cache Inode Table
     /// # Returns
-    /// - `(RootInode, HashMap<inode_number, Inode>)`
+    /// - `(RootInode, HashMap<inode_number, Inode>, HashMap<offset_from_seek, offset_from_bytes>)`
+    #[allow(clippy::type_complexity)]
     fn inodes(
         &mut self,
         superblock: &SuperBlock,
         kind: &Kind,
-    ) -> Result<(Inode, IntMap<u32, Inode>), BackhandError> {
+    ) -> Result<(Inode, IntMap<u32, Inode>, IntMap<u64, u64>), BackhandError> {
         let (map, bytes) = self.uncompress_metadatas(
             superblock.inode_table,
             superblock,
@@ -94,6 +97,26 @@ pub trait SquashFsReader: BufReadSeek + Sized {
             kind,
         )?;
 
+        let legacy = if kind.inner.version_major < 4 {
+            Some(LegacySuperBlock::read(self, kind)?)
+        } else {
+            None
+        };
+        let read_inode = |reader: &mut Reader<Cursor<Vec<u8>>>, position| match &legacy {
+            Some(legacy) => {
+                legacy::inode(reader, superblock, legacy, position, kind.inner.type_endian)
+            }
+            None => Inode::from_reader_with_ctx(
+                reader,
+                (
+                    superblock.bytes_used,
+                    superblock.block_size,
+                    superblock.block_log,
+                    kind.inner.type_endian,
+                ),
+            ),
+        };
+
         let mut inodes = IntMap::default();
         // Be nice the allocator, and only allocate a max of u16::MAX count of Indoes
         inodes.try_reserve(superblock.inode_count.min(u16::MAX as u32) as usize)?;
@@ -102,15 +125,8 @@ pub trait SquashFsReader: BufReadSeek + Sized {
         let mut cursor = Cursor::new(bytes);
         let mut reader = Reader::new(&mut cursor);
         while reader.bits_read != byte_len * 8 {
-            let inode = Inode::from_reader_with_ctx(
-                &mut reader,
-                (
-                    superblock.bytes_used,
-                    superblock.block_size,
-                    superblock.block_log,
-                    kind.inner.type_endian,
-                ),
-            )?;
+            let position = reader.bits_read as u64 / 8;
+            let inode = read_inode(&mut reader, position)?;
             inodes.insert(inode.header.inode_number, inode);
         }
 
@@ -126,21 +142,14 @@ pub trait SquashFsReader: BufReadSeek + Sized {
             return Err(BackhandError::CorruptedOrInvalidSquashfs);
         };
 
-        let mut cursor = reader.into_inner();
-        cursor.seek(SeekFrom::Start(root_offset + root_inode_offset as u64))?;
+        let position = root_offset + root_inode_offset as u64;
+        let cursor = reader.into_inner();
+        cursor.seek(SeekFrom::Start(position))?;
 
-        let mut reader = Reader::new(&mut cursor);
-        let root_inode = Inode::from_reader_with_ctx(
-            &mut reader,
-            (
-                superblock.bytes_used,
-                superblock.block_size,
-                superblock.block_log,
-                kind.inner.type_endian,
-            ),
-        )?;
+        let mut reader = Reader::new(cursor);
+        let root_inode = read_inode(&mut reader, position)?;
 
-        Ok((root_inode, inodes))
+        Ok((root_inode, inodes, map))
     }
 
     /// Parse required number of `Metadata`s uncompressed blocks required for `Dir`s
@@ -176,6 +185,9 @@ pub trait SquashFsReader: BufReadSeek + Sized {
         if superblock.frag_count == 0 || superblock.frag_table == NOT_SET {
             return Ok(None);
         }
+        if superblock.version_major < 3 {
+            return legacy::fragments(self, superblock, kind).map(Some);
+        }
         let (ptr, table) = self.lookup_table::<Fragment>(
             superblock,
             superblock.frag_table,
@@ -194,8 +206,8 @@ pub trait SquashFsReader: BufReadSeek + Sized {
     ) -> Result<Option<(u64, Vec<Export>)>, BackhandError> {
         if superblock.nfs_export_table_exists() && superblock.export_table != NOT_SET {
             let ptr = superblock.export_table;
-            let count = (superblock.inode_count as f32 / 1024_f32).ceil() as u64;
-            let (ptr, table) = self.lookup_table::<Export>(superblock, ptr, count, kind)?;
+            let size = u64::from(superblock.inode_count) * Export::SIZE as u64;
+            let (ptr, table) = self.lookup_table::<Export>(superblock, ptr, size, kind)?;
             Ok(Some((ptr, table)))
         } else {
             Ok(None)
@@ -208,12 +220,53 @@ pub trait SquashFsReader: BufReadSeek + Sized {
         superblock: &SuperBlock,
         kind: &Kind,
     ) -> Result<(u64, Vec<Id>), BackhandError> {
+        if kind.inner.version_major < 4 {
+            return LegacySuperBlock::read(self, kind)?.ids(self, kind);
+        }
         let ptr = superblock.id_table;
         let count = superblock.id_count as u64;
         let (ptr, table) = self.lookup_table::<Id>(superblock, ptr, count, kind)?;
         Ok((ptr, table))
     }
 
+    /// Parse and Cache Xattr Table
+    fn xattrs(
+        &mut self,
+        superblock: &SuperBlock,
+        kind: &Kind,
+    ) -> Result<Option<XattrTable>, BackhandError> {
+        if superblock.no_xattrs_in_archive() || superblock.xattr_table == NOT_SET {
+            return Ok(None);
+        }
+
+        // read the table header and the pointer to the first id metadata block
+        trace!("seek: {:02x?}", superblock.xattr_table);
+        self.seek(SeekFrom::Start(superblock.xattr_table))?;
+        let buf: &mut [u8] = &mut [0u8; XattrIdTable::SIZE + 8];
+        self.read_exact(buf)?;
+
+        kind.inner.transformer.from(buf, None)?;
+
+        let mut cursor = Cursor::new(buf);
+        let mut deku_reader = Reader::new(&mut cursor);
+        let table = XattrIdTable::from_reader_with_ctx(&mut deku_reader, kind.inner.type_endian)?;
+        trace!("{:02x?}", table);
+        if table.xattr_ids == 0 {
+            return Ok(None);
+        }
+        let ptr = u64::from_reader_with_ctx(&mut deku_reader, kind.inner.type_endian)?;
+
+        let size = u64::from(table.xattr_ids) * XattrId::SIZE as u64;
+        let block_count = (size as f32 / METADATA_MAXSIZE as f32).ceil() as u64;
+        let mut ids = self.metadata_with_count::<XattrId>(superblock, ptr, block_count, kind)?;
+        ids.truncate(table.xattr_ids as usize);
+
+        // key/value pairs are stored between the start of the table and the id metadata blocks
+        let blocks = self.uncompress_metadatas(table.xattr_table_start, superblock, ptr, kind)?;
+
+        Ok(Some(XattrTable { ids, blocks }))
+    }
+
     /// Parse Lookup Table
     fn lookup_table<T>(
         &mut self,
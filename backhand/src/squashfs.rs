@@ -1,27 +1,31 @@
 //! Read from on-disk image
 
+use std::borrow::Cow;
 use std::ffi::OsString;
 use std::io::{Cursor, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::sync::Mutex;
-use std::sync::{Arc, RwLock};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use deku::prelude::*;
 use solana_nohash_hasher::IntMap;
 use tracing::{error, info, trace};
 
 use crate::compressor::{CompressionOptions, Compressor};
-use crate::dir::Dir;
+use crate::dir::{self, Dir, DirEntry};
 use crate::error::BackhandError;
 use crate::filesystem::node::{InnerNode, Nodes};
 use crate::fragment::Fragment;
 use crate::inode::{Inode, InodeId, InodeInner};
-use crate::kinds::{Kind, LE_V4_0};
+use crate::kinds::{Confidence, Kind, LE_V4_0};
+use crate::legacy::{self, LegacySuperBlock};
+use crate::metadata::METADATA_MAXSIZE;
 use crate::reader::{BufReadSeek, SquashFsReader, SquashfsReaderWithOffset};
-use crate::unix_string::OsStringExt;
+use crate::unix_string::{OsStrExt, OsStringExt};
+use crate::xattr::XattrTable;
 use crate::{
     metadata, Export, FilesystemReader, Id, Node, NodeHeader, SquashfsBlockDevice,
-    SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsSymlink,
+    SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsHardlink, SquashfsSymlink,
+    Xattr,
 };
 
 /// 128KiB
@@ -78,7 +82,6 @@ pub struct SuperBlock {
     /// Because SquashFS archives must be padded to a multiple of the underlying device block size, this can be less than the actual file size.
     pub bytes_used: u64,
     pub id_table: u64,
-    //TODO: add read into Squashfs
     pub xattr_table: u64,
     pub inode_table: u64,
     pub dir_table: u64,
@@ -165,6 +168,74 @@ impl SuperBlock {
             export_table: NOT_SET,
         }
     }
+
+    /// Check that `block_size` is a valid power of two matching `block_log`, returning the
+    /// invalid field
+    pub(crate) fn check_block_size(&self) -> Result<(), &'static str> {
+        let block_size = self.block_size;
+        let power_of_two = block_size != 0 && (block_size & (block_size - 1)) == 0;
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || !power_of_two {
+            return Err("block_size");
+        }
+        if (block_size as f32).log2() != self.block_log as f32 {
+            return Err("block_log");
+        }
+        Ok(())
+    }
+
+    /// Check that the image and its tables fit in `total_length`, returning the invalid field
+    pub(crate) fn check_tables(&self, total_length: u64) -> Result<(), &'static str> {
+        if self.bytes_used > total_length {
+            return Err("bytes_used");
+        }
+
+        // check required fields
+        let required = [
+            ("id_table", self.id_table),
+            ("inode_table", self.inode_table),
+            ("dir_table", self.dir_table),
+        ];
+        if let Some((field, _)) = required.iter().find(|(_, ptr)| *ptr > total_length) {
+            return Err(field);
+        }
+
+        // check optional fields
+        let optional = [
+            ("xattr_table", self.xattr_table),
+            ("frag_table", self.frag_table),
+            ("export_table", self.export_table),
+        ];
+        if let Some((field, _)) =
+            optional.iter().find(|(_, ptr)| *ptr != NOT_SET && *ptr > total_length)
+        {
+            return Err(field);
+        }
+        Ok(())
+    }
+}
+
+/// Parse the [`SuperBlock`] of `kind` at the current offset of `reader`, converting the
+/// superblock of images older than v4.0
+pub(crate) fn read_superblock<R: BufReadSeek>(
+    reader: &mut R,
+    kind: &Kind,
+) -> Result<SuperBlock, BackhandError> {
+    let mut container = Reader::new(reader);
+    let superblock = if kind.inner.version_major < 4 {
+        LegacySuperBlock::from_reader_with_ctx(&mut container, kind.inner.type_endian)?
+            .to_superblock(kind)?
+    } else {
+        SuperBlock::from_reader_with_ctx(
+            &mut container,
+            (
+                kind.inner.magic,
+                kind.inner.version_major,
+                kind.inner.version_minor,
+                kind.inner.type_endian,
+            ),
+        )?
+    };
+    Ok(superblock)
 }
 
 #[rustfmt::skip]
@@ -203,6 +274,9 @@ pub struct Squashfs<'b> {
     pub inodes: IntMap<u32, Inode>,
     /// Root Inode
     pub root_inode: Inode,
+    /// Offset of each Inode Table block `<OffsetFromImage, OffsetInData>`, numbering the inodes of
+    /// images older than v3.0
+    pub(crate) inode_blocks: IntMap<u64, u64>,
     /// Bytes containing Directory Table `(<OffsetFromImage, OffsetInData>, Data)`
     pub dir_blocks: (IntMap<u64, u64>, Vec<u8>),
     /// Fragments Lookup Table Cache
@@ -211,6 +285,8 @@ pub struct Squashfs<'b> {
     pub export: Option<Vec<Export>>,
     /// Id Lookup Table Cache
     pub id: Vec<Id>,
+    /// Xattr Table Cache
+    pub(crate) xattr_table: Option<XattrTable>,
     //file reader
     file: Box<dyn BufReadSeek + 'b>,
 }
@@ -224,27 +300,12 @@ impl<'b> Squashfs<'b> {
         reader: &mut Box<dyn BufReadSeek + 'b>,
         kind: &Kind,
     ) -> Result<(SuperBlock, Option<CompressionOptions>), BackhandError> {
-        // Parse SuperBlock
-        let mut container = Reader::new(reader);
-        let superblock = SuperBlock::from_reader_with_ctx(
-            &mut container,
-            (
-                kind.inner.magic,
-                kind.inner.version_major,
-                kind.inner.version_minor,
-                kind.inner.type_endian,
-            ),
-        )?;
-
-        let block_size = superblock.block_size;
-        let power_of_two = block_size != 0 && (block_size & (block_size - 1)) == 0;
-        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || !power_of_two {
-            error!("block_size({:#02x}) invalid", superblock.block_size);
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
-        }
-
-        if (superblock.block_size as f32).log2() != superblock.block_log as f32 {
-            error!("block size.log2() != block_log");
+        let superblock = read_superblock(reader, kind)?;
+        if let Err(field) = superblock.check_block_size() {
+            error!(
+                "{field} invalid, block_size: {:#02x}, block_log: {}",
+                superblock.block_size, superblock.block_log
+            );
             return Err(BackhandError::CorruptedOrInvalidSquashfs);
         }
 
@@ -314,52 +375,64 @@ impl<'b> Squashfs<'b> {
         Self::inner_from_reader_with_offset_and_kind(reader, kind)
     }
 
-    fn inner_from_reader_with_offset_and_kind(
-        mut reader: Box<dyn BufReadSeek + 'b>,
-        kind: Kind,
-    ) -> Result<Self, BackhandError> {
+    /// Read and check the Superblock and Compression Options at the start of `reader`
+    ///
+    /// On failure, the kinds close to the image are logged, and the error is
+    /// [`BackhandError::OtherKind`] if the image is valid as another kind.
+    pub(crate) fn checked_superblock(
+        reader: &mut Box<dyn BufReadSeek + 'b>,
+        kind: &Kind,
+    ) -> Result<(SuperBlock, Option<CompressionOptions>), BackhandError> {
         let (superblock, compression_options) =
-            Self::superblock_and_compression_options(&mut reader, &kind)?;
+            match Self::superblock_and_compression_options(reader, kind) {
+                Ok(superblock) => superblock,
+                Err(e) => return Err(Self::with_candidates(reader, e)),
+            };
 
         // Check if legal image
         let total_length = reader.seek(SeekFrom::End(0))?;
         reader.rewind()?;
-        if superblock.bytes_used > total_length {
-            error!("corrupted or invalid bytes_used");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        if let Err(field) = superblock.check_tables(total_length) {
+            error!("corrupted or invalid {field}");
+            let e = BackhandError::CorruptedOrInvalidSquashfs;
+            return Err(Self::with_candidates(reader, e));
         }
 
-        // check required fields
-        if superblock.id_table > total_length {
-            error!("corrupted or invalid xattr_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
-        }
-        if superblock.inode_table > total_length {
-            error!("corrupted or invalid inode_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
-        }
-        if superblock.dir_table > total_length {
-            error!("corrupted or invalid dir_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
-        }
+        Ok((superblock, compression_options))
+    }
 
-        // check optional fields
-        if superblock.xattr_table != NOT_SET && superblock.xattr_table > total_length {
-            error!("corrupted or invalid frag_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
-        }
-        if superblock.frag_table != NOT_SET && superblock.frag_table > total_length {
-            error!("corrupted or invalid frag_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+    /// Hint at the kinds that are close to the image in `reader`, adding the kinds it is valid
+    /// as to `e`
+    fn with_candidates(reader: &mut Box<dyn BufReadSeek + 'b>, e: BackhandError) -> BackhandError {
+        let Ok(candidates) = Kind::probe(reader, 0) else {
+            return e;
+        };
+        for c in candidates.iter().filter(|c| c.confidence > Confidence::Magic) {
+            match c.target {
+                Some(target) => {
+                    error!("image could be kind {target} ({:?}): {}", c.confidence, c.reason)
+                }
+                None => error!("image could be {:?} ({:?}): {}", c.kind, c.confidence, c.reason),
+            }
         }
-        if superblock.export_table != NOT_SET && superblock.export_table > total_length {
-            error!("corrupted or invalid export_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+
+        let candidates: Vec<_> =
+            candidates.into_iter().filter(|c| c.confidence == Confidence::Valid).collect();
+        if candidates.is_empty() {
+            return e;
         }
+        BackhandError::OtherKind { source: Box::new(e), candidates }
+    }
+
+    fn inner_from_reader_with_offset_and_kind(
+        mut reader: Box<dyn BufReadSeek + 'b>,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let (superblock, compression_options) = Self::checked_superblock(&mut reader, &kind)?;
 
         // Read all fields from filesystem to make a Squashfs
         info!("Reading Inodes");
-        let (root_inode, inodes) = reader.inodes(&superblock, &kind)?;
+        let (root_inode, inodes, inode_blocks) = reader.inodes(&superblock, &kind)?;
 
         info!("Reading Fragments");
         let fragments = reader.fragments(&superblock, &kind)?;
@@ -376,6 +449,9 @@ impl<'b> Squashfs<'b> {
         let id_ptr = id.0;
         let id_table = id.1;
 
+        info!("Reading Xattrs");
+        let xattr_table = reader.xattrs(&superblock, &kind)?;
+
         let last_dir_position = if let Some(fragment_ptr) = fragment_ptr {
             trace!("using fragment for end of dir");
             fragment_ptr
@@ -401,10 +477,12 @@ impl<'b> Squashfs<'b> {
             compression_options,
             inodes,
             root_inode,
+            inode_blocks,
             dir_blocks,
             fragments: fragment_table,
             export: export_table,
             id: id_table,
+            xattr_table,
             file: reader,
         };
 
@@ -481,131 +559,137 @@ impl<'b> Squashfs<'b> {
         // Read until we fail to turn bytes into `T`
         let mut cursor = Cursor::new(bytes);
         let mut container = Reader::new(&mut cursor);
-        while let Ok(t) = Dir::from_reader_with_ctx(&mut container, self.kind.inner.type_endian) {
-            dirs.push(t);
+        let endian = self.kind.inner.type_endian;
+        loop {
+            let dir = if self.kind.inner.version_major < 4 {
+                let version_major = self.superblock.version_major;
+                legacy::dir(&mut container, version_major, &self.inode_blocks, endian)
+            } else {
+                Dir::from_reader_with_ctx(&mut container, endian)
+            };
+            let Ok(dir) = dir else { break };
+            dirs.push(dir);
         }
 
         trace!("finish");
         Ok(Some(dirs))
     }
 
-    fn extract_dir(
+    /// Find the inode number of entry `name` in directory `dir_inode`
+    ///
+    /// If the directory has an index, only the listing from the last indexed header before `name`
+    /// is read.
+    pub(crate) fn dir_lookup(
         &self,
-        fullpath: &mut PathBuf,
-        root: &mut Nodes<SquashfsFileReader>,
         dir_inode: &Inode,
-        id_table: &[Id],
-    ) -> Result<(), BackhandError> {
-        let dirs = match &dir_inode.inner {
-            InodeInner::BasicDirectory(basic_dir) => {
-                trace!("BASIC_DIR inodes: {:02x?}", basic_dir);
-                self.dir_from_index(
-                    u64::from(basic_dir.block_index),
-                    u32::from(basic_dir.file_size),
-                    basic_dir.block_offset as usize,
-                )?
-            }
-            InodeInner::ExtendedDirectory(ext_dir) => {
-                trace!("EXT_DIR: {:#02x?}", ext_dir);
-                self.dir_from_index(
-                    u64::from(ext_dir.block_index),
-                    ext_dir.file_size,
-                    ext_dir.block_offset as usize,
-                )?
-            }
-            _ => return Err(BackhandError::UnexpectedInode(dir_inode.inner.clone())),
+        name: &[u8],
+    ) -> Result<Option<u32>, BackhandError> {
+        let (block_index, file_size, block_offset) = dir_location(dir_inode, Some(name))?;
+        let Some(dirs) = self.dir_from_index(block_index, file_size, block_offset)? else {
+            return Ok(None);
         };
-        if let Some(dirs) = dirs {
-            for d in &dirs {
-                trace!("extracting entry: {:#?}", d.dir_entries);
-                for entry in &d.dir_entries {
-                    let Ok(inode_key) = (d.inode_num as i32 + entry.inode_offset as i32).try_into()
-                    else {
-                        return Err(BackhandError::CorruptedOrInvalidSquashfs);
-                    };
-                    let Some(found_inode) = &self.inodes.get(&inode_key) else {
-                        return Err(BackhandError::CorruptedOrInvalidSquashfs);
-                    };
-                    let header = found_inode.header;
-                    fullpath.push(entry.name()?);
-
-                    let inner: InnerNode<SquashfsFileReader> = match entry.t {
-                        // BasicDirectory, ExtendedDirectory
-                        InodeId::BasicDirectory | InodeId::ExtendedDirectory => {
-                            // its a dir, extract all children inodes
-                            if *found_inode == dir_inode {
-                                error!("self referential dir to already read inode");
-                                return Err(BackhandError::UnexpectedInode(
-                                    dir_inode.inner.clone(),
-                                ));
-                            }
-                            self.extract_dir(fullpath, root, found_inode, &self.id)?;
-                            InnerNode::Dir(SquashfsDir::default())
-                        }
-                        // BasicFile
-                        InodeId::BasicFile => {
-                            let inner = match &found_inode.inner {
-                                InodeInner::BasicFile(file) => {
-                                    SquashfsFileReader::Basic(file.clone())
-                                }
-                                InodeInner::ExtendedFile(file) => {
-                                    SquashfsFileReader::Extended(file.clone())
-                                }
-                                _ => {
-                                    return Err(BackhandError::UnexpectedInode(
-                                        found_inode.inner.clone(),
-                                    ))
-                                }
-                            };
-                            InnerNode::File(inner)
-                        }
-                        // Basic Symlink
-                        InodeId::BasicSymlink => {
-                            let link = self.symlink_target_path(found_inode)?;
-                            InnerNode::Symlink(SquashfsSymlink { link })
-                        }
-                        // Basic CharacterDevice
-                        InodeId::BasicCharacterDevice => {
-                            let device_number = Self::char_device_number(found_inode)?;
-                            InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number })
-                        }
-                        // Basic CharacterDevice
-                        InodeId::BasicBlockDevice => {
-                            let device_number = Self::block_device_number(found_inode)?;
-                            InnerNode::BlockDevice(SquashfsBlockDevice { device_number })
-                        }
-                        InodeId::BasicNamedPipe => InnerNode::NamedPipe,
-                        InodeId::BasicSocket => InnerNode::Socket,
-                        InodeId::ExtendedFile => {
-                            return Err(BackhandError::UnsupportedInode(found_inode.inner.clone()))
-                        }
-                    };
-                    let node = Node::new(
-                        fullpath.clone(),
-                        NodeHeader::from_inode(header, id_table)?,
-                        inner,
-                    );
-                    root.nodes.push(node);
-                    fullpath.pop();
-                }
+        match dir::find(&dirs, name) {
+            Some((d, entry)) => Ok(Some(d.inode_number(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Inode at `path`, found by walking the directories from the root inode
+    ///
+    /// # Returns
+    /// `Ok(None)` if `path` doesn't exist
+    pub fn inode_from_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<&Inode>, BackhandError> {
+        let mut inode = &self.root_inode;
+        for component in path.as_ref().components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => name,
+                _ => return Err(BackhandError::InvalidFilePath),
+            };
+            if !matches!(inode.id, InodeId::BasicDirectory | InodeId::ExtendedDirectory) {
+                return Ok(None);
             }
+            let Some(inode_key) = self.dir_lookup(inode, name.as_bytes())? else {
+                return Ok(None);
+            };
+            let Some(found_inode) = self.inodes.get(&inode_key) else {
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            };
+            inode = found_inode;
         }
-        //TODO: todo!("verify all the paths are valid");
-        Ok(())
+        Ok(Some(inode))
     }
 
-    /// Symlink target path
+    /// Extended attributes of `inode`
     ///
     /// # Returns
-    /// `Ok(target_path)`
-    fn symlink_target_path(&self, inode: &Inode) -> Result<PathBuf, BackhandError> {
-        if let InodeInner::BasicSymlink(basic_sym) = &inode.inner {
-            let path = OsString::from_vec(basic_sym.target_path.clone());
-            return Ok(PathBuf::from(path));
+    /// `Ok(xattrs)`, empty if the inode has none
+    pub fn xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, BackhandError> {
+        match &self.xattr_table {
+            Some(table) => table.lookup(inode.xattr_index(), &self.kind),
+            None => Ok(vec![]),
         }
+    }
 
-        error!("symlink not found");
-        Err(BackhandError::FileNotFound)
+    /// Node contents of `inode`, listed as type `t` in its directory
+    ///
+    /// Directories are returned without reading their entries.
+    pub(crate) fn inner_node(
+        t: InodeId,
+        inode: &Inode,
+    ) -> Result<InnerNode<SquashfsFileReader>, BackhandError> {
+        let inner = match t {
+            // BasicDirectory, ExtendedDirectory
+            InodeId::BasicDirectory | InodeId::ExtendedDirectory => {
+                InnerNode::Dir(SquashfsDir::default())
+            }
+            // BasicFile, ExtendedFile
+            InodeId::BasicFile | InodeId::ExtendedFile => {
+                let inner = match &inode.inner {
+                    InodeInner::BasicFile(file) => SquashfsFileReader::Basic(file.clone()),
+                    InodeInner::ExtendedFile(file) => SquashfsFileReader::Extended(file.clone()),
+                    _ => return Err(BackhandError::UnexpectedInode(inode.inner.clone())),
+                };
+                InnerNode::File(inner)
+            }
+            // BasicSymlink, ExtendedSymlink
+            InodeId::BasicSymlink | InodeId::ExtendedSymlink => {
+                let link = Self::symlink_target_path(inode)?;
+                InnerNode::Symlink(SquashfsSymlink { link })
+            }
+            // BasicCharacterDevice, ExtendedCharacterDevice
+            InodeId::BasicCharacterDevice | InodeId::ExtendedCharacterDevice => {
+                let device_number = Self::char_device_number(inode)?;
+                InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number })
+            }
+            // BasicBlockDevice, ExtendedBlockDevice
+            InodeId::BasicBlockDevice | InodeId::ExtendedBlockDevice => {
+                let device_number = Self::block_device_number(inode)?;
+                InnerNode::BlockDevice(SquashfsBlockDevice { device_number })
+            }
+            InodeId::BasicNamedPipe | InodeId::ExtendedNamedPipe => InnerNode::NamedPipe,
+            InodeId::BasicSocket | InodeId::ExtendedSocket => InnerNode::Socket,
+        };
+        Ok(inner)
+    }
+
+    /// Symlink target path
+    ///
+    /// # Returns
+    /// `Ok(target_path)`
+    fn symlink_target_path(inode: &Inode) -> Result<PathBuf, BackhandError> {
+        let target_path = match &inode.inner {
+            InodeInner::BasicSymlink(basic_sym) => &basic_sym.target_path,
+            InodeInner::ExtendedSymlink(ext_sym) => &ext_sym.target_path,
+            _ => {
+                error!("symlink not found");
+                return Err(BackhandError::FileNotFound);
+            }
+        };
+        Ok(PathBuf::from(OsString::from_vec(target_path.clone())))
     }
 
     /// Char Device Number
@@ -613,8 +697,10 @@ impl<'b> Squashfs<'b> {
     /// # Returns
     /// `Ok(dev_num)`
     fn char_device_number(inode: &Inode) -> Result<u32, BackhandError> {
-        if let InodeInner::BasicCharacterDevice(spc_file) = &inode.inner {
-            return Ok(spc_file.device_number);
+        match &inode.inner {
+            InodeInner::BasicCharacterDevice(spc_file) => return Ok(spc_file.device_number),
+            InodeInner::ExtendedCharacterDevice(spc_file) => return Ok(spc_file.device_number),
+            _ => (),
         }
 
         error!("char dev not found");
@@ -626,8 +712,10 @@ impl<'b> Squashfs<'b> {
     /// # Returns
     /// `Ok(dev_num)`
     fn block_device_number(inode: &Inode) -> Result<u32, BackhandError> {
-        if let InodeInner::BasicBlockDevice(spc_file) = &inode.inner {
-            return Ok(spc_file.device_number);
+        match &inode.inner {
+            InodeInner::BasicBlockDevice(spc_file) => return Ok(spc_file.device_number),
+            InodeInner::ExtendedBlockDevice(spc_file) => return Ok(spc_file.device_number),
+            _ => (),
         }
 
         error!("block dev not found");
@@ -637,26 +725,304 @@ impl<'b> Squashfs<'b> {
     /// Convert into [`FilesystemReader`] by extracting all file bytes and converting into a filesystem
     /// like structure in-memory
     pub fn into_filesystem_reader(self) -> Result<FilesystemReader<'b>, BackhandError> {
+        let root = self.tree()?;
+        let filesystem = FilesystemReader::new(
+            self.kind,
+            &self.superblock,
+            self.compression_options,
+            self.id,
+            self.fragments,
+            root,
+            self.file,
+        );
+        Ok(filesystem)
+    }
+}
+
+impl DirTree for Squashfs<'_> {
+    fn id_table(&self) -> &[Id] {
+        &self.id
+    }
+
+    fn root(&self) -> Result<Cow<'_, Inode>, BackhandError> {
+        Ok(Cow::Borrowed(&self.root_inode))
+    }
+
+    fn listing(&self, dir_inode: &Inode) -> Result<Vec<Dir>, BackhandError> {
+        let (block_index, file_size, block_offset) = dir_location(dir_inode, None)?;
+        let dirs = self.dir_from_index(block_index, file_size, block_offset)?;
+        Ok(dirs.unwrap_or_default())
+    }
+
+    fn entry_inode(&self, dir: &Dir, entry: &DirEntry) -> Result<Cow<'_, Inode>, BackhandError> {
+        match self.inodes.get(&dir.inode_number(entry)?) {
+            Some(inode) => Ok(Cow::Borrowed(inode)),
+            None => Err(BackhandError::CorruptedOrInvalidSquashfs),
+        }
+    }
+
+    fn inode_xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, BackhandError> {
+        self.xattrs(inode)
+    }
+}
+
+/// Directories and inodes of an image, read to build the nodes of a [`FilesystemReader`]
+pub(crate) trait DirTree {
+    /// Id Table
+    fn id_table(&self) -> &[Id];
+
+    /// Root Inode
+    fn root(&self) -> Result<Cow<'_, Inode>, BackhandError>;
+
+    /// All directory headers of the listing of `dir_inode`
+    fn listing(&self, dir_inode: &Inode) -> Result<Vec<Dir>, BackhandError>;
+
+    /// Inode of `entry` of the directory header `dir`
+    fn entry_inode(&self, dir: &Dir, entry: &DirEntry) -> Result<Cow<'_, Inode>, BackhandError>;
+
+    /// Extended attributes of `inode`
+    fn inode_xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, BackhandError>;
+
+    /// Node of `inode` at `fullpath`, listed as type `t` in its directory
+    fn node(
+        &self,
+        fullpath: PathBuf,
+        t: InodeId,
+        inode: &Inode,
+    ) -> Result<Node<SquashfsFileReader>, BackhandError> {
+        let header = NodeHeader::from_inode(inode.header, self.id_table())?;
+        let mut node = Node::new(fullpath, header, Squashfs::inner_node(t, inode)?);
+        node.xattrs = self.inode_xattrs(inode)?;
+        Ok(node)
+    }
+
+    /// Nodes of all inodes from the root inode, sorted by path
+    fn tree(&self) -> Result<Nodes<SquashfsFileReader>, BackhandError> {
         info!("creating fs tree");
-        let mut root = Nodes::new_root(NodeHeader::from_inode(self.root_inode.header, &self.id)?);
-        self.extract_dir(&mut PathBuf::from("/"), &mut root, &self.root_inode, &self.id)?;
+        let root_inode = self.root()?;
+        let mut root = Nodes::new_root(NodeHeader::from_inode(root_inode.header, self.id_table())?);
+        root.root_mut().xattrs = self.inode_xattrs(&root_inode)?;
+        self.extract_dir(&mut PathBuf::from("/"), &mut root, &root_inode, &mut IntMap::default())?;
         root.nodes.sort();
-
         info!("created fs tree");
-        let filesystem = FilesystemReader {
-            kind: self.kind,
-            block_size: self.superblock.block_size,
-            block_log: self.superblock.block_log,
-            compressor: self.superblock.compressor,
-            compression_options: self.compression_options,
-            mod_time: self.superblock.mod_time,
-            id_table: self.id,
-            fragments: self.fragments,
-            root,
-            reader: Mutex::new(Box::new(self.file)),
-            cache: RwLock::new(Cache::default()),
-            no_duplicate_files: self.superblock.data_has_been_deduplicated(),
+        Ok(root)
+    }
+
+    /// Add the nodes of all inodes below `dir_inode` to `root`
+    ///
+    /// `links` holds the path of the first entry of each inode, later entries of it are read as
+    /// [`InnerNode::Hardlink`]
+    fn extract_dir(
+        &self,
+        fullpath: &mut PathBuf,
+        root: &mut Nodes<SquashfsFileReader>,
+        dir_inode: &Inode,
+        links: &mut IntMap<u32, PathBuf>,
+    ) -> Result<(), BackhandError> {
+        trace!("DIR: {:#02x?}", dir_inode);
+        for d in &self.listing(dir_inode)? {
+            trace!("extracting entry: {:#?}", d.dir_entries);
+            for entry in &d.dir_entries {
+                let inode_key = d.inode_number(entry)?;
+                let found_inode = self.entry_inode(d, entry)?;
+                fullpath.push(entry.name()?);
+
+                // another entry already points to this inode, only directories can't be linked
+                let is_dir =
+                    matches!(entry.t, InodeId::BasicDirectory | InodeId::ExtendedDirectory);
+                if !is_dir {
+                    if let Some(link) = links.get(&inode_key) {
+                        let mut node = Node::new(
+                            fullpath.clone(),
+                            NodeHeader::from_inode(found_inode.header, self.id_table())?,
+                            InnerNode::Hardlink(SquashfsHardlink { link: link.clone() }),
+                        );
+                        node.xattrs = self.inode_xattrs(&found_inode)?;
+                        root.nodes.push(node);
+                        fullpath.pop();
+                        continue;
+                    }
+                    links.insert(inode_key, fullpath.clone());
+                }
+
+                // its a dir, extract all children inodes
+                if is_dir {
+                    if *found_inode == *dir_inode {
+                        error!("self referential dir to already read inode");
+                        return Err(BackhandError::UnexpectedInode(dir_inode.inner.clone()));
+                    }
+                    self.extract_dir(fullpath, root, &found_inode, links)?;
+                }
+                root.nodes.push(self.node(fullpath.clone(), entry.t, &found_inode)?);
+                fullpath.pop();
+            }
+        }
+        //TODO: todo!("verify all the paths are valid");
+        Ok(())
+    }
+}
+
+/// Location `(block_index, file_size, block_offset)` of the listing of directory `dir_inode` in the
+/// directory table
+///
+/// With `name`, the location of the last indexed header starting before `name` is returned
+/// instead, if the directory has an index.
+pub(crate) fn dir_location(
+    dir_inode: &Inode,
+    name: Option<&[u8]>,
+) -> Result<(u64, u32, usize), BackhandError> {
+    let (block_index, file_size, block_offset, dir_index) = match &dir_inode.inner {
+        InodeInner::BasicDirectory(basic_dir) => (
+            basic_dir.block_index,
+            u32::from(basic_dir.file_size),
+            basic_dir.block_offset as usize,
+            &[][..],
+        ),
+        InodeInner::ExtendedDirectory(ext_dir) => (
+            ext_dir.block_index,
+            ext_dir.file_size,
+            ext_dir.block_offset as usize,
+            ext_dir.dir_index.as_slice(),
+        ),
+        _ => return Err(BackhandError::UnexpectedInode(dir_inode.inner.clone())),
+    };
+    let Some(name) = name else {
+        return Ok((u64::from(block_index), file_size, block_offset));
+    };
+
+    // skip to the last header starting before `name`
+    let mut block_index = block_index;
+    let mut skipped = 0;
+    for index in dir_index.iter().take_while(|index| index.name.as_slice() <= name) {
+        block_index = index.start;
+        skipped = index.index;
+    }
+    let block_offset = (block_offset + skipped as usize) % METADATA_MAXSIZE;
+    let Some(file_size) = file_size.checked_sub(skipped) else {
+        return Err(BackhandError::CorruptedOrInvalidSquashfs);
+    };
+    Ok((u64::from(block_index), file_size, block_offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FilesystemWriter, NodeHeader, Xattr};
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_dir_index_lookup() {
+        let header = NodeHeader::default();
+        let mut fs = FilesystemWriter::default();
+        fs.push_dir("big", header).unwrap();
+        let names: Vec<_> = (0..600).map(|i| format!("a_rather_long_file_name_{i:05}")).collect();
+        for name in &names {
+            fs.push_file(Cursor::new(name.clone()), format!("big/{name}"), header).unwrap();
+        }
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+
+        let squashfs = Squashfs::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+        let big = squashfs.inode_from_path("/big").unwrap().unwrap();
+        let InodeInner::ExtendedDirectory(ext_dir) = &big.inner else {
+            panic!("expected extended directory");
         };
-        Ok(filesystem)
+        assert!(ext_dir.index_count > 1);
+
+        for name in &names {
+            let inode = squashfs.inode_from_path(format!("/big/{name}")).unwrap().unwrap();
+            assert_eq!(inode.id, InodeId::BasicFile);
+        }
+        assert!(squashfs.inode_from_path("/big/a_rather_long_file_name_1").unwrap().is_none());
+        assert!(squashfs.inode_from_path("/big/zzz").unwrap().is_none());
+        assert!(squashfs.inode_from_path("/missing/file").unwrap().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_export_table() {
+        let header = NodeHeader::default();
+        let mut fs = FilesystemWriter::default();
+        fs.set_export_table(true);
+        fs.push_dir("dir", header).unwrap();
+        fs.push_file(Cursor::new("a"), "dir/a", header).unwrap();
+        fs.push_hardlink("dir/a", "b").unwrap();
+        fs.push_symlink("dir/a", "c", header).unwrap();
+        // more than one metadata block of exports
+        for i in 0..1100 {
+            fs.push_file(Cursor::new(""), format!("dir/{i}"), header).unwrap();
+        }
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+
+        let squashfs = Squashfs::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+        assert!(squashfs.superblock.nfs_export_table_exists());
+        let export = squashfs.export.as_ref().unwrap();
+        assert_eq!(export.len(), squashfs.superblock.inode_count as usize);
+        let root = squashfs.root_inode.header.inode_number;
+        assert_eq!(export[root as usize - 1].num, squashfs.superblock.root_inode);
+        let mut refs: Vec<_> = export.iter().map(|e| e.num).collect();
+        refs.sort();
+        refs.dedup();
+        assert_eq!(refs.len(), export.len());
+
+        // kept when re-writing the image
+        let reader = squashfs.into_filesystem_reader().unwrap();
+        let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        let squashfs = Squashfs::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+        assert_eq!(squashfs.export.unwrap().len(), 1104);
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_uncompressed() {
+        let header = NodeHeader::default();
+        let mut fs = FilesystemWriter::default();
+        fs.set_uncompressed_inodes(true);
+        fs.set_uncompressed_data(true);
+        fs.set_uncompressed_fragments(true);
+        fs.set_uncompressed_xattrs(true);
+        fs.push_file(Cursor::new(vec![b'a'; 0x30000]), "blocks", header).unwrap();
+        fs.push_file(Cursor::new(vec![b'b'; 0x1000]), "fragment", header).unwrap();
+        fs.set_xattrs("fragment", vec![Xattr::new("user.a", vec![b'c'; 0x100])]).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        let bytes = bytes.into_inner();
+
+        // metadata block at `start` has the uncompressed bit set
+        let metadata_uncompressed = |start: u64| {
+            let start = start as usize;
+            !metadata::is_compressed(u16::from_le_bytes([bytes[start], bytes[start + 1]]))
+        };
+        let check = |squashfs: &Squashfs| {
+            let superblock = &squashfs.superblock;
+            assert!(superblock.inodes_uncompressed());
+            assert!(superblock.data_block_stored_uncompressed());
+            assert!(superblock.fragments_stored_uncompressed());
+            assert!(superblock.xattrs_are_stored_uncompressed());
+            assert!(squashfs.fragments.as_ref().unwrap().iter().all(|f| f.size.uncompressed()));
+            for inode in squashfs.inodes.values() {
+                if let InodeInner::BasicFile(file) = &inode.inner {
+                    assert!(file.block_sizes.iter().all(|block| block.uncompressed()));
+                }
+            }
+        };
+
+        let squashfs = Squashfs::from_reader(Cursor::new(bytes.clone())).unwrap();
+        check(&squashfs);
+        assert!(metadata_uncompressed(squashfs.superblock.inode_table));
+        assert!(metadata_uncompressed(squashfs.superblock.dir_table));
+        let start = squashfs.superblock.xattr_table as usize;
+        let xattr_table_start = u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap());
+        assert!(metadata_uncompressed(xattr_table_start));
+
+        // kept when re-writing the image
+        let reader = squashfs.into_filesystem_reader().unwrap();
+        let mut fs = FilesystemWriter::from_fs_reader(&reader).unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        fs.write(&mut bytes).unwrap();
+        check(&Squashfs::from_reader(Cursor::new(bytes.into_inner())).unwrap());
     }
 }
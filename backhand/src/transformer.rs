@@ -1,3 +1,5 @@
+use std::io;
+
 use crate::error::BackhandError;
 
 /// Custom Transformation support
@@ -13,6 +15,20 @@ pub trait TransformAction {
     /// * `buf` - Input bytes to be mutated
     /// * `skip` - Number of bytes to skip if using a stateful streaming transformation
     fn from(&self, buf: &mut [u8], skip: Option<usize>) -> Result<(), BackhandError>;
+
+    /// Transform function used for all "to" transformation actions, the inverse of
+    /// [`TransformAction::from`]
+    ///
+    /// Returns an [`io::ErrorKind::Unsupported`] error by default, so transformations that are
+    /// only read fail when writing.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Input bytes to be mutated
+    /// * `skip` - Number of bytes to skip if using a stateful streaming transformation
+    fn to(&self, _buf: &mut [u8], _skip: Option<usize>) -> Result<(), BackhandError> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
 }
 
 /// Default transformer that simply copies the data
@@ -26,4 +42,9 @@ impl TransformAction for DefaultTransformer {
         // Default implementation does nothing
         Ok(())
     }
+
+    fn to(&self, _: &mut [u8], _: Option<usize>) -> Result<(), BackhandError> {
+        // Default implementation does nothing
+        Ok(())
+    }
 }
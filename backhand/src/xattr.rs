@@ -0,0 +1,306 @@
+//! Extended attributes
+//!
+//! The xattr table is made up of a list of key/value pairs stored in metadata blocks, followed by
+//! a lookup table of [`XattrId`]s that each inode references with its `xattr_index`.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+
+use deku::prelude::*;
+use solana_nohash_hasher::IntMap;
+use tracing::error;
+
+use crate::error::BackhandError;
+use crate::kinds::Kind;
+use crate::metadata::MetadataWriter;
+
+/// Inode `xattr_index` value for an inode without extended attributes
+pub(crate) const NO_XATTR: u32 = 0xffff_ffff;
+
+/// Bit set in [`XattrKey::t`] if the value is stored out-of-line
+const XATTR_VALUE_OOL: u16 = 0x0100;
+
+/// Mask of [`XattrKey::t`] for the prefix id
+const XATTR_PREFIX_MASK: u16 = 0x00ff;
+
+/// Supported name prefixes, with the id stored on disk
+const XATTR_PREFIXES: [(u16, &[u8]); 3] = [(0, b"user."), (1, b"trusted."), (2, b"security.")];
+
+/// Extended attribute of a [`Node`](crate::Node)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Xattr {
+    /// Full name of the attribute, including the namespace prefix. For example:
+    /// `b"security.capability"`
+    pub name: Vec<u8>,
+    /// Raw value of the attribute
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    pub fn new<N: Into<Vec<u8>>, V: Into<Vec<u8>>>(name: N, value: V) -> Self {
+        Self { name: name.into(), value: value.into() }
+    }
+
+    /// Split name into the on-disk prefix id and the name without the prefix
+    pub(crate) fn prefix_id(&self) -> Result<(u16, &[u8]), BackhandError> {
+        XATTR_PREFIXES
+            .iter()
+            .find(|(_, prefix)| self.name.starts_with(prefix))
+            .map(|(id, prefix)| (*id, &self.name[prefix.len()..]))
+            .ok_or(BackhandError::InvalidXattr)
+    }
+}
+
+/// Located at `superblock.xattr_table`, followed by the pointers to the [`XattrId`] metadata blocks
+#[derive(Debug, Copy, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+pub(crate) struct XattrIdTable {
+    /// Start of the key/value metadata blocks
+    pub(crate) xattr_table_start: u64,
+    /// Number of [`XattrId`]s
+    pub(crate) xattr_ids: u32,
+    pub(crate) unused: u32,
+}
+
+impl XattrIdTable {
+    pub(crate) const SIZE: usize = 16;
+}
+
+/// Lookup table entry, referenced by `xattr_index` of an inode
+#[derive(Debug, Copy, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+pub struct XattrId {
+    /// Reference to the first key, from the start of the key/value metadata blocks
+    pub xattr: u64,
+    /// Number of key/value pairs
+    pub count: u32,
+    /// Total size of the uncompressed key/value pairs
+    pub size: u32,
+}
+
+impl XattrId {
+    pub const SIZE: usize = 16;
+}
+
+#[derive(Debug, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+pub(crate) struct XattrKey {
+    /// Prefix id, ORed with [`XATTR_VALUE_OOL`] if out-of-line
+    pub(crate) t: u16,
+    pub(crate) name_size: u16,
+    #[deku(count = "*name_size")]
+    pub(crate) name: Vec<u8>,
+}
+
+#[derive(Debug, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
+#[deku(endian = "type_endian", ctx = "type_endian: deku::ctx::Endian")]
+pub(crate) struct XattrValue {
+    pub(crate) value_size: u32,
+    #[deku(count = "*value_size")]
+    pub(crate) value: Vec<u8>,
+}
+
+/// Uncompressed xattr table read from image
+#[derive(Debug, Clone, Default)]
+pub(crate) struct XattrTable {
+    /// Lookup table
+    pub(crate) ids: Vec<XattrId>,
+    /// Bytes containing key/value pairs `(<OffsetFromTableStart, OffsetInData>, Data)`
+    pub(crate) blocks: (IntMap<u64, u64>, Vec<u8>),
+}
+
+impl XattrTable {
+    /// Convert a metadata reference into an offset into the uncompressed `blocks`
+    fn offset(&self, reference: u64) -> Result<usize, BackhandError> {
+        let block = reference >> 16;
+        let offset = reference & 0xffff;
+        let Some(block_offset) = self.blocks.0.get(&block) else {
+            error!("xattr reference {reference:#02x?} not found");
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        };
+        Ok((block_offset + offset) as usize)
+    }
+
+    /// Resolve all key/value pairs referenced by an inode `xattr_index`
+    pub(crate) fn lookup(&self, index: u32, kind: &Kind) -> Result<Vec<Xattr>, BackhandError> {
+        if index == NO_XATTR {
+            return Ok(vec![]);
+        }
+        let Some(id) = self.ids.get(index as usize) else {
+            error!("xattr index {index} out of range");
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        };
+
+        let start = self.offset(id.xattr)?;
+        let Some(bytes) = self.blocks.1.get(start..) else {
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        };
+        let mut cursor = Cursor::new(bytes);
+        let mut reader = Reader::new(&mut cursor);
+
+        let mut xattrs = Vec::with_capacity(id.count as usize);
+        for _ in 0..id.count {
+            let key = XattrKey::from_reader_with_ctx(&mut reader, kind.inner.type_endian)?;
+            let value = XattrValue::from_reader_with_ctx(&mut reader, kind.inner.type_endian)?;
+            let value = if key.t & XATTR_VALUE_OOL != 0 {
+                self.out_of_line_value(&value, kind)?
+            } else {
+                value.value
+            };
+
+            let prefix_id = key.t & XATTR_PREFIX_MASK;
+            let Some((_, prefix)) = XATTR_PREFIXES.iter().find(|(id, _)| *id == prefix_id) else {
+                error!("unknown xattr prefix: {prefix_id}");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            };
+            let mut name = prefix.to_vec();
+            name.extend_from_slice(&key.name);
+            xattrs.push(Xattr { name, value });
+        }
+
+        Ok(xattrs)
+    }
+
+    /// An out-of-line value stores a reference to the real value
+    fn out_of_line_value(&self, value: &XattrValue, kind: &Kind) -> Result<Vec<u8>, BackhandError> {
+        let mut cursor = Cursor::new(&value.value);
+        let mut reader = Reader::new(&mut cursor);
+        let reference = u64::from_reader_with_ctx(&mut reader, kind.inner.type_endian)?;
+
+        let start = self.offset(reference)?;
+        let Some(bytes) = self.blocks.1.get(start..) else {
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        };
+        let mut cursor = Cursor::new(bytes);
+        let mut reader = Reader::new(&mut cursor);
+        let value = XattrValue::from_reader_with_ctx(&mut reader, kind.inner.type_endian)?;
+        Ok(value.value)
+    }
+}
+
+/// Deduplicating writer of the key/value pairs and lookup table
+pub(crate) struct XattrWriter {
+    /// Key/value metadata blocks
+    pub(crate) kv_writer: MetadataWriter,
+    /// Lookup table
+    pub(crate) ids: Vec<XattrId>,
+    /// Already written sets of xattrs `<Xattrs, XattrIndex>`
+    sets: HashMap<Vec<Xattr>, u32>,
+    /// Already written values `<Value, Reference>`
+    values: HashMap<Vec<u8>, u64>,
+}
+
+impl XattrWriter {
+    pub(crate) fn new(kv_writer: MetadataWriter) -> Self {
+        Self { kv_writer, ids: vec![], sets: HashMap::new(), values: HashMap::new() }
+    }
+
+    /// Current metadata reference of the key/value writer
+    fn reference(&self) -> u64 {
+        (u64::from(self.kv_writer.metadata_start) << 16)
+            | self.kv_writer.uncompressed_bytes.len() as u64
+    }
+
+    /// Add `xattrs`, returning the index to store in the inode
+    ///
+    /// Identical sets share one lookup table entry, and values that were already written are
+    /// referenced out-of-line.
+    pub(crate) fn add(&mut self, xattrs: &[Xattr]) -> Result<u32, BackhandError> {
+        if xattrs.is_empty() {
+            return Ok(NO_XATTR);
+        }
+        if let Some(index) = self.sets.get(xattrs) {
+            return Ok(*index);
+        }
+
+        let type_endian = self.kv_writer.kind.inner.type_endian;
+        let xattr = self.reference();
+        let mut size = 0;
+        for x in xattrs {
+            let (prefix_id, name) = x.prefix_id()?;
+            size += x.name.len() as u32 + 1 + x.value.len() as u32;
+
+            // out-of-line values only save space if they are larger than the reference
+            let ool = match self.values.get(&x.value) {
+                Some(reference) if x.value.len() > 8 => Some(*reference),
+                _ => None,
+            };
+
+            let t = if ool.is_some() { prefix_id | XATTR_VALUE_OOL } else { prefix_id };
+            let key = XattrKey { t, name_size: name.len() as u16, name: name.to_vec() };
+            let mut bytes = Cursor::new(vec![]);
+            let mut writer = Writer::new(&mut bytes);
+            key.to_writer(&mut writer, type_endian)?;
+            self.kv_writer.write_all(bytes.get_ref())?;
+
+            let value = match ool {
+                Some(reference) => {
+                    let mut bytes = Cursor::new(vec![]);
+                    let mut writer = Writer::new(&mut bytes);
+                    reference.to_writer(&mut writer, type_endian)?;
+                    XattrValue { value_size: 8, value: bytes.into_inner() }
+                }
+                None => {
+                    let reference = self.reference();
+                    self.values.entry(x.value.clone()).or_insert(reference);
+                    XattrValue { value_size: x.value.len() as u32, value: x.value.clone() }
+                }
+            };
+            let mut bytes = Cursor::new(vec![]);
+            let mut writer = Writer::new(&mut bytes);
+            value.to_writer(&mut writer, type_endian)?;
+            self.kv_writer.write_all(bytes.get_ref())?;
+        }
+
+        let index = self.ids.len() as u32;
+        self.ids.push(XattrId { xattr, count: xattrs.len() as u32, size });
+        self.sets.insert(xattrs.to_vec(), index);
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::kinds::LE_V4_0;
+
+    fn to_bytes<T: DekuWriter<deku::ctx::Endian>>(t: &T, out: &mut Vec<u8>) {
+        let mut cursor = Cursor::new(vec![]);
+        let mut writer = Writer::new(&mut cursor);
+        t.to_writer(&mut writer, deku::ctx::Endian::Little).unwrap();
+        writer.finalize().unwrap();
+        out.extend_from_slice(cursor.get_ref());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let kind = Kind { inner: Arc::new(LE_V4_0) };
+        let mut bytes = vec![];
+
+        // out-of-line value, stored first
+        to_bytes(&XattrKey { t: 0, name_size: 1, name: b"a".to_vec() }, &mut bytes);
+        to_bytes(&XattrValue { value_size: 3, value: b"ool".to_vec() }, &mut bytes);
+
+        let start = bytes.len() as u64;
+        to_bytes(&XattrKey { t: 2, name_size: 10, name: b"capability".to_vec() }, &mut bytes);
+        to_bytes(&XattrValue { value_size: 2, value: vec![0x01, 0x02] }, &mut bytes);
+        to_bytes(&XattrKey { t: XATTR_VALUE_OOL, name_size: 1, name: b"b".to_vec() }, &mut bytes);
+        to_bytes(&XattrValue { value_size: 8, value: 5_u64.to_le_bytes().to_vec() }, &mut bytes);
+
+        let mut map = IntMap::default();
+        map.insert(0, 0);
+        let table = XattrTable {
+            ids: vec![XattrId { xattr: start, count: 2, size: 0 }],
+            blocks: (map, bytes),
+        };
+
+        assert_eq!(table.lookup(NO_XATTR, &kind).unwrap(), vec![]);
+        assert_eq!(
+            table.lookup(0, &kind).unwrap(),
+            vec![Xattr::new("security.capability", [0x01, 0x02]), Xattr::new("user.b", "ool")]
+        );
+        assert!(table.lookup(1, &kind).is_err());
+    }
+}